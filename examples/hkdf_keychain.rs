@@ -1,4 +1,7 @@
-use keychains_rs::{HashFunc::*, key_chains::hkdf_keychain::HkdfKeyChain};
+use keychains_rs::{
+    HashFunc::*,
+    key_chains::{InitialState, hkdf_keychain::HkdfKeyChain},
+};
 use rand::{RngCore, rngs::OsRng};
 
 fn main() {
@@ -12,8 +15,8 @@ fn main() {
 
         let kci_salt: [u8; 18] = *b"I use the kci salt";
 
-        let kc_init_state: Vec<u8> = hkdf_kc_obj
-            .key_chain_instantiate(&initial_skm, Some(kci_salt.to_vec()), None)
+        let kc_init_state: InitialState = hkdf_kc_obj
+            .key_chain_instantiate_ref(&initial_skm, Some(kci_salt.as_slice()), None)
             .unwrap();
 
         let kcu_info_1: [u8; 20] = *b"The first info param";
@@ -22,11 +25,11 @@ fn main() {
         OsRng::fill_bytes(&mut OsRng, &mut input_param_1);
 
         let (new_state_1, output_key_1) = hkdf_kc_obj
-            .key_chain_update(
+            .key_chain_update_ref(
                 &input_param_1,
                 &kc_init_state,
                 None,
-                Some(kcu_info_1.to_vec()),
+                Some(kcu_info_1.as_slice()),
             )
             .unwrap();
 
@@ -34,7 +37,8 @@ fn main() {
 
         println!(
             "First Key in the key chain using {:?}: {:?}",
-            variant, output_key_1
+            variant,
+            output_key_1.as_bytes()
         );
 
         let kcu_salt_1: [u8; 19] = *b"I use some kcu salt";
@@ -43,10 +47,10 @@ fn main() {
         OsRng::fill_bytes(&mut OsRng, &mut input_param_2);
 
         let (_new_state_2, output_key_2) = hkdf_kc_obj
-            .key_chain_update(
+            .key_chain_update_ref(
                 &input_param_2,
                 &new_state_1,
-                Some(kcu_salt_1.to_vec()),
+                Some(kcu_salt_1.as_slice()),
                 None,
             )
             .unwrap();
@@ -55,7 +59,8 @@ fn main() {
 
         println!(
             "Second Key in the key chain using {:?}: {:?}",
-            variant, output_key_2
+            variant,
+            output_key_2.as_bytes()
         );
     }
 }
@@ -1,6 +1,7 @@
 use keychains_rs::{
     HashFunc::Sha256,
     key_chains::{
+        InitialState, NewState,
         hkdf_keychain::HkdfKeyChain,
         storage_handler::{DefaultStorage, KeyChainType, Storage},
     },
@@ -18,15 +19,16 @@ fn main() {
         Some(true),
         Some(storage.clone()),
     )
-    .unwrap();
+    .unwrap()
+    .with_chain_id("tenant-a");
 
     let mut initial_skm: [u8; 32] = [0u8; 32];
     OsRng::fill_bytes(&mut OsRng, &mut initial_skm);
 
     let kci_salt: [u8; 18] = *b"I use the kci salt";
 
-    let kc_init_state: Vec<u8> = hkdf_kc_obj
-        .key_chain_instantiate(&initial_skm, Some(kci_salt.to_vec()), None)
+    let kc_init_state: InitialState = hkdf_kc_obj
+        .key_chain_instantiate_ref(&initial_skm, Some(kci_salt.as_slice()), None)
         .unwrap();
 
     let kcu_info_1: [u8; 20] = *b"The first info param";
@@ -35,23 +37,24 @@ fn main() {
     OsRng::fill_bytes(&mut OsRng, &mut input_param_1);
 
     let (new_state_1, output_key_1) = hkdf_kc_obj
-        .key_chain_update(
+        .key_chain_update_ref(
             &input_param_1,
             &kc_init_state,
             None,
-            Some(kcu_info_1.to_vec()),
+            Some(kcu_info_1.as_slice()),
         )
         .unwrap();
 
     assert_eq!(output_key_1.len(), output_length);
 
-    let state_from_storage: Vec<u8> = storage.fetch_hkdf_keychain_state(Sha256).unwrap();
+    let state_from_storage: NewState = storage.fetch_state(&"tenant-a".into()).unwrap();
 
     assert_eq!(new_state_1, state_from_storage);
 
     println!(
         "First Key in the key chain using {:?}: {:?}",
-        Sha256, output_key_1
+        Sha256,
+        output_key_1.as_bytes()
     );
 
     let kcu_salt_1: [u8; 19] = *b"I use some kcu salt";
@@ -60,22 +63,23 @@ fn main() {
     OsRng::fill_bytes(&mut OsRng, &mut input_param_2);
 
     let (new_state_2, output_key_2) = hkdf_kc_obj
-        .key_chain_update(
+        .key_chain_update_ref(
             &input_param_2,
             &new_state_1,
-            Some(kcu_salt_1.to_vec()),
+            Some(kcu_salt_1.as_slice()),
             None,
         )
         .unwrap();
 
     assert_eq!(output_key_2.len(), output_length);
 
-    let state_from_storage: Vec<u8> = storage.fetch_hkdf_keychain_state(Sha256).unwrap();
+    let state_from_storage: NewState = storage.fetch_state(&"tenant-a".into()).unwrap();
 
     assert_eq!(new_state_2, state_from_storage);
 
     println!(
         "Second Key in the key chain using {:?}: {:?}",
-        Sha256, output_key_2
+        Sha256,
+        output_key_2.as_bytes()
     );
 }
@@ -1,4 +1,4 @@
-use keychains_rs::key_chains::prg_keychain::PrgKeyChain;
+use keychains_rs::key_chains::{InitialState, prg_keychain::PrgKeyChain};
 use rand::{RngCore, rngs::OsRng};
 
 fn main() {
@@ -10,7 +10,7 @@ fn main() {
         let mut initial_seed: Vec<u8> = vec![0u8; *sec_param];
         OsRng::fill_bytes(&mut OsRng, &mut initial_seed);
 
-        let kc_init_state: Vec<u8> = prg_kc_obj.key_chain_instantiate(&initial_seed).unwrap();
+        let kc_init_state: InitialState = prg_kc_obj.key_chain_instantiate(&initial_seed).unwrap();
 
         let mut input_param_1: Vec<u8> = vec![0u8; *sec_param];
         OsRng::fill_bytes(&mut OsRng, &mut input_param_1);
@@ -23,7 +23,8 @@ fn main() {
 
         println!(
             "First Key in the key chain using lambda = {}: {:?}",
-            sec_param, output_key_1
+            sec_param,
+            output_key_1.as_bytes()
         );
 
         let mut input_param_2: Vec<u8> = vec![0u8; *sec_param];
@@ -37,7 +38,8 @@ fn main() {
 
         println!(
             "Second Key in the key chain using lambda = {}: {:?}",
-            sec_param, output_key_2
+            sec_param,
+            output_key_2.as_bytes()
         );
     }
 }
@@ -0,0 +1,50 @@
+use keychains_rs::{
+    SecretBytes,
+    primitives::{HashFunc, HkdfWrap, Prg, Xdrbg, Xof},
+};
+use rand::{RngCore, rngs::OsRng};
+
+fn main() {
+    let mut input_key_material: [u8; 32] = [0u8; 32];
+    OsRng::fill_bytes(&mut OsRng, &mut input_key_material);
+
+    let hkdf_obj: HkdfWrap = HkdfWrap::new(HashFunc::Sha512);
+
+    let pseudo_random_key: SecretBytes = hkdf_obj
+        .hkdf_extract_ref(Some(b"some salt".as_slice()), &input_key_material)
+        .unwrap();
+
+    let okm: SecretBytes = hkdf_obj
+        .hkdf_expand_ref(&pseudo_random_key, Some(b"some info".as_slice()), 32)
+        .unwrap();
+
+    println!(
+        "HKDF output using {:?}: {:?}",
+        HashFunc::Sha512,
+        okm.as_bytes()
+    );
+
+    let prg_obj: Prg = Prg::new(32);
+
+    let refreshed_prg_state: SecretBytes = prg_obj.prg_refresh(&okm, &input_key_material).unwrap();
+
+    let (prg_output, _new_prg_state) = prg_obj.prg_next(&refreshed_prg_state).unwrap();
+
+    println!("PRG output using lambda = 32: {:?}", prg_output.as_bytes());
+
+    let xdrbg_obj: Xdrbg = Xdrbg::new(Xof::Ascon);
+
+    let xdrbg_state: SecretBytes = xdrbg_obj
+        .xdrbg_instantiate_ref(&prg_output, Some(b"The alpha for instantiate.".as_slice()))
+        .unwrap();
+
+    let (_new_xdrbg_state, xdrbg_output) = xdrbg_obj
+        .xdrbg_generate_ref(&xdrbg_state, 64, None)
+        .unwrap();
+
+    println!(
+        "XDRBG output using {:?}: {:?}",
+        Xof::Ascon,
+        xdrbg_output.as_bytes()
+    );
+}
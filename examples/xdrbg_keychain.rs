@@ -1,4 +1,7 @@
-use keychains_rs::{Xof::*, key_chains::xdrbg_keychain::XdrbgKeyChain};
+use keychains_rs::{
+    Xof::*,
+    key_chains::{InitialState, xdrbg_keychain::XdrbgKeyChain},
+};
 use rand::{RngCore, rngs::OsRng};
 
 fn main() {
@@ -12,8 +15,8 @@ fn main() {
 
         let alpha_instantiate: [u8; 26] = *b"The alpha for instantiate.";
 
-        let kc_init_state: Vec<u8> = xdrbg_kc_obj
-            .key_chain_instantiate(&seed_instantiate, Some(alpha_instantiate.to_vec()))
+        let kc_init_state: InitialState = xdrbg_kc_obj
+            .key_chain_instantiate_ref(&seed_instantiate, Some(alpha_instantiate.as_slice()))
             .unwrap();
 
         let mut arbitrary_input_param_1: [u8; 32] = [0u8; 32];
@@ -22,10 +25,10 @@ fn main() {
         let alpha_reseed: [u8; 21] = *b"The alpha for reseed.";
 
         let (new_state_1, output_key_1) = xdrbg_kc_obj
-            .key_chain_update(
+            .key_chain_update_ref(
                 &arbitrary_input_param_1,
                 &kc_init_state,
-                Some(alpha_reseed.to_vec()),
+                Some(alpha_reseed.as_slice()),
                 None,
             )
             .unwrap();
@@ -34,7 +37,8 @@ fn main() {
 
         println!(
             "First Key in the key chain using {:?}: {:?}",
-            xof, output_key_1
+            xof,
+            output_key_1.as_bytes()
         );
 
         let mut arbitrary_input_param_2: [u8; 32] = [0u8; 32];
@@ -43,11 +47,11 @@ fn main() {
         let alpha_generate: [u8; 23] = *b"The alpha for generate.";
 
         let (_new_state_2, output_key_2) = xdrbg_kc_obj
-            .key_chain_update(
+            .key_chain_update_ref(
                 &arbitrary_input_param_2,
                 &new_state_1,
                 None,
-                Some(alpha_generate.to_vec()),
+                Some(alpha_generate.as_slice()),
             )
             .unwrap();
 
@@ -55,7 +59,8 @@ fn main() {
 
         println!(
             "Second Key in the key chain using {:?}: {:?}",
-            xof, output_key_2
+            xof,
+            output_key_2.as_bytes()
         );
     }
 }
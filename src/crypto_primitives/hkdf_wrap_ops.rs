@@ -1,12 +1,23 @@
 // [1] Krawczyk, Hugo. "Cryptographic extraction and key derivation: The HKDF scheme."
 // Annual Cryptology Conference. Berlin, Heidelberg: Springer Berlin Heidelberg, 2010.
 
-use crate::errors::Errors::{self, *};
-use hkdf::{Hkdf, HkdfExtract};
-use sha2::{Sha256, Sha512, digest::Digest};
+use crate::{
+    errors::Errors::{self, *},
+    secret_bytes::SecretBytes,
+};
+use alloc::{format, vec::Vec};
+use core::{fmt, marker::PhantomData};
+use hkdf::SimpleHkdfExtract;
+use hmac::{Mac, SimpleHmac};
+use sha2::{
+    Sha256, Sha512,
+    digest::{Digest, Output, core_api::BlockSizeUser},
+};
 use sha3::{Sha3_256, Sha3_512};
+use zeroize::Zeroize;
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum HashFunc {
     Sha256,
     Sha512,
@@ -14,76 +25,261 @@ pub enum HashFunc {
     Sha3_512,
 }
 
+// Binds $digest to the digest type behind the hash function, so the body is
+// monomorphized once per variant.
+macro_rules! with_digest {
+    ($hash_func:expr, $digest:ident => $body:expr) => {
+        match $hash_func {
+            HashFunc::Sha256 => {
+                type $digest = Sha256;
+                $body
+            }
+            HashFunc::Sha512 => {
+                type $digest = Sha512;
+                $body
+            }
+            HashFunc::Sha3_256 => {
+                type $digest = Sha3_256;
+                $body
+            }
+            HashFunc::Sha3_512 => {
+                type $digest = Sha3_512;
+                $body
+            }
+        }
+    };
+}
+
 impl HashFunc {
     pub fn output_size(&self) -> usize {
-        match self {
-            Self::Sha256 => Sha256::output_size(),
-            Self::Sha512 => Sha512::output_size(),
-            Self::Sha3_256 => Sha3_256::output_size(),
-            Self::Sha3_512 => Sha3_512::output_size(),
+        with_digest!(self, D => <D as Digest>::output_size())
+    }
+
+    // HKDF-Expand yields at most 255 blocks, and a key chain spends one of them
+    // on its next state.
+    pub fn max_output_key_length(&self) -> usize {
+        with_digest!(self, D => TypedHkdf::<D>::new().max_output_key_length())
+    }
+}
+
+/// Digests HKDF can be instantiated with: any RustCrypto hash with a block size,
+/// including ones defined outside this crate.
+pub trait HkdfDigest: Digest + BlockSizeUser + Clone {}
+
+impl<D: Digest + BlockSizeUser + Clone> HkdfDigest for D {}
+
+/// HKDF \[1\] monomorphized over the digest `D`. [`HkdfWrap`] is the dynamic layer over it for
+/// the digests of [`HashFunc`].
+pub struct TypedHkdf<D> {
+    _digest: PhantomData<fn() -> D>,
+}
+
+impl<D> Clone for TypedHkdf<D> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<D> Copy for TypedHkdf<D> {}
+
+impl<D> fmt::Debug for TypedHkdf<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TypedHkdf<{}>", core::any::type_name::<D>())
+    }
+}
+
+impl<D: HkdfDigest> Default for TypedHkdf<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: HkdfDigest> TypedHkdf<D> {
+    pub fn new() -> Self {
+        Self {
+            _digest: PhantomData,
         }
     }
 
-    fn check_and_get_salt(&self, extractor_salt: Option<Vec<u8>>) -> Result<Vec<u8>, Errors> {
-        let digest_size: usize = self.output_size();
-        let default_salt: Vec<u8>;
-        let salt: Vec<u8> = match extractor_salt {
-            Some(val) => {
-                if val.len() > digest_size {
-                    return Err(InvalidLength(format!(
-                        "Provided salt of {} bytes. Acceptable length is <= {} bytes for the hash function {:?}",
-                        val.len(),
-                        digest_size,
-                        self
-                    )));
+    pub fn output_size(&self) -> usize {
+        <D as Digest>::output_size()
+    }
+
+    pub fn max_output_key_length(&self) -> usize {
+        254 * self.output_size()
+    }
+
+    /// HKDF-Extract. The salt defaults to `HashLen` zero bytes and may not be longer than the digest.
+    pub fn hkdf_extract_ref(
+        self,
+        extractor_salt: Option<&[u8]>,
+        source_key_material: &[u8],
+    ) -> Result<SecretBytes, Errors> {
+        let mut pseudo_random_key: SecretBytes = SecretBytes::zeroed(self.output_size());
+        self.hkdf_extract_into(extractor_salt, source_key_material, &mut pseudo_random_key)?;
+
+        Ok(pseudo_random_key)
+    }
+
+    /// HKDF-Extract into a caller provided buffer of exactly `HashLen` bytes. Does not allocate.
+    pub fn hkdf_extract_into(
+        self,
+        extractor_salt: Option<&[u8]>,
+        source_key_material: &[u8],
+        pseudo_random_key: &mut [u8],
+    ) -> Result<(), Errors> {
+        self.hkdf_extract_parts_into(extractor_salt, &[source_key_material], pseudo_random_key)
+    }
+
+    // The source key material may arrive in several parts, which are absorbed
+    // in order as if they were concatenated.
+    pub(crate) fn hkdf_extract_parts_into(
+        self,
+        extractor_salt: Option<&[u8]>,
+        source_key_parts: &[&[u8]],
+        pseudo_random_key: &mut [u8],
+    ) -> Result<(), Errors> {
+        self.check_size_of_salt(extractor_salt)?;
+
+        if pseudo_random_key.len() != self.output_size() {
+            return Err(InvalidLength(format!(
+                "Provided a buffer of {} bytes for the pseudo random key. Expected {} bytes for the hash function {}",
+                pseudo_random_key.len(),
+                self.output_size(),
+                core::any::type_name::<D>()
+            )));
+        }
+
+        // Without a salt, HKDF-Extract falls back to HashLen zero bytes
+        let mut hkdf_inst = SimpleHkdfExtract::<D>::new(extractor_salt);
+        for part in source_key_parts {
+            hkdf_inst.input_ikm(part);
+        }
+        let (mut prk, _) = hkdf_inst.finalize();
+        pseudo_random_key.copy_from_slice(prk.as_slice());
+        prk.as_mut_slice().zeroize();
+
+        Ok(())
+    }
+
+    /// HKDF-Expand of the pseudo random key into at most `255 * HashLen` bytes.
+    pub fn hkdf_expand_ref(
+        self,
+        pseudo_random_key: &[u8],
+        info_param: Option<&[u8]>,
+        total_output_length: usize,
+    ) -> Result<SecretBytes, Errors> {
+        self.is_output_length_okay(total_output_length)?;
+
+        let mut total_output: SecretBytes = SecretBytes::zeroed(total_output_length);
+        self.hkdf_expand_into(pseudo_random_key, info_param, &mut total_output)?;
+
+        Ok(total_output)
+    }
+
+    /// HKDF-Expand filling the whole caller provided buffer. Does not allocate.
+    pub fn hkdf_expand_into(
+        self,
+        pseudo_random_key: &[u8],
+        info_param: Option<&[u8]>,
+        output: &mut [u8],
+    ) -> Result<(), Errors> {
+        self.hkdf_expand_parts_into(pseudo_random_key, info_param, &mut [output])
+    }
+
+    // HKDF-Expand written block by block straight into the output buffers, so
+    // one output stream can be spread over e.g. the next state of a key chain
+    // and its output key without a contiguous copy.
+    pub(crate) fn hkdf_expand_parts_into(
+        self,
+        pseudo_random_key: &[u8],
+        info_param: Option<&[u8]>,
+        outputs: &mut [&mut [u8]],
+    ) -> Result<(), Errors> {
+        let total_output_length: usize = outputs.iter().map(|output| output.len()).sum();
+        self.is_output_length_okay(total_output_length)?;
+        self.check_length_of_prk(pseudo_random_key)?;
+
+        let info: &[u8] = info_param.unwrap_or_default();
+
+        let hmac_inst = <SimpleHmac<D> as Mac>::new_from_slice(pseudo_random_key)
+            .expect("Hmac accepts keys of any length");
+        let mut block: Output<D> = Output::<D>::default();
+        let mut counter: u8 = 0;
+        let mut available: usize = 0;
+
+        for output in outputs.iter_mut() {
+            let mut written: usize = 0;
+            while written < output.len() {
+                if available == 0 {
+                    let mut mac = hmac_inst.clone();
+                    if counter > 0 {
+                        mac.update(&block);
+                    }
+                    counter += 1;
+                    mac.update(info);
+                    mac.update(&[counter]);
+                    block = mac.finalize().into_bytes();
+                    available = block.len();
                 }
-                val
-            }
-            None => {
-                default_salt = vec![0u8; digest_size];
-                default_salt
+
+                let offset: usize = block.len() - available;
+                let take: usize = available.min(output.len() - written);
+                output[written..written + take].copy_from_slice(&block[offset..offset + take]);
+                written += take;
+                available -= take;
             }
-        };
+        }
+
+        block.as_mut_slice().zeroize();
+
+        Ok(())
+    }
 
-        Ok(salt)
+    fn check_size_of_salt(&self, extractor_salt: Option<&[u8]>) -> Result<(), Errors> {
+        if let Some(salt) = extractor_salt
+            && salt.len() > self.output_size()
+        {
+            return Err(InvalidLength(format!(
+                "Provided salt of {} bytes. Acceptable length is <= {} bytes for the hash function {}",
+                salt.len(),
+                self.output_size(),
+                core::any::type_name::<D>()
+            )));
+        }
+
+        Ok(())
     }
 
     fn is_output_length_okay(&self, total_output_length: usize) -> Result<(), Errors> {
-        let digest_size: usize = self.output_size();
-        if total_output_length > (255 * digest_size) {
+        if total_output_length > (255 * self.output_size()) {
             return Err(InvalidLength(format!(
-                "Total requested output size: {} bytes. Acceptable length is <= {} bytes for the hash function {:?}",
+                "Total requested output size: {} bytes. Acceptable length is <= {} bytes for the hash function {}",
                 total_output_length,
-                (255 * digest_size),
-                self
+                (255 * self.output_size()),
+                core::any::type_name::<D>()
             )));
         }
 
         Ok(())
     }
-}
 
-macro_rules! hkdf_extract {
-    ($hash_algo:ty, $salt:expr, $source_key_material:expr) => {{
-        let mut hkdf_inst = HkdfExtract::<$hash_algo>::new(Some(&$salt));
-        hkdf_inst.input_ikm($source_key_material);
-        let (pseudo_random_key, _) = hkdf_inst.finalize();
-        pseudo_random_key.to_vec()
-    }};
-}
+    fn check_length_of_prk(&self, pseudo_random_key: &[u8]) -> Result<(), Errors> {
+        if pseudo_random_key.len() < self.output_size() {
+            return Err(InvalidLength(format!(
+                "Provided a pseudo random key of {} bytes. Minimum length is {} bytes for the hash function {}",
+                pseudo_random_key.len(),
+                self.output_size(),
+                core::any::type_name::<D>()
+            )));
+        }
 
-macro_rules! hkdf_expand {
-    ($hash_algo:ty, $pseudo_random_key:expr, $info_param:expr, $total_output:expr) => {{
-        let hkdf_inst =
-            Hkdf::<$hash_algo>::from_prk($pseudo_random_key).expect("Invalid (length of) Prk.");
-        hkdf_inst
-            .expand(&$info_param, &mut $total_output)
-            .expect("Hkdf expansion failed");
-        $total_output
-    }};
+        Ok(())
+    }
 }
 
-#[derive(Clone, Copy)]
+/// HKDF \[1\] instantiated with one of the supported hash functions.
+#[derive(Clone, Copy, Debug)]
 pub struct HkdfWrap {
     hash_func: HashFunc,
 }
@@ -103,49 +299,80 @@ impl HkdfWrap {
         }
     }
 
+    /// HKDF-Extract with an owned salt.
+    #[deprecated(note = "Use hkdf_extract_ref, which borrows the salt.")]
     pub fn hkdf_extract(
         self,
         extractor_salt: Option<Vec<u8>>,
         source_key_material: &[u8],
-    ) -> Result<Vec<u8>, Errors> {
-        let salt: Vec<u8> = self.hash_func.check_and_get_salt(extractor_salt)?;
-
-        match self.hash_func {
-            HashFunc::Sha256 => Ok(hkdf_extract!(Sha256, salt, source_key_material)),
-            HashFunc::Sha512 => Ok(hkdf_extract!(Sha512, salt, source_key_material)),
-            HashFunc::Sha3_256 => Ok(hkdf_extract!(Sha3_256, salt, source_key_material)),
-            HashFunc::Sha3_512 => Ok(hkdf_extract!(Sha3_512, salt, source_key_material)),
-        }
+    ) -> Result<SecretBytes, Errors> {
+        self.hkdf_extract_ref(extractor_salt.as_deref(), source_key_material)
+    }
+
+    /// HKDF-Extract. The salt defaults to `HashLen` zero bytes and may not be longer than the digest.
+    pub fn hkdf_extract_ref(
+        self,
+        extractor_salt: Option<&[u8]>,
+        source_key_material: &[u8],
+    ) -> Result<SecretBytes, Errors> {
+        with_digest!(self.hash_func, D => {
+            TypedHkdf::<D>::new().hkdf_extract_ref(extractor_salt, source_key_material)
+        })
+    }
+
+    /// HKDF-Extract into a caller provided buffer of exactly `HashLen` bytes. Does not allocate.
+    pub fn hkdf_extract_into(
+        self,
+        extractor_salt: Option<&[u8]>,
+        source_key_material: &[u8],
+        pseudo_random_key: &mut [u8],
+    ) -> Result<(), Errors> {
+        with_digest!(self.hash_func, D => {
+            TypedHkdf::<D>::new().hkdf_extract_into(
+                extractor_salt,
+                source_key_material,
+                pseudo_random_key,
+            )
+        })
     }
 
+    /// HKDF-Expand with an owned info parameter.
+    #[deprecated(note = "Use hkdf_expand_ref, which borrows the info parameter.")]
     pub fn hkdf_expand(
         self,
         pseudo_random_key: &[u8],
         info_param: Option<Vec<u8>>,
         total_output_length: usize,
-    ) -> Result<Vec<u8>, Errors> {
-        let mut total_output: Vec<u8> = vec![0u8; total_output_length];
-        let info: Vec<u8> = info_param.unwrap_or(b"".to_vec());
-
-        match self.hash_func.is_output_length_okay(total_output_length) {
-            Ok(_) => match self.hash_func {
-                HashFunc::Sha256 => Ok(hkdf_expand!(Sha256, pseudo_random_key, info, total_output)),
-                HashFunc::Sha512 => Ok(hkdf_expand!(Sha512, pseudo_random_key, info, total_output)),
-                HashFunc::Sha3_256 => Ok(hkdf_expand!(
-                    Sha3_256,
-                    pseudo_random_key,
-                    info,
-                    total_output
-                )),
-                HashFunc::Sha3_512 => Ok(hkdf_expand!(
-                    Sha3_512,
-                    pseudo_random_key,
-                    info,
-                    total_output
-                )),
-            },
-            Err(err) => return Err(err),
-        }
+    ) -> Result<SecretBytes, Errors> {
+        self.hkdf_expand_ref(
+            pseudo_random_key,
+            info_param.as_deref(),
+            total_output_length,
+        )
+    }
+
+    /// HKDF-Expand of the pseudo random key into at most `255 * HashLen` bytes.
+    pub fn hkdf_expand_ref(
+        self,
+        pseudo_random_key: &[u8],
+        info_param: Option<&[u8]>,
+        total_output_length: usize,
+    ) -> Result<SecretBytes, Errors> {
+        with_digest!(self.hash_func, D => {
+            TypedHkdf::<D>::new().hkdf_expand_ref(pseudo_random_key, info_param, total_output_length)
+        })
+    }
+
+    /// HKDF-Expand filling the whole caller provided buffer. Does not allocate.
+    pub fn hkdf_expand_into(
+        self,
+        pseudo_random_key: &[u8],
+        info_param: Option<&[u8]>,
+        output: &mut [u8],
+    ) -> Result<(), Errors> {
+        with_digest!(self.hash_func, D => {
+            TypedHkdf::<D>::new().hkdf_expand_into(pseudo_random_key, info_param, output)
+        })
     }
 
     pub fn get_chosen_hash_func(&self) -> HashFunc {
@@ -156,6 +383,7 @@ impl HkdfWrap {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use hkdf::Hkdf;
 
     fn sample_input() -> Vec<u8> {
         b"example input key material".to_vec()
@@ -171,7 +399,7 @@ mod tests {
         let salt = Some(sample_salt(32)); // SHA256 output size
         let ikm = sample_input();
 
-        let result = hkdf.hkdf_extract(salt, &ikm);
+        let result = hkdf.hkdf_extract_ref(salt.as_deref(), &ikm);
         assert!(result.is_ok());
         assert_eq!(result.unwrap().len(), 32);
     }
@@ -181,7 +409,7 @@ mod tests {
         let hkdf = HkdfWrap::new(HashFunc::Sha512);
         let ikm = sample_input();
 
-        let result = hkdf.hkdf_extract(None, &ikm);
+        let result = hkdf.hkdf_extract_ref(None, &ikm);
         assert!(result.is_ok());
         assert_eq!(result.unwrap().len(), 64);
     }
@@ -192,7 +420,7 @@ mod tests {
         let ikm = sample_input();
         let long_salt = Some(sample_salt(64)); // longer than SHA256 output
 
-        let result = hkdf.hkdf_extract(long_salt, &ikm);
+        let result = hkdf.hkdf_extract_ref(long_salt.as_deref(), &ikm);
         assert!(matches!(result, Err(InvalidLength(_))));
     }
 
@@ -201,8 +429,8 @@ mod tests {
         let hkdf = HkdfWrap::new(HashFunc::Sha256);
         let ikm = sample_input();
 
-        let prk = hkdf.hkdf_extract(None, &ikm).unwrap();
-        let output = hkdf.hkdf_expand(&prk, None, 64);
+        let prk = hkdf.hkdf_extract_ref(None, &ikm).unwrap();
+        let output = hkdf.hkdf_expand_ref(&prk, None, 64);
 
         assert!(output.is_ok());
         assert_eq!(output.unwrap().len(), 64);
@@ -213,8 +441,8 @@ mod tests {
         let hkdf = HkdfWrap::new(HashFunc::Sha3_256);
         let ikm = sample_input();
 
-        let prk = hkdf.hkdf_extract(None, &ikm).unwrap();
-        let result = hkdf.hkdf_expand(&prk, None, 255 * 32 + 1); // Just over the allowed limit
+        let prk = hkdf.hkdf_extract_ref(None, &ikm).unwrap();
+        let result = hkdf.hkdf_expand_ref(&prk, None, 255 * 32 + 1); // Just over the allowed limit
 
         assert!(matches!(result, Err(InvalidLength(_))));
     }
@@ -223,10 +451,10 @@ mod tests {
     fn test_expand_with_info() {
         let hkdf = HkdfWrap::new(HashFunc::Sha3_512);
         let ikm = sample_input();
-        let prk = hkdf.hkdf_extract(None, &ikm).unwrap();
+        let prk = hkdf.hkdf_extract_ref(None, &ikm).unwrap();
 
         let info = Some(b"contextual-info".to_vec());
-        let output = hkdf.hkdf_expand(&prk, info, 128);
+        let output = hkdf.hkdf_expand_ref(&prk, info.as_deref(), 128);
 
         assert!(output.is_ok());
         assert_eq!(output.unwrap().len(), 128);
@@ -238,8 +466,8 @@ mod tests {
         let ikm = sample_input();
         let salt = Some(sample_salt(32));
 
-        let prk = hkdf.hkdf_extract(salt, &ikm).unwrap();
-        let output = hkdf.hkdf_expand(&prk, None, 64).unwrap();
+        let prk = hkdf.hkdf_extract_ref(salt.as_deref(), &ikm).unwrap();
+        let output = hkdf.hkdf_expand_ref(&prk, None, 64).unwrap();
 
         assert_eq!(output.len(), 64);
     }
@@ -249,7 +477,106 @@ mod tests {
         let hkdf = HkdfWrap::default();
         let ikm = sample_input();
 
-        let prk = hkdf.hkdf_extract(None, &ikm).unwrap();
+        let prk = hkdf.hkdf_extract_ref(None, &ikm).unwrap();
         assert_eq!(prk.len(), 32);
     }
+
+    #[test]
+    fn test_into_variants_match_reference_hkdf() {
+        for hash_func in [
+            HashFunc::Sha256,
+            HashFunc::Sha512,
+            HashFunc::Sha3_256,
+            HashFunc::Sha3_512,
+        ] {
+            let hkdf = HkdfWrap::new(hash_func);
+            let salt = sample_salt(hash_func.output_size());
+
+            let mut prk = vec![0u8; hash_func.output_size()];
+            hkdf.hkdf_extract_into(Some(&salt), &sample_input(), &mut prk)
+                .unwrap();
+
+            let mut expected = vec![0u8; 3 * hash_func.output_size() + 5];
+            match hash_func {
+                HashFunc::Sha256 => {
+                    Hkdf::<Sha256>::new(Some(&salt), &sample_input()).expand(b"info", &mut expected)
+                }
+                HashFunc::Sha512 => {
+                    Hkdf::<Sha512>::new(Some(&salt), &sample_input()).expand(b"info", &mut expected)
+                }
+                HashFunc::Sha3_256 => Hkdf::<Sha3_256>::new(Some(&salt), &sample_input())
+                    .expand(b"info", &mut expected),
+                HashFunc::Sha3_512 => Hkdf::<Sha3_512>::new(Some(&salt), &sample_input())
+                    .expand(b"info", &mut expected),
+            }
+            .unwrap();
+
+            let mut output = vec![0u8; expected.len()];
+            hkdf.hkdf_expand_into(&prk, Some(b"info"), &mut output)
+                .unwrap();
+            assert_eq!(output, expected);
+        }
+    }
+
+    fn check_parts_match_contiguous<D: HkdfDigest>() {
+        let hkdf = TypedHkdf::<D>::new();
+        let salt = sample_salt(hkdf.output_size());
+
+        let prk = hkdf.hkdf_extract_ref(Some(&salt), &sample_input()).unwrap();
+        let mut split_prk = vec![0u8; hkdf.output_size()];
+        hkdf.hkdf_extract_parts_into(
+            Some(&salt),
+            &[b"example input", b" key material"],
+            &mut split_prk,
+        )
+        .unwrap();
+        assert_eq!(prk.as_bytes(), split_prk.as_slice());
+
+        let expected = hkdf
+            .hkdf_expand_ref(&prk, Some(b"info"), 3 * hkdf.output_size() + 5)
+            .unwrap();
+
+        // Splits inside and on block boundaries yield the same stream
+        for split_at in [1, hkdf.output_size(), hkdf.output_size() + 7] {
+            let (mut first, mut second) =
+                (vec![0u8; split_at], vec![0u8; expected.len() - split_at]);
+            hkdf.hkdf_expand_parts_into(&prk, Some(b"info"), &mut [&mut first, &mut second])
+                .unwrap();
+            assert_eq!([first, second].concat(), expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_parts_match_contiguous_input_and_output() {
+        check_parts_match_contiguous::<Sha256>();
+        check_parts_match_contiguous::<Sha3_512>();
+    }
+
+    #[test]
+    fn test_typed_hkdf_accepts_digests_outside_hash_func() {
+        // SHA-384 is not one of HashFunc, but any RustCrypto digest works
+        let hkdf = TypedHkdf::<sha2::Sha384>::new();
+        let prk = hkdf.hkdf_extract_ref(None, &sample_input()).unwrap();
+        let output = hkdf.hkdf_expand_ref(&prk, Some(b"info"), 100).unwrap();
+
+        let mut expected = [0u8; 100];
+        Hkdf::<sha2::Sha384>::new(None, &sample_input())
+            .expand(b"info", &mut expected)
+            .unwrap();
+        assert_eq!(output.as_bytes(), &expected);
+        assert_eq!(hkdf.max_output_key_length(), 254 * 48);
+    }
+
+    #[test]
+    fn test_into_variants_reject_wrong_buffer_lengths() {
+        let hkdf = HkdfWrap::new(HashFunc::Sha256);
+
+        let mut short_prk = [0u8; 31];
+        let result = hkdf.hkdf_extract_into(None, &sample_input(), &mut short_prk);
+        assert!(matches!(result, Err(InvalidLength(_))));
+
+        let mut output = [0u8; 32];
+        let result = hkdf.hkdf_expand_into(&short_prk, None, &mut output);
+        assert!(matches!(result, Err(InvalidLength(_))));
+    }
 }
@@ -1,10 +1,15 @@
 // [1] Barak, Boaz, and Shai Halevi. "A model and architecture for pseudo-random generation with applications to/dev/random."
 // Proceedings of the 12th ACM conference on Computer and communications security. 2005. https://eprint.iacr.org/2005/029.pdf
 
-use crate::errors::Errors::{self, *};
+use crate::{
+    errors::Errors::{self, *},
+    secret_bytes::SecretBytes,
+};
 use aes::{Aes128, Aes192, Aes256};
+use alloc::format;
 use ctr::Ctr128LE;
 use ctr::cipher::{KeyIvInit, StreamCipher};
+use zeroize::Zeroize;
 
 const NONCE_FOR_PRG_NEXT: &[u8; 12] = b"\x96\n\n\n\n\n\n\n\n\n\n\n";
 const NONCE_FOR_PRG_REFRESH: &[u8; 12] = b"\x96\r\r\r\r\r\r\r\r\r\r\r";
@@ -15,13 +20,11 @@ enum Steps {
     Next,
 }
 
-#[derive(PartialEq, Eq, Clone)]
-enum PrgOutput {
-    Refresh(Vec<u8>),
-    Next(Vec<u8>, Vec<u8>),
-}
+// Largest AES key, and so the largest PRG state.
+pub(crate) const MAX_KEY_LEN: usize = 32;
 
-#[derive(Clone, Copy)]
+/// Barak-Halevi PRG \[1\] built from AES in counter mode. The state must be a valid AES key, i.e. 16, 24 or 32 bytes.
+#[derive(Clone, Copy, Debug)]
 pub struct Prg {
     security_param_lambda: usize,
 }
@@ -41,57 +44,86 @@ impl Prg {
         }
     }
 
+    /// refresh(): XORs the extracted parameter into the state and returns the refreshed state.
     pub fn prg_refresh(
         self,
         current_prg_state: &[u8],
         extracted_parameter: &[u8],
-    ) -> Result<Vec<u8>, Errors> {
-        let xored_value: Vec<u8> = match self.xor_bytes(current_prg_state, extracted_parameter) {
-            Ok(output) => output,
-            Err(err) => return Err(err),
-        };
-
-        let prg_state_after_refreshing: Vec<u8> =
-            match self.aes_in_counter_mode_as_prg(&xored_value, Steps::Refresh) {
-                Ok(PrgOutput::Refresh(output)) => output,
-                Ok(PrgOutput::Next(_, _)) => {
-                    return Err(UnexpectedOutputType(format!(
-                        "Received output of prg_next() during prg_refresh()."
-                    )));
-                }
-                Err(err) => return Err(err),
-            };
+    ) -> Result<SecretBytes, Errors> {
+        let mut prg_state_after_refreshing: SecretBytes =
+            SecretBytes::zeroed(self.security_param_lambda);
+        self.prg_refresh_into(
+            current_prg_state,
+            extracted_parameter,
+            &mut prg_state_after_refreshing,
+        )?;
 
         Ok(prg_state_after_refreshing)
     }
 
-    pub fn prg_next(self, current_prg_state: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Errors> {
-        let (random_output, new_state) =
-            match self.aes_in_counter_mode_as_prg(current_prg_state, Steps::Next) {
-                Ok(PrgOutput::Refresh(_)) => {
-                    return Err(UnexpectedOutputType(format!(
-                        "Received output of prg_refresh() during prg_next()."
-                    )));
-                }
-                Ok(PrgOutput::Next(random_output, new_prg_state)) => (random_output, new_prg_state),
-                Err(err) => return Err(err),
-            };
+    /// refresh() into a caller provided state buffer of `security_param_lambda` bytes. Does not allocate.
+    pub fn prg_refresh_into(
+        self,
+        current_prg_state: &[u8],
+        extracted_parameter: &[u8],
+        new_prg_state: &mut [u8],
+    ) -> Result<(), Errors> {
+        if current_prg_state.len() != extracted_parameter.len() {
+            return Err(InvalidLength(format!(
+                "Cannot XOR two parameters of unequal length."
+            )));
+        }
+        self.check_length_of_key(current_prg_state)?;
+        self.check_length_of_output(new_prg_state)?;
+
+        let mut xored_value: [u8; MAX_KEY_LEN] = [0u8; MAX_KEY_LEN];
+        let xored_value_slice: &mut [u8] = &mut xored_value[..current_prg_state.len()];
+        for ((out, a), b) in xored_value_slice
+            .iter_mut()
+            .zip(current_prg_state)
+            .zip(extracted_parameter)
+        {
+            *out = a ^ b;
+        }
+
+        self.aes_in_counter_mode_as_prg(xored_value_slice, Steps::Refresh, &mut [new_prg_state]);
+        xored_value.zeroize();
+
+        Ok(())
+    }
+
+    /// next(): returns `(random_output, new_state)`, each `security_param_lambda` bytes long.
+    pub fn prg_next(self, current_prg_state: &[u8]) -> Result<(SecretBytes, SecretBytes), Errors> {
+        let mut random_output: SecretBytes = SecretBytes::zeroed(self.security_param_lambda);
+        let mut new_state: SecretBytes = SecretBytes::zeroed(self.security_param_lambda);
+        self.prg_next_into(current_prg_state, &mut random_output, &mut new_state)?;
 
         Ok((random_output, new_state))
     }
 
-    fn aes_in_counter_mode_as_prg(
+    /// next() into caller provided buffers of `security_param_lambda` bytes each. Does not allocate.
+    pub fn prg_next_into(
         self,
-        input_key: &[u8],
-        step: Steps,
-    ) -> Result<PrgOutput, Errors> {
-        if ![16, 24, 32].contains(&input_key.len()) {
-            return Err(InvalidLength(format!(
-                "AES input key provided of {} bytes. Acceptable key sizes are 16, 24 or 32 bytes.",
-                &input_key.len()
-            )));
-        }
+        current_prg_state: &[u8],
+        random_output: &mut [u8],
+        new_prg_state: &mut [u8],
+    ) -> Result<(), Errors> {
+        self.check_length_of_key(current_prg_state)?;
+        self.check_length_of_output(random_output)?;
+        self.check_length_of_output(new_prg_state)?;
+
+        self.aes_in_counter_mode_as_prg(
+            current_prg_state,
+            Steps::Next,
+            &mut [random_output, new_prg_state],
+        );
+
+        Ok(())
+    }
 
+    // Writes the AES-CTR keystream across the output buffers in order. The key
+    // length must have been checked by the caller.
+    fn aes_in_counter_mode_as_prg(self, input_key: &[u8], step: Steps, outputs: &mut [&mut [u8]]) {
         // Construct the IV from the nonce to be used
         let mut iv: [u8; 16] = [0u8; 16];
 
@@ -104,58 +136,56 @@ impl Prg {
         // Set the initial counter value to 1 of 32 bits
         iv[12..16].copy_from_slice(&[0, 0, 0, 1]);
 
-        let plaintext: Vec<u8> = vec![0u8; 2 * self.security_param_lambda];
-
-        // Create a mutable copy
-        let mut ciphertext: Vec<u8> = plaintext;
+        // The all-zero plaintext is encrypted in place, so the buffers end up holding the keystream
+        for output in outputs.iter_mut() {
+            output.fill(0);
+        }
 
         // Check the input key length and use the AES in Counter Mode Encryption Variant
         match input_key.len() {
             16 => {
                 let mut cipher = Ctr128LE::<Aes128>::new(input_key.into(), iv.as_slice().into());
-                cipher.apply_keystream(&mut ciphertext); // In-place encryption
+                for output in outputs.iter_mut() {
+                    cipher.apply_keystream(output); // In-place encryption
+                }
             }
             24 => {
                 let mut cipher = Ctr128LE::<Aes192>::new(input_key.into(), iv.as_slice().into());
-                cipher.apply_keystream(&mut ciphertext); // In-place encryption
+                for output in outputs.iter_mut() {
+                    cipher.apply_keystream(output); // In-place encryption
+                }
             }
             32 => {
                 let mut cipher = Ctr128LE::<Aes256>::new(input_key.into(), iv.as_slice().into());
-                cipher.apply_keystream(&mut ciphertext); // In-place encryption
+                for output in outputs.iter_mut() {
+                    cipher.apply_keystream(output); // In-place encryption
+                }
             }
             _ => unreachable!(),
         }
+    }
 
-        match step {
-            Steps::Refresh => {
-                let (pseudorandom_output, _) = ciphertext.split_at(self.security_param_lambda);
-                Ok(PrgOutput::Refresh(pseudorandom_output.to_vec()))
-            }
-            Steps::Next => {
-                let (random_output, new_prg_state) =
-                    ciphertext.split_at(self.security_param_lambda);
-                Ok(PrgOutput::Next(
-                    random_output.to_vec(),
-                    new_prg_state.to_vec(),
-                ))
-            }
+    fn check_length_of_key(self, input_key: &[u8]) -> Result<(), Errors> {
+        if ![16, 24, 32].contains(&input_key.len()) {
+            return Err(InvalidLength(format!(
+                "AES input key provided of {} bytes. Acceptable key sizes are 16, 24 or 32 bytes.",
+                &input_key.len()
+            )));
         }
+
+        Ok(())
     }
 
-    fn xor_bytes(self, param_1: &[u8], param_2: &[u8]) -> Result<Vec<u8>, Errors> {
-        if param_1.len() != param_2.len() {
+    fn check_length_of_output(self, output: &[u8]) -> Result<(), Errors> {
+        if output.len() != self.security_param_lambda {
             return Err(InvalidLength(format!(
-                "Cannot XOR two parameters of unequal length."
+                "Provided an output buffer of {} bytes. Expected {} bytes.",
+                output.len(),
+                self.security_param_lambda
             )));
         }
 
-        let output: Vec<u8> = param_1
-            .iter()
-            .zip(param_2.iter())
-            .map(|(a, b)| a ^ b)
-            .collect();
-
-        Ok(output)
+        Ok(())
     }
 
     pub fn get_chosen_security_param_lambda(&self) -> usize {
@@ -274,6 +304,32 @@ mod tests {
         let param = gen_key(16);
 
         let refreshed = prg.clone().prg_refresh(&key, &param).unwrap();
-        assert_ne!(refreshed, key); // Output should not be equal to input key
+        assert_ne!(refreshed.as_bytes(), key.as_slice()); // Output should not be equal to input key
+    }
+
+    #[test]
+    fn test_into_variants_match_allocating_variants() {
+        let prg = Prg::new(32);
+        let key = gen_key(32);
+
+        // next() is one keystream of 2 * lambda bytes split in two
+        let (mut random_output, mut new_state) = ([0u8; 32], [0u8; 32]);
+        prg.prg_next_into(&key, &mut random_output, &mut new_state)
+            .unwrap();
+        let (expected_output, expected_state) = prg.prg_next(&key).unwrap();
+        assert_eq!(&random_output[..], expected_output.as_bytes());
+        assert_eq!(&new_state[..], expected_state.as_bytes());
+
+        let mut refreshed = [0u8; 32];
+        prg.prg_refresh_into(&key, &random_output, &mut refreshed)
+            .unwrap();
+        assert_eq!(
+            &refreshed[..],
+            prg.prg_refresh(&key, &random_output).unwrap().as_bytes()
+        );
+
+        let mut short_state = [0u8; 16];
+        let result = prg.prg_next_into(&key, &mut random_output, &mut short_state);
+        assert!(matches!(result, Err(InvalidLength(_))));
     }
 }
@@ -1,14 +1,22 @@
 // [1] Kelsey, John, Stefan Lucks, and Stephan Müller. "XDRBG: A Proposed Deterministic Random Bit Generator Based on Any XOF."
 // IACR Transactions on Symmetric Cryptology 2024.1 (2024): 5-34. https://tosc.iacr.org/index.php/ToSC/article/view/11399
 
-use crate::errors::Errors::{self, *};
+use crate::{
+    errors::Errors::{self, *},
+    secret_bytes::SecretBytes,
+};
+use alloc::{format, vec::Vec};
 use ascon_hash::AsconXof128;
+use core::{fmt, marker::PhantomData};
 use sha3::{
     Shake128, Shake256,
     digest::{ExtendableOutput, Update, XofReader},
 };
 
-const MAX_LEN_ALPHA: usize = 84;
+pub(crate) const MAX_LEN_ALPHA: usize = 84;
+
+// State size of the largest XOF a key chain can hold, i.e. 256 bits of security.
+pub(crate) const MAX_STATE_SIZE: usize = 64;
 
 #[derive(Clone, Copy, Debug)]
 pub enum XdrbgOps {
@@ -16,72 +24,270 @@ pub enum XdrbgOps {
     Reseed,
 }
 
+/// XOFs XDRBG \[1\] can be instantiated with, together with their parameters in bytes. Implement it
+/// for any RustCrypto XOF to use it with [`TypedXdrbg`].
+pub trait XdrbgXof: Default + Update + ExtendableOutput {
+    /// Twice the security strength. Key chains accept at most 64 bytes.
+    const STATE_SIZE: usize;
+    const MIN_SEED_SIZE_INSTANTIATE: usize;
+    const MIN_SEED_SIZE_RESEED: usize;
+    /// Bound on the state plus the output of one generate call.
+    const MAX_TOTAL_OUTPUT_SIZE: usize;
+}
+
+impl XdrbgXof for Shake128 {
+    const STATE_SIZE: usize = 32;
+    const MIN_SEED_SIZE_INSTANTIATE: usize = 24;
+    const MIN_SEED_SIZE_RESEED: usize = 16;
+    const MAX_TOTAL_OUTPUT_SIZE: usize = 304;
+}
+
+impl XdrbgXof for Shake256 {
+    const STATE_SIZE: usize = 64;
+    const MIN_SEED_SIZE_INSTANTIATE: usize = 48;
+    const MIN_SEED_SIZE_RESEED: usize = 32;
+    const MAX_TOTAL_OUTPUT_SIZE: usize = 344;
+}
+
+impl XdrbgXof for AsconXof128 {
+    const STATE_SIZE: usize = 32;
+    const MIN_SEED_SIZE_INSTANTIATE: usize = 24;
+    const MIN_SEED_SIZE_RESEED: usize = 16;
+    const MAX_TOTAL_OUTPUT_SIZE: usize = 256;
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum Xof {
     Shake128,
     Shake256,
     Ascon,
 }
 
+// Binds $xof_type to the XOF type behind the variant, so the body is
+// monomorphized once per variant.
+macro_rules! with_xof {
+    ($xof:expr, $xof_type:ident => $body:expr) => {
+        match $xof {
+            Xof::Shake128 => {
+                type $xof_type = Shake128;
+                $body
+            }
+            Xof::Shake256 => {
+                type $xof_type = Shake256;
+                $body
+            }
+            Xof::Ascon => {
+                type $xof_type = AsconXof128;
+                $body
+            }
+        }
+    };
+}
+
 impl Xof {
     pub fn state_size(&self) -> usize {
-        match self {
-            Self::Shake128 => 32,
-            Self::Shake256 => 64,
-            Self::Ascon => 32,
-        }
+        with_xof!(self, X => X::STATE_SIZE)
+    }
+
+    pub fn are_params_okay(&self, seed: &[u8], alpha: &[u8], ops: XdrbgOps) -> Result<(), Errors> {
+        with_xof!(self, X => TypedXdrbg::<X>::new().are_params_okay(seed, alpha, ops))
+    }
+
+    pub fn is_output_length_okay(&self, output_key_length: usize) -> Result<(), Errors> {
+        with_xof!(self, X => TypedXdrbg::<X>::new().is_output_length_okay(output_key_length))
+    }
+
+    pub fn max_output_key_length(&self) -> usize {
+        with_xof!(self, X => TypedXdrbg::<X>::new().max_output_key_length())
+    }
+}
+
+/// XDRBG \[1\] monomorphized over the XOF `X`. [`Xdrbg`] is the dynamic layer over it for the
+/// XOFs of [`Xof`].
+pub struct TypedXdrbg<X> {
+    _xof: PhantomData<fn() -> X>,
+}
+
+impl<X> Clone for TypedXdrbg<X> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<X> Copy for TypedXdrbg<X> {}
+
+impl<X> fmt::Debug for TypedXdrbg<X> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TypedXdrbg<{}>", core::any::type_name::<X>())
+    }
+}
+
+impl<X: XdrbgXof> Default for TypedXdrbg<X> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<X: XdrbgXof> TypedXdrbg<X> {
+    pub fn new() -> Self {
+        Self { _xof: PhantomData }
+    }
+
+    pub fn state_size(&self) -> usize {
+        X::STATE_SIZE
+    }
+
+    pub fn max_output_key_length(&self) -> usize {
+        X::MAX_TOTAL_OUTPUT_SIZE - X::STATE_SIZE
     }
 
     pub fn are_params_okay(&self, seed: &[u8], alpha: &[u8], ops: XdrbgOps) -> Result<(), Errors> {
         match ops {
             XdrbgOps::Instantiate => {
-                self.check_size_of_seed(seed, self.min_seed_size_instantiate(), ops)?;
+                self.check_size_of_seed(seed, X::MIN_SEED_SIZE_INSTANTIATE, ops)?;
                 self.check_size_of_alpha(alpha)
             }
             XdrbgOps::Reseed => {
-                self.check_size_of_seed(seed, self.min_seed_size_reseed(), ops)?;
+                self.check_size_of_seed(seed, X::MIN_SEED_SIZE_RESEED, ops)?;
                 self.check_size_of_alpha(alpha)
             }
         }
     }
 
     pub fn is_output_length_okay(&self, output_key_length: usize) -> Result<(), Errors> {
-        if (output_key_length + self.state_size()) > self.max_total_output_size() {
+        let total_output_size: usize =
+            output_key_length
+                .checked_add(X::STATE_SIZE)
+                .ok_or_else(|| {
+                    InvalidLength(format!(
+                        "Requested output key size of {} bytes is out of range.",
+                        output_key_length
+                    ))
+                })?;
+
+        if total_output_size > X::MAX_TOTAL_OUTPUT_SIZE {
             return Err(InvalidLength(format!(
-                "Requested output key size + XOF state size: {} + {} = {} bytes. Acceptable length is <= {} bytes for the XOF {:?}.",
+                "Requested output key size + XOF state size: {} + {} = {} bytes. Acceptable length is <= {} bytes for the XOF {}.",
                 output_key_length,
-                self.state_size(),
-                output_key_length + self.state_size(),
-                self.max_total_output_size(),
-                self,
+                X::STATE_SIZE,
+                total_output_size,
+                X::MAX_TOTAL_OUTPUT_SIZE,
+                core::any::type_name::<X>()
             )));
         }
 
         Ok(())
     }
 
-    fn min_seed_size_instantiate(&self) -> usize {
-        match self {
-            Self::Shake128 => 24,
-            Self::Shake256 => 48,
-            Self::Ascon => 24,
-        }
+    /// Derives the initial state from the seed and the optional personalization string alpha.
+    pub fn xdrbg_instantiate_ref(
+        &self,
+        seed: &[u8],
+        alpha: Option<&[u8]>,
+    ) -> Result<SecretBytes, Errors> {
+        let mut init_state: SecretBytes = SecretBytes::zeroed(X::STATE_SIZE);
+        self.xdrbg_instantiate_into(seed, alpha, &mut init_state)?;
+
+        Ok(init_state)
     }
 
-    fn min_seed_size_reseed(&self) -> usize {
-        match self {
-            Self::Shake128 => 16,
-            Self::Shake256 => 32,
-            Self::Ascon => 16,
-        }
+    /// Instantiate into a caller provided state buffer of `state_size()` bytes. Does not allocate.
+    pub fn xdrbg_instantiate_into(
+        &self,
+        seed: &[u8],
+        alpha: Option<&[u8]>,
+        new_xdrbg_state: &mut [u8],
+    ) -> Result<(), Errors> {
+        let aplha_val: &[u8] = alpha.unwrap_or_default();
+
+        self.are_params_okay(seed, aplha_val, XdrbgOps::Instantiate)?;
+        self.check_length_of_state(new_xdrbg_state)?;
+
+        self.encode_and_generate(&[seed], aplha_val, 0_usize, &mut [new_xdrbg_state]);
+
+        Ok(())
     }
 
-    fn max_total_output_size(&self) -> usize {
-        match self {
-            Self::Shake128 => 304,
-            Self::Shake256 => 344,
-            Self::Ascon => 256,
-        }
+    /// Absorbs fresh seed material into the current state and returns the reseeded state.
+    pub fn xdrbg_reseed_ref(
+        &self,
+        current_xdrbg_state: &[u8],
+        seed: &[u8],
+        alpha: Option<&[u8]>,
+    ) -> Result<SecretBytes, Errors> {
+        let mut reseeded_state: SecretBytes = SecretBytes::zeroed(X::STATE_SIZE);
+        self.xdrbg_reseed_into(current_xdrbg_state, seed, alpha, &mut reseeded_state)?;
+
+        Ok(reseeded_state)
+    }
+
+    /// Reseed into a caller provided state buffer of `state_size()` bytes. Does not allocate.
+    pub fn xdrbg_reseed_into(
+        &self,
+        current_xdrbg_state: &[u8],
+        seed: &[u8],
+        alpha: Option<&[u8]>,
+        new_xdrbg_state: &mut [u8],
+    ) -> Result<(), Errors> {
+        let aplha_val: &[u8] = alpha.unwrap_or_default();
+
+        self.are_params_okay(seed, aplha_val, XdrbgOps::Reseed)?;
+        self.check_length_of_state(new_xdrbg_state)?;
+
+        self.encode_and_generate(
+            &[current_xdrbg_state, seed],
+            aplha_val,
+            1_usize,
+            &mut [new_xdrbg_state],
+        );
+
+        Ok(())
+    }
+
+    /// Returns `(new_state, random_output)` with `output_key_length` bytes of output.
+    pub fn xdrbg_generate_ref(
+        &self,
+        current_xdrbg_state: &[u8],
+        output_key_length: usize,
+        alpha: Option<&[u8]>,
+    ) -> Result<(SecretBytes, SecretBytes), Errors> {
+        self.is_output_length_okay(output_key_length)?;
+
+        let mut new_xdrbg_state: SecretBytes = SecretBytes::zeroed(X::STATE_SIZE);
+        let mut random_output: SecretBytes = SecretBytes::zeroed(output_key_length);
+        self.xdrbg_generate_into(
+            current_xdrbg_state,
+            alpha,
+            &mut new_xdrbg_state,
+            &mut random_output,
+        )?;
+
+        Ok((new_xdrbg_state, random_output))
+    }
+
+    /// Generate into caller provided buffers. The new state takes `state_size()` bytes and the
+    /// random output is as long as its buffer. Does not allocate.
+    pub fn xdrbg_generate_into(
+        &self,
+        current_xdrbg_state: &[u8],
+        alpha: Option<&[u8]>,
+        new_xdrbg_state: &mut [u8],
+        random_output: &mut [u8],
+    ) -> Result<(), Errors> {
+        let aplha_val: &[u8] = alpha.unwrap_or_default();
+
+        self.is_output_length_okay(random_output.len())?;
+        self.check_length_of_state(new_xdrbg_state)?;
+
+        self.encode_and_generate(
+            &[current_xdrbg_state],
+            aplha_val,
+            2_usize,
+            &mut [new_xdrbg_state, random_output],
+        );
+
+        Ok(())
     }
 
     fn check_size_of_seed(
@@ -92,10 +298,10 @@ impl Xof {
     ) -> Result<(), Errors> {
         if seed.len() < min_seed_len {
             return Err(InvalidLength(format!(
-                "Provided a seed of {} bytes. Minimum seed length is {} bytes for {:?} XOF during {:?}.",
+                "Provided a seed of {} bytes. Minimum seed length is {} bytes for the XOF {} during {:?}.",
                 seed.len(),
                 min_seed_len,
-                self,
+                core::any::type_name::<X>(),
                 ops
             )));
         }
@@ -114,9 +320,61 @@ impl Xof {
 
         Ok(())
     }
+
+    fn check_length_of_state(&self, xdrbg_state: &[u8]) -> Result<(), Errors> {
+        if xdrbg_state.len() != X::STATE_SIZE {
+            return Err(InvalidLength(format!(
+                "Provided a state buffer of {} bytes. Expected {} bytes for the XOF {}.",
+                xdrbg_state.len(),
+                X::STATE_SIZE,
+                core::any::type_name::<X>()
+            )));
+        }
+
+        Ok(())
+    }
+
+    // encode(seed, alpha, n) = seed || alpha || the shortest big endian encoding
+    // of n * 85 + len(alpha). Returns the encoding buffer and its length.
+    fn encode_param(&self, alpha: &[u8], value_n: usize) -> ([u8; size_of::<usize>()], usize) {
+        let computed_param: usize = value_n * 85_usize + alpha.len();
+
+        let num_bytes: usize = if computed_param == 0 {
+            1
+        } else {
+            ((usize::BITS - computed_param.leading_zeros() + 7) / 8) as usize
+        };
+
+        (computed_param.to_be_bytes(), num_bytes)
+    }
+
+    // Feeds encode(seed, alpha, n) to the XOF piecewise instead of building it,
+    // with the seed given as the parts it is made of, and squeezes the output
+    // stream across the output buffers in order.
+    fn encode_and_generate(
+        &self,
+        seed_parts: &[&[u8]],
+        alpha: &[u8],
+        value_n: usize,
+        outputs: &mut [&mut [u8]],
+    ) {
+        let (param_bytes, num_bytes) = self.encode_param(alpha, value_n);
+        let param_bytes: &[u8] = &param_bytes[param_bytes.len() - num_bytes..];
+
+        let mut xof_instance: X = X::default();
+        for part in seed_parts.iter().copied().chain([alpha, param_bytes]) {
+            xof_instance.update(part);
+        }
+
+        let mut xof_digest_reader = xof_instance.finalize_xof();
+        for output in outputs.iter_mut() {
+            xof_digest_reader.read(output);
+        }
+    }
 }
 
-#[derive(Clone, Copy)]
+/// XDRBG \[1\] over one of the supported XOFs.
+#[derive(Clone, Copy, Debug)]
 pub struct Xdrbg {
     xof: Xof,
 }
@@ -126,134 +384,118 @@ impl Xdrbg {
         Self { xof: chosen_xof }
     }
 
+    /// Instantiate with an owned alpha.
+    #[deprecated(note = "Use xdrbg_instantiate_ref, which borrows alpha.")]
     pub fn xdrbg_instantiate(
         &self,
         seed: &[u8],
         alpha: Option<Vec<u8>>,
-    ) -> Result<Vec<u8>, Errors> {
-        let aplha_val: Vec<u8> = alpha.unwrap_or(b"".to_vec());
-
-        match self
-            .xof
-            .are_params_okay(seed, &aplha_val, XdrbgOps::Instantiate)
-        {
-            Ok(_) => {
-                let encoded_bytes: Vec<u8> = self.encode(seed, &aplha_val, 0_usize);
+    ) -> Result<SecretBytes, Errors> {
+        self.xdrbg_instantiate_ref(seed, alpha.as_deref())
+    }
 
-                let init_state: Vec<u8> =
-                    self.generate_output(&encoded_bytes, self.xof.state_size());
+    /// Derives the initial state from the seed and the optional personalization string alpha.
+    pub fn xdrbg_instantiate_ref(
+        &self,
+        seed: &[u8],
+        alpha: Option<&[u8]>,
+    ) -> Result<SecretBytes, Errors> {
+        with_xof!(self.xof, X => TypedXdrbg::<X>::new().xdrbg_instantiate_ref(seed, alpha))
+    }
 
-                Ok(init_state)
-            }
-            Err(err) => return Err(err),
-        }
+    /// Instantiate into a caller provided state buffer of `state_size()` bytes. Does not allocate.
+    pub fn xdrbg_instantiate_into(
+        &self,
+        seed: &[u8],
+        alpha: Option<&[u8]>,
+        new_xdrbg_state: &mut [u8],
+    ) -> Result<(), Errors> {
+        with_xof!(self.xof, X => {
+            TypedXdrbg::<X>::new().xdrbg_instantiate_into(seed, alpha, new_xdrbg_state)
+        })
     }
 
+    /// Reseed with an owned alpha.
+    #[deprecated(note = "Use xdrbg_reseed_ref, which borrows alpha.")]
     pub fn xdrbg_reseed(
         &self,
         current_xdrbg_state: &[u8],
         seed: &[u8],
         alpha: Option<Vec<u8>>,
-    ) -> Result<Vec<u8>, Errors> {
-        let aplha_val: Vec<u8> = alpha.unwrap_or(b"".to_vec());
-
-        match self.xof.are_params_okay(seed, &aplha_val, XdrbgOps::Reseed) {
-            Ok(_) => {
-                let input_bytes: Vec<u8> = [current_xdrbg_state, seed].concat();
-
-                let encoded_bytes: Vec<u8> = self.encode(&input_bytes, &aplha_val, 1_usize);
+    ) -> Result<SecretBytes, Errors> {
+        self.xdrbg_reseed_ref(current_xdrbg_state, seed, alpha.as_deref())
+    }
 
-                let reseeded_state: Vec<u8> =
-                    self.generate_output(&encoded_bytes, self.xof.state_size());
+    /// Absorbs fresh seed material into the current state and returns the reseeded state.
+    pub fn xdrbg_reseed_ref(
+        &self,
+        current_xdrbg_state: &[u8],
+        seed: &[u8],
+        alpha: Option<&[u8]>,
+    ) -> Result<SecretBytes, Errors> {
+        with_xof!(self.xof, X => {
+            TypedXdrbg::<X>::new().xdrbg_reseed_ref(current_xdrbg_state, seed, alpha)
+        })
+    }
 
-                Ok(reseeded_state)
-            }
-            Err(err) => return Err(err),
-        }
+    /// Reseed into a caller provided state buffer of `state_size()` bytes. Does not allocate.
+    pub fn xdrbg_reseed_into(
+        &self,
+        current_xdrbg_state: &[u8],
+        seed: &[u8],
+        alpha: Option<&[u8]>,
+        new_xdrbg_state: &mut [u8],
+    ) -> Result<(), Errors> {
+        with_xof!(self.xof, X => {
+            TypedXdrbg::<X>::new().xdrbg_reseed_into(
+                current_xdrbg_state,
+                seed,
+                alpha,
+                new_xdrbg_state,
+            )
+        })
     }
 
+    /// Generate with an owned alpha.
+    #[deprecated(note = "Use xdrbg_generate_ref, which borrows alpha.")]
     pub fn xdrbg_generate(
         &self,
         current_xdrbg_state: &[u8],
         output_key_length: usize,
         alpha: Option<Vec<u8>>,
-    ) -> Result<(Vec<u8>, Vec<u8>), Errors> {
-        let aplha_val: Vec<u8> = alpha.unwrap_or(b"".to_vec());
-
-        match self.xof.is_output_length_okay(output_key_length) {
-            Ok(_) => {
-                let encoded_bytes: Vec<u8> = self.encode(current_xdrbg_state, &aplha_val, 2_usize);
-
-                let total_output: Vec<u8> =
-                    self.generate_output(&encoded_bytes, output_key_length + self.xof.state_size());
-
-                let (new_xdrbg_state, random_output) = total_output.split_at(self.xof.state_size());
-
-                Ok((new_xdrbg_state.to_vec(), random_output.to_vec()))
-            }
-            Err(err) => return Err(err),
-        }
+    ) -> Result<(SecretBytes, SecretBytes), Errors> {
+        self.xdrbg_generate_ref(current_xdrbg_state, output_key_length, alpha.as_deref())
     }
 
-    fn encode(&self, seed: &[u8], alpha: &[u8], value_n: usize) -> Vec<u8> {
-        let computed_param: usize = value_n * 85_usize + alpha.len();
-
-        let num_bytes: usize = if computed_param == 0 {
-            1
-        } else {
-            ((usize::BITS - computed_param.leading_zeros() + 7) / 8) as usize
-        };
-
-        let mut param_bytes: Vec<u8> = vec![0u8; num_bytes];
-        for (i, b) in param_bytes.iter_mut().enumerate() {
-            *b = ((computed_param >> (8 * (num_bytes - i - 1))) & 0xFF) as u8;
-        }
-
-        let mut encoded_value: Vec<u8> =
-            Vec::with_capacity(seed.len() + alpha.len() + param_bytes.len());
-        encoded_value.extend_from_slice(seed);
-        encoded_value.extend_from_slice(alpha);
-        encoded_value.extend_from_slice(&param_bytes);
-
-        encoded_value
+    /// Returns `(new_state, random_output)` with `output_key_length` bytes of output.
+    pub fn xdrbg_generate_ref(
+        &self,
+        current_xdrbg_state: &[u8],
+        output_key_length: usize,
+        alpha: Option<&[u8]>,
+    ) -> Result<(SecretBytes, SecretBytes), Errors> {
+        with_xof!(self.xof, X => {
+            TypedXdrbg::<X>::new().xdrbg_generate_ref(current_xdrbg_state, output_key_length, alpha)
+        })
     }
 
-    fn generate_output(&self, encoded_bytes: &[u8], total_output_length: usize) -> Vec<u8> {
-        match self.xof {
-            Xof::Shake128 => {
-                let mut xof_instance = Shake128::default();
-
-                xof_instance.update(encoded_bytes);
-                let mut xof_digest_reader = xof_instance.finalize_xof();
-
-                let mut xof_output_buffer: Vec<u8> = vec![0u8; total_output_length];
-                xof_digest_reader.read(&mut xof_output_buffer);
-
-                xof_output_buffer
-            }
-            Xof::Shake256 => {
-                let mut xof_instance = Shake256::default();
-
-                xof_instance.update(encoded_bytes);
-                let mut xof_digest_reader = xof_instance.finalize_xof();
-
-                let mut xof_output_buffer: Vec<u8> = vec![0u8; total_output_length];
-                xof_digest_reader.read(&mut xof_output_buffer);
-
-                xof_output_buffer
-            }
-            Xof::Ascon => {
-                let mut xof_instance = AsconXof128::default();
-
-                xof_instance.update(encoded_bytes);
-                let mut xof_digest_reader = xof_instance.finalize_xof();
-
-                let mut xof_output_buffer: Vec<u8> = vec![0u8; total_output_length];
-                xof_digest_reader.read(&mut xof_output_buffer);
-
-                xof_output_buffer
-            }
-        }
+    /// Generate into caller provided buffers. The new state takes `state_size()` bytes and the
+    /// random output is as long as its buffer. Does not allocate.
+    pub fn xdrbg_generate_into(
+        &self,
+        current_xdrbg_state: &[u8],
+        alpha: Option<&[u8]>,
+        new_xdrbg_state: &mut [u8],
+        random_output: &mut [u8],
+    ) -> Result<(), Errors> {
+        with_xof!(self.xof, X => {
+            TypedXdrbg::<X>::new().xdrbg_generate_into(
+                current_xdrbg_state,
+                alpha,
+                new_xdrbg_state,
+                random_output,
+            )
+        })
     }
 
     pub fn get_chosen_xof(&self) -> Xof {
@@ -307,13 +549,17 @@ mod tests {
     fn test_is_output_length_okay() {
         assert!(Xof::Shake128.is_output_length_okay(200).is_ok());
         assert!(Xof::Shake128.is_output_length_okay(1000).is_err());
+        assert!(matches!(
+            Xof::Shake128.is_output_length_okay(usize::MAX),
+            Err(InvalidLength(_))
+        ));
     }
 
     #[test]
     fn test_xdrbg_instantiate_success() {
         let xdrbg = Xdrbg::new(Xof::Shake128);
         let seed = make_seed(24);
-        let result = xdrbg.xdrbg_instantiate(&seed, None);
+        let result = xdrbg.xdrbg_instantiate_ref(&seed, None);
         assert!(result.is_ok());
         let state = result.unwrap();
         assert_eq!(state.len(), xdrbg.xof.state_size());
@@ -323,7 +569,7 @@ mod tests {
     fn test_xdrbg_instantiate_fail() {
         let xdrbg = Xdrbg::new(Xof::Shake256);
         let seed = make_seed(10); // too short
-        let result = xdrbg.xdrbg_instantiate(&seed, None);
+        let result = xdrbg.xdrbg_instantiate_ref(&seed, None);
         assert!(result.is_err());
     }
 
@@ -331,8 +577,8 @@ mod tests {
     fn test_xdrbg_reseed_success() {
         let xdrbg = Xdrbg::new(Xof::Shake128);
         let seed = make_seed(24);
-        let state = xdrbg.xdrbg_instantiate(&seed, None).unwrap();
-        let reseeded = xdrbg.xdrbg_reseed(&state, &seed, None);
+        let state = xdrbg.xdrbg_instantiate_ref(&seed, None).unwrap();
+        let reseeded = xdrbg.xdrbg_reseed_ref(&state, &seed, None);
         assert!(reseeded.is_ok());
         assert_eq!(reseeded.unwrap().len(), xdrbg.xof.state_size());
     }
@@ -341,8 +587,8 @@ mod tests {
     fn test_xdrbg_generate_success() {
         let xdrbg = Xdrbg::new(Xof::Shake128);
         let seed = make_seed(24);
-        let state = xdrbg.xdrbg_instantiate(&seed, None).unwrap();
-        let (new_state, output) = xdrbg.xdrbg_generate(&state, 64, None).unwrap();
+        let state = xdrbg.xdrbg_instantiate_ref(&seed, None).unwrap();
+        let (new_state, output) = xdrbg.xdrbg_generate_ref(&state, 64, None).unwrap();
         assert_eq!(new_state.len(), xdrbg.xof.state_size());
         assert_eq!(output.len(), 64);
     }
@@ -351,18 +597,124 @@ mod tests {
     fn test_xdrbg_generate_fail_too_large_output() {
         let xdrbg = Xdrbg::new(Xof::Shake128);
         let seed = make_seed(24);
-        let state = xdrbg.xdrbg_instantiate(&seed, None).unwrap();
-        let result = xdrbg.xdrbg_generate(&state, 10_000, None);
+        let state = xdrbg.xdrbg_instantiate_ref(&seed, None).unwrap();
+        let result = xdrbg.xdrbg_generate_ref(&state, 10_000, None);
         assert!(result.is_err());
     }
 
     #[test]
     fn test_encode_functionality() {
-        let xdrbg = Xdrbg::new(Xof::Ascon);
-        let seed = make_seed(5);
+        let xdrbg = TypedXdrbg::<AsconXof128>::new();
         let alpha = vec![9, 9, 9];
-        let encoded = xdrbg.encode(&seed, &alpha, 2);
-        assert!(encoded.len() >= seed.len() + alpha.len());
-        assert!(encoded.ends_with(&[(2 * 85 + 3) as u8]));
+        let (param_bytes, num_bytes) = xdrbg.encode_param(&alpha, 2);
+        assert_eq!(num_bytes, 1);
+        assert!(param_bytes.ends_with(&[(2 * 85 + 3) as u8]));
+
+        let (param_bytes, num_bytes) = xdrbg.encode_param(&[], 0);
+        assert_eq!(num_bytes, 1);
+        assert!(param_bytes.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_encoding_is_absorbed_as_one_input() {
+        let xdrbg = Xdrbg::new(Xof::Shake256);
+        let seed = make_seed(48);
+        let alpha = vec![7u8; 10];
+
+        // encode(seed, alpha, 0) built in one piece
+        let mut xof_instance = Shake256::default();
+        xof_instance.update(&[seed.as_slice(), &alpha, &[10u8]].concat());
+        let mut expected = [0u8; 64];
+        xof_instance.finalize_xof().read(&mut expected);
+
+        let state = xdrbg.xdrbg_instantiate_ref(&seed, Some(&alpha)).unwrap();
+        assert_eq!(state.as_bytes(), &expected);
+    }
+
+    #[test]
+    fn test_into_variants_match_allocating_variants() {
+        let xdrbg = Xdrbg::new(Xof::Ascon);
+        let seed = make_seed(24);
+
+        let mut state = [0u8; 32];
+        xdrbg
+            .xdrbg_instantiate_into(&seed, Some(b"alpha"), &mut state)
+            .unwrap();
+        assert_eq!(
+            &state[..],
+            xdrbg
+                .xdrbg_instantiate_ref(&seed, Some(b"alpha"))
+                .unwrap()
+                .as_bytes()
+        );
+
+        let mut reseeded_state = [0u8; 32];
+        xdrbg
+            .xdrbg_reseed_into(&state, &seed[..16], None, &mut reseeded_state)
+            .unwrap();
+        assert_eq!(
+            &reseeded_state[..],
+            xdrbg
+                .xdrbg_reseed_ref(&state, &seed[..16], None)
+                .unwrap()
+                .as_bytes()
+        );
+
+        let (mut new_state, mut output) = ([0u8; 32], [0u8; 48]);
+        xdrbg
+            .xdrbg_generate_into(&reseeded_state, None, &mut new_state, &mut output)
+            .unwrap();
+        let (expected_state, expected_output) =
+            xdrbg.xdrbg_generate_ref(&reseeded_state, 48, None).unwrap();
+        assert_eq!(&new_state[..], expected_state.as_bytes());
+        assert_eq!(&output[..], expected_output.as_bytes());
+
+        let mut short_state = [0u8; 16];
+        let result =
+            xdrbg.xdrbg_generate_into(&reseeded_state, None, &mut short_state, &mut output);
+        assert!(matches!(result, Err(InvalidLength(_))));
+    }
+
+    // A user defined XOF: SHAKE128 with custom XDRBG parameters
+    #[derive(Clone, Default)]
+    struct CustomXof(Shake128);
+
+    impl Update for CustomXof {
+        fn update(&mut self, data: &[u8]) {
+            self.0.update(data);
+        }
+    }
+
+    impl ExtendableOutput for CustomXof {
+        type Reader = <Shake128 as ExtendableOutput>::Reader;
+
+        fn finalize_xof(self) -> Self::Reader {
+            self.0.finalize_xof()
+        }
+    }
+
+    impl XdrbgXof for CustomXof {
+        const STATE_SIZE: usize = 32;
+        const MIN_SEED_SIZE_INSTANTIATE: usize = 32;
+        const MIN_SEED_SIZE_RESEED: usize = 32;
+        const MAX_TOTAL_OUTPUT_SIZE: usize = 128;
+    }
+
+    #[test]
+    fn test_typed_xdrbg_accepts_user_defined_xof() {
+        let xdrbg = TypedXdrbg::<CustomXof>::new();
+
+        // The custom parameters apply
+        assert!(xdrbg.xdrbg_instantiate_ref(&make_seed(24), None).is_err());
+        assert_eq!(xdrbg.max_output_key_length(), 96);
+
+        // The construction is the same as over the built in SHAKE128
+        let state = xdrbg.xdrbg_instantiate_ref(&make_seed(32), None).unwrap();
+        assert_eq!(
+            state,
+            Xdrbg::new(Xof::Shake128)
+                .xdrbg_instantiate_ref(&make_seed(32), None)
+                .unwrap()
+        );
     }
 }
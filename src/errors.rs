@@ -1,3 +1,4 @@
+use alloc::string::String;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -16,4 +17,28 @@ pub enum Errors {
 
     #[error("{0}")]
     NoStoredState(String),
+
+    #[error("State Mismatch: {0}")]
+    StateMismatch(String),
+
+    #[error("Rollback Detected: {0}")]
+    RollbackDetected(String),
+
+    #[error("State Conflict: {0}")]
+    StateConflict(String),
+
+    #[error("Storage Failure: {0}")]
+    StorageFailure(String),
+
+    #[error("Malformed State: {0}")]
+    MalformedState(String),
+
+    #[error("Authentication Failure: {0}")]
+    AuthenticationFailure(String),
+
+    #[error("Key Derivation Failure: {0}")]
+    KeyDerivationFailure(String),
+
+    #[error("Invalid Configuration: {0}")]
+    InvalidConfiguration(String),
 }
@@ -0,0 +1,152 @@
+use super::{
+    ChainId, HighWaterMark, KeyChainAlgorithm,
+    hkdf_keychain::HkdfKeyChain,
+    keychain_trait::{BoxedKeyChain, DynKeyChain, KeyChainInfo},
+    prg_keychain::PrgKeyChain,
+    storage_handler::Storage,
+    xdrbg_keychain::XdrbgKeyChain,
+};
+use crate::errors::Errors;
+use alloc::boxed::Box;
+
+// One of the three key chains, picked at runtime, e.g. when a key chain is
+// rebuilt from the algorithm tag of a stored state.
+#[derive(Clone)]
+pub enum AnyKeyChain {
+    Hkdf(HkdfKeyChain),
+    Prg(PrgKeyChain),
+    Xdrbg(XdrbgKeyChain),
+}
+
+impl AnyKeyChain {
+    // The rebuilt key chain does not store its states persistently. It goes
+    // through the builders, so parameters from untrusted input are validated.
+    pub fn from_algorithm(
+        algorithm: KeyChainAlgorithm,
+        chain_id: Option<ChainId>,
+    ) -> Result<Self, Errors> {
+        // The chain ID goes through the builder, so it is validated like the
+        // other parameters.
+        match algorithm {
+            KeyChainAlgorithm::Hkdf {
+                hash_func,
+                output_key_length,
+            } => {
+                let mut builder =
+                    HkdfKeyChain::builder(hash_func).with_output_key_length(output_key_length);
+                if let Some(chain_id) = chain_id {
+                    builder = builder.with_chain_id(chain_id);
+                }
+
+                Ok(Self::Hkdf(builder.build()?))
+            }
+            KeyChainAlgorithm::Prg {
+                security_param_lambda,
+            } => {
+                let mut builder = PrgKeyChain::builder(security_param_lambda);
+                if let Some(chain_id) = chain_id {
+                    builder = builder.with_chain_id(chain_id);
+                }
+
+                Ok(Self::Prg(builder.build()?))
+            }
+            KeyChainAlgorithm::Xdrbg {
+                xof,
+                output_key_length,
+            } => {
+                let mut builder =
+                    XdrbgKeyChain::builder(xof).with_output_key_length(output_key_length);
+                if let Some(chain_id) = chain_id {
+                    builder = builder.with_chain_id(chain_id);
+                }
+
+                Ok(Self::Xdrbg(builder.build()?))
+            }
+        }
+    }
+
+    pub fn with_chain_id(self, chain_id: impl Into<ChainId>) -> Self {
+        match self {
+            Self::Hkdf(key_chain) => Self::Hkdf(key_chain.with_chain_id(chain_id)),
+            Self::Prg(key_chain) => Self::Prg(key_chain.with_chain_id(chain_id)),
+            Self::Xdrbg(key_chain) => Self::Xdrbg(key_chain.with_chain_id(chain_id)),
+        }
+    }
+
+    pub fn as_dyn(&self) -> &dyn DynKeyChain {
+        match self {
+            Self::Hkdf(key_chain) => key_chain,
+            Self::Prg(key_chain) => key_chain,
+            Self::Xdrbg(key_chain) => key_chain,
+        }
+    }
+
+    pub fn into_boxed(self) -> BoxedKeyChain {
+        match self {
+            Self::Hkdf(key_chain) => Box::new(key_chain),
+            Self::Prg(key_chain) => Box::new(key_chain),
+            Self::Xdrbg(key_chain) => Box::new(key_chain),
+        }
+    }
+}
+
+impl KeyChainInfo for AnyKeyChain {
+    fn algorithm(&self) -> KeyChainAlgorithm {
+        self.as_dyn().algorithm()
+    }
+
+    fn chain_id(&self) -> Option<&ChainId> {
+        self.as_dyn().chain_id()
+    }
+
+    fn high_water_mark(&self) -> &HighWaterMark {
+        self.as_dyn().high_water_mark()
+    }
+
+    fn storage(&self) -> Option<&dyn Storage> {
+        self.as_dyn().storage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HashFunc, Xof};
+
+    #[test]
+    fn test_from_algorithm_round_trip() {
+        let algorithms = [
+            KeyChainAlgorithm::Hkdf {
+                hash_func: HashFunc::Sha3_256,
+                output_key_length: 64,
+            },
+            KeyChainAlgorithm::Prg {
+                security_param_lambda: 24,
+            },
+            KeyChainAlgorithm::Xdrbg {
+                xof: Xof::Shake256,
+                output_key_length: 16,
+            },
+        ];
+
+        for algorithm in algorithms {
+            let key_chain =
+                AnyKeyChain::from_algorithm(algorithm, Some(ChainId::from("tenant-a"))).unwrap();
+
+            assert_eq!(key_chain.algorithm(), algorithm);
+            assert_eq!(key_chain.chain_id(), Some(&ChainId::from("tenant-a")));
+            assert_eq!(key_chain.into_boxed().algorithm(), algorithm);
+        }
+    }
+    #[test]
+    fn test_from_algorithm_rejects_empty_chain_id() {
+        let algorithm = KeyChainAlgorithm::Prg {
+            security_param_lambda: 16,
+        };
+
+        assert!(matches!(
+            AnyKeyChain::from_algorithm(algorithm, Some(ChainId::from(""))),
+            Err(Errors::InvalidConfiguration(_))
+        ));
+    }
+}
@@ -0,0 +1,330 @@
+use super::{
+    ChainId, NewState,
+    file_storage::FileStorage,
+    storage_handler::{DefaultStorage, Storage},
+};
+use crate::errors::Errors::{self, StorageFailure};
+use async_trait::async_trait;
+use std::sync::Arc;
+
+// Async counterpart of Storage for backends behind async I/O. The sync
+// backends that may block are moved to Tokio's blocking pool, so they need
+// to be awaited inside a Tokio runtime.
+#[async_trait]
+pub trait AsyncStorage: Send + Sync {
+    async fn store_state(
+        &self,
+        chain_id: &ChainId,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors>;
+
+    async fn fetch_state(&self, chain_id: &ChainId) -> Result<NewState, Errors>;
+
+    // Same contract as Storage::compare_and_swap.
+    async fn compare_and_swap(
+        &self,
+        chain_id: &ChainId,
+        expected_epoch: Option<u64>,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors>;
+
+    async fn list_chains(&self) -> Result<Vec<ChainId>, Errors>;
+
+    async fn delete_state(&self, chain_id: &ChainId) -> Result<(), Errors>;
+}
+
+// Runs any sync Storage on the blocking pool, e.g. an EncryptedStorage or a
+// custom backend.
+#[derive(Clone)]
+pub struct SyncStorageAdapter {
+    storage: Arc<dyn Storage>,
+}
+
+impl SyncStorageAdapter {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    pub fn get_inner(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+}
+
+#[async_trait]
+impl AsyncStorage for SyncStorageAdapter {
+    async fn store_state(
+        &self,
+        chain_id: &ChainId,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors> {
+        let storage: Arc<dyn Storage> = self.storage.clone();
+        let (chain_id, state_of_key_chain) = (chain_id.clone(), state_of_key_chain.clone());
+
+        run_blocking(move || storage.store_state(&chain_id, &state_of_key_chain)).await
+    }
+
+    async fn fetch_state(&self, chain_id: &ChainId) -> Result<NewState, Errors> {
+        let storage: Arc<dyn Storage> = self.storage.clone();
+        let chain_id: ChainId = chain_id.clone();
+
+        run_blocking(move || storage.fetch_state(&chain_id)).await
+    }
+
+    async fn compare_and_swap(
+        &self,
+        chain_id: &ChainId,
+        expected_epoch: Option<u64>,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors> {
+        let storage: Arc<dyn Storage> = self.storage.clone();
+        let (chain_id, state_of_key_chain) = (chain_id.clone(), state_of_key_chain.clone());
+
+        run_blocking(move || {
+            storage.compare_and_swap(&chain_id, expected_epoch, &state_of_key_chain)
+        })
+        .await
+    }
+
+    async fn list_chains(&self) -> Result<Vec<ChainId>, Errors> {
+        let storage: Arc<dyn Storage> = self.storage.clone();
+
+        run_blocking(move || storage.list_chains()).await
+    }
+
+    async fn delete_state(&self, chain_id: &ChainId) -> Result<(), Errors> {
+        let storage: Arc<dyn Storage> = self.storage.clone();
+        let chain_id: ChainId = chain_id.clone();
+
+        run_blocking(move || storage.delete_state(&chain_id)).await
+    }
+}
+
+// The maps are only held for a lookup or an insert, which never blocks for
+// long, so there is no need for the blocking pool.
+#[async_trait]
+impl AsyncStorage for DefaultStorage {
+    async fn store_state(
+        &self,
+        chain_id: &ChainId,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors> {
+        Storage::store_state(self, chain_id, state_of_key_chain)
+    }
+
+    async fn fetch_state(&self, chain_id: &ChainId) -> Result<NewState, Errors> {
+        Storage::fetch_state(self, chain_id)
+    }
+
+    async fn compare_and_swap(
+        &self,
+        chain_id: &ChainId,
+        expected_epoch: Option<u64>,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors> {
+        Storage::compare_and_swap(self, chain_id, expected_epoch, state_of_key_chain)
+    }
+
+    async fn list_chains(&self) -> Result<Vec<ChainId>, Errors> {
+        Storage::list_chains(self)
+    }
+
+    async fn delete_state(&self, chain_id: &ChainId) -> Result<(), Errors> {
+        Storage::delete_state(self, chain_id)
+    }
+}
+
+#[async_trait]
+impl AsyncStorage for FileStorage {
+    async fn store_state(
+        &self,
+        chain_id: &ChainId,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors> {
+        let storage: FileStorage = self.clone();
+        let (chain_id, state_of_key_chain) = (chain_id.clone(), state_of_key_chain.clone());
+
+        run_blocking(move || Storage::store_state(&storage, &chain_id, &state_of_key_chain)).await
+    }
+
+    async fn fetch_state(&self, chain_id: &ChainId) -> Result<NewState, Errors> {
+        let storage: FileStorage = self.clone();
+        let chain_id: ChainId = chain_id.clone();
+
+        run_blocking(move || Storage::fetch_state(&storage, &chain_id)).await
+    }
+
+    async fn compare_and_swap(
+        &self,
+        chain_id: &ChainId,
+        expected_epoch: Option<u64>,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors> {
+        let storage: FileStorage = self.clone();
+        let (chain_id, state_of_key_chain) = (chain_id.clone(), state_of_key_chain.clone());
+
+        run_blocking(move || {
+            Storage::compare_and_swap(&storage, &chain_id, expected_epoch, &state_of_key_chain)
+        })
+        .await
+    }
+
+    async fn list_chains(&self) -> Result<Vec<ChainId>, Errors> {
+        let storage: FileStorage = self.clone();
+
+        run_blocking(move || Storage::list_chains(&storage)).await
+    }
+
+    async fn delete_state(&self, chain_id: &ChainId) -> Result<(), Errors> {
+        let storage: FileStorage = self.clone();
+        let chain_id: ChainId = chain_id.clone();
+
+        run_blocking(move || Storage::delete_state(&storage, &chain_id)).await
+    }
+}
+
+async fn run_blocking<T, F>(storage_operation: F) -> Result<T, Errors>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Errors> + Send + 'static,
+{
+    tokio::task::spawn_blocking(storage_operation)
+        .await
+        .map_err(|err| StorageFailure(format!("Storage task failed: {}", err)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        HashFunc, Xof,
+        key_chains::{
+            hkdf_keychain::HkdfKeyChain, keychain_trait::KeyChainInfo, prg_keychain::PrgKeyChain,
+            xdrbg_keychain::XdrbgKeyChain,
+        },
+    };
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_async_updates_match_sync_updates() {
+        let storage = DefaultStorage::new_all();
+
+        // The sync updates run on fresh key chains, as the async ones already
+        // moved past the states they start from
+
+        let hkdf_kc = HkdfKeyChain::new(HashFunc::Sha256, None, None, None).unwrap();
+        let hkdf_state = hkdf_kc
+            .key_chain_instantiate_async_ref(b"seed", None, None, &storage)
+            .await
+            .unwrap();
+        let (hkdf_new_state, hkdf_key) = hkdf_kc
+            .key_chain_update_async_ref(b"input", &hkdf_state, None, None, &storage)
+            .await
+            .unwrap();
+        assert_eq!(
+            (hkdf_new_state.clone(), hkdf_key),
+            HkdfKeyChain::new(HashFunc::Sha256, None, None, None)
+                .unwrap()
+                .key_chain_update_ref(b"input", &hkdf_state, None, None)
+                .unwrap()
+        );
+
+        let prg_kc = PrgKeyChain::new(16, None, None).unwrap();
+        let prg_state = prg_kc
+            .key_chain_instantiate_async(&[0x11; 16], &storage)
+            .await
+            .unwrap();
+        let (prg_new_state, prg_key) = prg_kc
+            .key_chain_update_async(&[0x22; 16], &prg_state, &storage)
+            .await
+            .unwrap();
+        assert_eq!(
+            (prg_new_state.clone(), prg_key),
+            PrgKeyChain::new(16, None, None)
+                .unwrap()
+                .key_chain_update(&[0x22; 16], &prg_state)
+                .unwrap()
+        );
+
+        let xdrbg_kc = XdrbgKeyChain::new(Xof::Shake128, None, None, None).unwrap();
+        let xdrbg_state = xdrbg_kc
+            .key_chain_instantiate_async_ref(&[0x33; 24], None, &storage)
+            .await
+            .unwrap();
+        let (xdrbg_new_state, xdrbg_key) = xdrbg_kc
+            .key_chain_update_async_ref(&[0x44; 16], &xdrbg_state, None, None, &storage)
+            .await
+            .unwrap();
+        assert_eq!(
+            (xdrbg_new_state.clone(), xdrbg_key),
+            XdrbgKeyChain::new(Xof::Shake128, None, None, None)
+                .unwrap()
+                .key_chain_update_ref(&[0x44; 16], &xdrbg_state, None, None)
+                .unwrap()
+        );
+
+        assert_eq!(
+            AsyncStorage::fetch_state(&storage, &hkdf_kc.storage_chain_id())
+                .await
+                .unwrap(),
+            hkdf_new_state
+        );
+        assert_eq!(
+            AsyncStorage::fetch_state(&storage, &prg_kc.storage_chain_id())
+                .await
+                .unwrap(),
+            prg_new_state
+        );
+        assert_eq!(
+            AsyncStorage::fetch_state(&storage, &xdrbg_kc.storage_chain_id())
+                .await
+                .unwrap(),
+            xdrbg_new_state
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_and_adapter_share_states() {
+        let directory =
+            std::env::temp_dir().join(format!("keychains_rs_async_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+
+        let file_storage = FileStorage::new(&directory).unwrap();
+        let adapter = SyncStorageAdapter::new(Arc::new(file_storage.clone()));
+
+        let key_chain = HkdfKeyChain::new(HashFunc::Sha512, None, None, None)
+            .unwrap()
+            .with_chain_id("tenant-a");
+        let initial_state = key_chain
+            .key_chain_instantiate_async_ref(b"seed", None, None, &file_storage)
+            .await
+            .unwrap();
+        assert!(matches!(
+            key_chain
+                .key_chain_instantiate_async_ref(b"seed", None, None, &adapter)
+                .await,
+            Err(Errors::StateConflict(_))
+        ));
+        let (new_state, _) = key_chain
+            .key_chain_update_async_ref(b"input", &initial_state, None, None, &adapter)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            AsyncStorage::fetch_state(&file_storage, &"tenant-a".into())
+                .await
+                .unwrap(),
+            new_state
+        );
+        assert_eq!(
+            adapter.list_chains().await.unwrap(),
+            vec![ChainId::from("tenant-a")]
+        );
+
+        AsyncStorage::delete_state(&file_storage, &"tenant-a".into())
+            .await
+            .unwrap();
+        assert!(adapter.fetch_state(&"tenant-a".into()).await.is_err());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
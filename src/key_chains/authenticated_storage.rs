@@ -0,0 +1,231 @@
+use super::{
+    ChainId, NewState,
+    state_encoding::{decode_state, encode_state},
+    storage_handler::{BlobStorage, Storage, check_expected_epoch},
+};
+use crate::{
+    errors::Errors::{self, AuthenticationFailure, InvalidLength, MalformedState, NoStoredState},
+    secret_bytes::SecretBytes,
+};
+use alloc::{format, vec::Vec};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const MAC_KEY_LENGTH: usize = 32;
+
+const TAG_LENGTH: usize = 32;
+const TAG_DOMAIN: &[u8] = b"keychains_rs/state-tag";
+
+// Appends an HMAC-SHA256 tag to every state, computed under a MAC key that is
+// kept apart from the storage backend. The tag covers the chain ID and the
+// encoded state including its epoch, so a tampered, forged or moved state is
+// rejected on load. A genuine older state still verifies; the high water mark
+// of the key chain catches those. Unlike EncryptedStorage, the states remain
+// readable by the backend.
+pub struct AuthenticatedStorage<B: BlobStorage> {
+    inner: B,
+    mac_key: SecretBytes,
+}
+
+impl<B: BlobStorage> AuthenticatedStorage<B> {
+    pub fn new(inner: B, mac_key: SecretBytes) -> Result<Self, Errors> {
+        if mac_key.len() != MAC_KEY_LENGTH {
+            return Err(InvalidLength(format!(
+                "Provided a MAC key of {} bytes. Expected MAC key length is {} bytes.",
+                mac_key.len(),
+                MAC_KEY_LENGTH
+            )));
+        }
+
+        Ok(Self { inner, mac_key })
+    }
+
+    pub fn get_inner(&self) -> &B {
+        &self.inner
+    }
+
+    fn tag(&self, chain_id: &ChainId, encoded_state: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.mac_key).unwrap();
+        mac.update(TAG_DOMAIN);
+        mac.update(&(chain_id.as_str().len() as u32).to_be_bytes());
+        mac.update(chain_id.as_str().as_bytes());
+        mac.update(encoded_state);
+        mac
+    }
+
+    fn seal(&self, chain_id: &ChainId, state_of_key_chain: &NewState) -> SecretBytes {
+        let encoded_state: SecretBytes = encode_state(state_of_key_chain);
+        let tag = self.tag(chain_id, &encoded_state).finalize().into_bytes();
+
+        [encoded_state.as_bytes(), tag.as_slice()].concat().into()
+    }
+
+    fn open(&self, chain_id: &ChainId, tagged_state: &[u8]) -> Result<NewState, Errors> {
+        if tagged_state.len() < TAG_LENGTH {
+            return Err(MalformedState(format!(
+                "Tagged state of {} bytes is too short",
+                tagged_state.len()
+            )));
+        }
+
+        let (encoded_state, tag) = tagged_state.split_at(tagged_state.len() - TAG_LENGTH);
+
+        self.tag(chain_id, encoded_state)
+            .verify_slice(tag)
+            .map_err(|_| {
+                AuthenticationFailure(format!("State of chain {} failed to verify", chain_id))
+            })?;
+
+        decode_state(encoded_state)
+    }
+}
+
+impl<B: BlobStorage> Storage for AuthenticatedStorage<B> {
+    fn store_state(&self, chain_id: &ChainId, state_of_key_chain: &NewState) -> Result<(), Errors> {
+        self.inner
+            .store_blob(chain_id, &self.seal(chain_id, state_of_key_chain))
+    }
+
+    fn fetch_state(&self, chain_id: &ChainId) -> Result<NewState, Errors> {
+        self.open(chain_id, &self.inner.fetch_blob(chain_id)?)
+    }
+
+    fn compare_and_swap(
+        &self,
+        chain_id: &ChainId,
+        expected_epoch: Option<u64>,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors> {
+        let current_blob: Option<SecretBytes> = match self.inner.fetch_blob(chain_id) {
+            Ok(tagged_state) => Some(tagged_state),
+            Err(NoStoredState(_)) => None,
+            Err(err) => return Err(err),
+        };
+
+        let stored_epoch: Option<u64> = match &current_blob {
+            Some(tagged_state) => Some(self.open(chain_id, tagged_state)?.get_epoch()),
+            None => None,
+        };
+        check_expected_epoch(chain_id, expected_epoch, stored_epoch)?;
+
+        self.inner.compare_and_swap_blob(
+            chain_id,
+            current_blob.as_deref(),
+            &self.seal(chain_id, state_of_key_chain),
+        )
+    }
+
+    fn list_chains(&self) -> Result<Vec<ChainId>, Errors> {
+        self.inner.list_blobs()
+    }
+
+    fn delete_state(&self, chain_id: &ChainId) -> Result<(), Errors> {
+        self.inner.delete_blob(chain_id)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{
+        HashFunc,
+        errors::Errors::RollbackDetected,
+        key_chains::{
+            file_storage::FileStorage, hkdf_keychain::HkdfKeyChain,
+            keychain_session::KeyChainSession, keychain_trait::KeyChain,
+        },
+    };
+    use std::{fs, path::PathBuf, sync::Arc};
+
+    fn temp_directory(test_name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("keychains_rs_{}_{}", test_name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    #[test]
+    fn test_tampered_state_is_rejected() {
+        let directory = temp_directory("authenticated_tampering");
+        let storage = AuthenticatedStorage::new(
+            FileStorage::new(&directory).unwrap(),
+            SecretBytes::from(vec![0x11; MAC_KEY_LENGTH]),
+        )
+        .unwrap();
+        let chain_id = ChainId::from("tenant-a");
+        let state = HkdfKeyChain::new(HashFunc::Sha256, None, None, None)
+            .unwrap()
+            .with_chain_id("tenant-a")
+            .key_chain_instantiate_ref(b"seed", None, None)
+            .unwrap();
+
+        storage.store_state(&chain_id, &state).unwrap();
+        assert_eq!(storage.fetch_state(&chain_id).unwrap(), state);
+
+        let mut tagged_state: Vec<u8> = storage.get_inner().fetch_blob(&chain_id).unwrap().to_vec();
+        tagged_state[0] ^= 0x01;
+        storage
+            .get_inner()
+            .store_blob(&chain_id, &tagged_state)
+            .unwrap();
+
+        assert!(matches!(
+            storage.fetch_state(&chain_id),
+            Err(AuthenticationFailure(_))
+        ));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_restored_backup_is_rejected() {
+        let directory = temp_directory("authenticated_rollback");
+        let storage = Arc::new(
+            AuthenticatedStorage::new(
+                FileStorage::new(&directory).unwrap(),
+                SecretBytes::from(vec![0x22; MAC_KEY_LENGTH]),
+            )
+            .unwrap(),
+        );
+        let key_chain =
+            HkdfKeyChain::new(HashFunc::Sha256, None, Some(true), Some(storage.clone()))
+                .unwrap()
+                .with_chain_id("tenant-a");
+        let mut session = key_chain
+            .clone()
+            .instantiate_or_resume_ref(b"seed", None, None)
+            .unwrap();
+        session.next_key(b"input 1").unwrap();
+
+        let backup: SecretBytes = storage.get_inner().fetch_blob(&"tenant-a".into()).unwrap();
+        session.next_key(b"input 2").unwrap();
+
+        // The backup still verifies, but is behind the high water mark
+        storage
+            .get_inner()
+            .store_blob(&"tenant-a".into(), &backup)
+            .unwrap();
+
+        assert!(matches!(
+            key_chain.fetch_stored_state(storage.as_ref()),
+            Err(RollbackDetected(_))
+        ));
+        assert!(matches!(
+            KeyChainSession::from_storage(key_chain.clone()),
+            Err(RollbackDetected(_))
+        ));
+
+        // A restarted process seeds the mark from a trusted counter
+        let restarted_key_chain =
+            HkdfKeyChain::new(HashFunc::Sha256, None, Some(true), Some(storage.clone()))
+                .unwrap()
+                .with_chain_id("tenant-a")
+                .with_high_water_mark(2);
+        assert!(matches!(
+            KeyChainSession::from_storage(restarted_key_chain),
+            Err(RollbackDetected(_))
+        ));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
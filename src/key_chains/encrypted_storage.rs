@@ -0,0 +1,430 @@
+use super::{
+    ChainId, NewState,
+    state_encoding::{decode_state, encode_state},
+    storage_handler::{BlobStorage, Storage, check_expected_epoch},
+};
+use crate::{
+    errors::Errors::{self, AuthenticationFailure, InvalidLength, MalformedState, NoStoredState},
+    secret_bytes::SecretBytes,
+};
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use rand_core::{OsRng, RngCore};
+use std::sync::RwLock;
+
+pub const KEK_LENGTH: usize = 32;
+
+const EPOCH_LENGTH: usize = 8;
+const NONCE_LENGTH: usize = 24;
+
+// Seals every state with XChaCha20-Poly1305 under a key-encryption key (KEK)
+// before handing it to the inner backend. A sealed state is laid out as
+//   epoch (8, big endian) | random nonce (24) | ciphertext and tag
+// and the chain ID and the epoch are bound as associated data. A state that
+// was moved to another chain, or whose epoch was altered, fails to open.
+pub struct EncryptedStorage<B: BlobStorage> {
+    inner: B,
+    kek: RwLock<SecretBytes>,
+}
+
+impl<B: BlobStorage> EncryptedStorage<B> {
+    pub fn new(inner: B, kek: SecretBytes) -> Result<Self, Errors> {
+        check_kek_length(&kek)?;
+
+        Ok(Self {
+            inner,
+            kek: RwLock::new(kek),
+        })
+    }
+
+    pub fn get_inner(&self) -> &B {
+        &self.inner
+    }
+
+    // Re-encrypts all stored states under new_kek, which then replaces the
+    // current KEK. States that already open under new_kek are skipped, so an
+    // interrupted run can simply be repeated.
+    pub fn rewrap_all(&self, new_kek: SecretBytes) -> Result<(), Errors> {
+        check_kek_length(&new_kek)?;
+
+        let mut kek = self.kek.write().unwrap();
+
+        for chain_id in self.inner.list_blobs()? {
+            let sealed_state: SecretBytes = self.inner.fetch_blob(&chain_id)?;
+
+            if open(&new_kek, &chain_id, &sealed_state).is_ok() {
+                continue;
+            }
+
+            let encoded_state: SecretBytes = open(&kek, &chain_id, &sealed_state)?;
+            let epoch: u64 = read_epoch(&sealed_state)?;
+
+            self.inner.store_blob(
+                &chain_id,
+                &seal(&new_kek, &chain_id, epoch, &encoded_state)?,
+            )?;
+        }
+
+        *kek = new_kek;
+
+        Ok(())
+    }
+}
+
+impl<B: BlobStorage> Storage for EncryptedStorage<B> {
+    fn store_state(&self, chain_id: &ChainId, state_of_key_chain: &NewState) -> Result<(), Errors> {
+        let kek = self.kek.read().unwrap();
+
+        let sealed_state: Vec<u8> = seal(
+            &kek,
+            chain_id,
+            state_of_key_chain.get_epoch(),
+            &encode_state(state_of_key_chain),
+        )?;
+
+        self.inner.store_blob(chain_id, &sealed_state)
+    }
+
+    fn fetch_state(&self, chain_id: &ChainId) -> Result<NewState, Errors> {
+        let kek = self.kek.read().unwrap();
+
+        let sealed_state: SecretBytes = self.inner.fetch_blob(chain_id)?;
+
+        decode_state(&open(&kek, chain_id, &sealed_state)?)
+    }
+
+    // The sealed states carry random nonces, so comparing blobs pins the exact
+    // state that was checked.
+    fn compare_and_swap(
+        &self,
+        chain_id: &ChainId,
+        expected_epoch: Option<u64>,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors> {
+        let kek = self.kek.read().unwrap();
+
+        let current_blob: Option<SecretBytes> = match self.inner.fetch_blob(chain_id) {
+            Ok(sealed_state) => Some(sealed_state),
+            Err(NoStoredState(_)) => None,
+            Err(err) => return Err(err),
+        };
+
+        let stored_epoch: Option<u64> = match &current_blob {
+            Some(sealed_state) => {
+                open(&kek, chain_id, sealed_state)?;
+                Some(read_epoch(sealed_state)?)
+            }
+            None => None,
+        };
+        check_expected_epoch(chain_id, expected_epoch, stored_epoch)?;
+
+        let sealed_state: Vec<u8> = seal(
+            &kek,
+            chain_id,
+            state_of_key_chain.get_epoch(),
+            &encode_state(state_of_key_chain),
+        )?;
+
+        self.inner
+            .compare_and_swap_blob(chain_id, current_blob.as_deref(), &sealed_state)
+    }
+
+    fn list_chains(&self) -> Result<Vec<ChainId>, Errors> {
+        self.inner.list_blobs()
+    }
+
+    fn delete_state(&self, chain_id: &ChainId) -> Result<(), Errors> {
+        self.inner.delete_blob(chain_id)
+    }
+}
+
+pub(crate) fn check_kek_length(kek: &SecretBytes) -> Result<(), Errors> {
+    if kek.len() != KEK_LENGTH {
+        return Err(InvalidLength(format!(
+            "Provided a KEK of {} bytes. Expected KEK length is {} bytes.",
+            kek.len(),
+            KEK_LENGTH
+        )));
+    }
+
+    Ok(())
+}
+
+fn associated_data(chain_id: &ChainId, epoch: u64) -> Vec<u8> {
+    let chain_id: &[u8] = chain_id.as_str().as_bytes();
+
+    [
+        &(chain_id.len() as u32).to_be_bytes()[..],
+        chain_id,
+        &epoch.to_be_bytes(),
+    ]
+    .concat()
+}
+
+pub(crate) fn seal(
+    kek: &SecretBytes,
+    chain_id: &ChainId,
+    epoch: u64,
+    encoded_state: &[u8],
+) -> Result<Vec<u8>, Errors> {
+    let cipher = XChaCha20Poly1305::new_from_slice(kek)
+        .map_err(|_| InvalidLength(format!("Expected KEK length is {} bytes.", KEK_LENGTH)))?;
+
+    let mut nonce = [0u8; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+
+    let ciphertext: Vec<u8> = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: encoded_state,
+                aad: &associated_data(chain_id, epoch),
+            },
+        )
+        .map_err(|_| {
+            AuthenticationFailure(format!("Failed to seal state of chain {}", chain_id))
+        })?;
+
+    Ok([&epoch.to_be_bytes()[..], &nonce, &ciphertext].concat())
+}
+
+pub(crate) fn open(
+    kek: &SecretBytes,
+    chain_id: &ChainId,
+    sealed_state: &[u8],
+) -> Result<SecretBytes, Errors> {
+    let epoch: u64 = read_epoch(sealed_state)?;
+    let (nonce, ciphertext) = sealed_state[EPOCH_LENGTH..].split_at(NONCE_LENGTH);
+
+    let cipher = XChaCha20Poly1305::new_from_slice(kek)
+        .map_err(|_| InvalidLength(format!("Expected KEK length is {} bytes.", KEK_LENGTH)))?;
+
+    cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &associated_data(chain_id, epoch),
+            },
+        )
+        .map(SecretBytes::from)
+        .map_err(|_| {
+            AuthenticationFailure(format!(
+                "Sealed state of chain {} failed to authenticate",
+                chain_id
+            ))
+        })
+}
+
+fn read_epoch(sealed_state: &[u8]) -> Result<u64, Errors> {
+    if sealed_state.len() < EPOCH_LENGTH + NONCE_LENGTH {
+        return Err(MalformedState(format!(
+            "Sealed state of {} bytes is too short",
+            sealed_state.len()
+        )));
+    }
+
+    Ok(u64::from_be_bytes(
+        sealed_state[..EPOCH_LENGTH].try_into().unwrap(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        HashFunc,
+        errors::Errors::StateConflict,
+        key_chains::{
+            file_storage::FileStorage, hkdf_keychain::HkdfKeyChain,
+            keychain_session::KeyChainSession,
+        },
+    };
+    use std::{fs, path::PathBuf, sync::Arc};
+
+    fn temp_directory(test_name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("keychains_rs_{}_{}", test_name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn sample_state(chain_id: &str) -> NewState {
+        HkdfKeyChain::new(HashFunc::Sha256, None, None, None)
+            .unwrap()
+            .with_chain_id(chain_id)
+            .key_chain_instantiate_ref(b"seed", None, None)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_states_are_not_stored_in_plaintext() {
+        let directory = temp_directory("encrypted_plaintext");
+        let storage = Arc::new(
+            EncryptedStorage::new(
+                FileStorage::new(&directory).unwrap(),
+                SecretBytes::from(vec![0x11; KEK_LENGTH]),
+            )
+            .unwrap(),
+        );
+
+        let key_chain =
+            HkdfKeyChain::new(HashFunc::Sha256, None, Some(true), Some(storage.clone()))
+                .unwrap()
+                .with_chain_id("tenant-a");
+        let mut session =
+            KeyChainSession::instantiate(key_chain.clone(), b"seed", Default::default()).unwrap();
+        session.next_key(b"input").unwrap();
+
+        let chain_id = ChainId::from("tenant-a");
+        let stored_state = storage.fetch_state(&chain_id).unwrap();
+        let sealed_state = storage.get_inner().fetch_blob(&chain_id).unwrap();
+
+        assert_eq!(stored_state.get_epoch(), 1);
+        assert!(
+            !sealed_state
+                .windows(stored_state.len())
+                .any(|window| window == stored_state.as_bytes())
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_tampering_and_wrong_kek_are_detected() {
+        let directory = temp_directory("encrypted_tampering");
+        let storage = EncryptedStorage::new(
+            FileStorage::new(&directory).unwrap(),
+            SecretBytes::from(vec![0x22; KEK_LENGTH]),
+        )
+        .unwrap();
+
+        storage
+            .store_state(&"tenant-a".into(), &sample_state("tenant-a"))
+            .unwrap();
+        storage
+            .store_state(&"tenant-b".into(), &sample_state("tenant-b"))
+            .unwrap();
+
+        // Swapping the sealed states of two chains
+        let inner = storage.get_inner();
+        let sealed_a = inner.fetch_blob(&"tenant-a".into()).unwrap();
+        inner.store_blob(&"tenant-b".into(), &sealed_a).unwrap();
+        assert!(matches!(
+            storage.fetch_state(&"tenant-b".into()),
+            Err(AuthenticationFailure(_))
+        ));
+
+        // Altering the epoch in the header
+        let mut sealed_a = sealed_a.to_vec();
+        sealed_a[EPOCH_LENGTH - 1] ^= 1;
+        inner.store_blob(&"tenant-a".into(), &sealed_a).unwrap();
+        assert!(matches!(
+            storage.fetch_state(&"tenant-a".into()),
+            Err(AuthenticationFailure(_))
+        ));
+
+        let other_kek_storage = EncryptedStorage::new(
+            FileStorage::new(&directory).unwrap(),
+            SecretBytes::from(vec![0x33; KEK_LENGTH]),
+        )
+        .unwrap();
+        other_kek_storage
+            .store_state(&"tenant-c".into(), &sample_state("tenant-c"))
+            .unwrap();
+        assert!(matches!(
+            storage.fetch_state(&"tenant-c".into()),
+            Err(AuthenticationFailure(_))
+        ));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_rewrap_all() {
+        let directory = temp_directory("encrypted_rewrap");
+        let old_kek = SecretBytes::from(vec![0x44; KEK_LENGTH]);
+        let new_kek = SecretBytes::from(vec![0x55; KEK_LENGTH]);
+
+        let storage =
+            EncryptedStorage::new(FileStorage::new(&directory).unwrap(), old_kek.clone()).unwrap();
+        for tenant in ["tenant-a", "tenant-b"] {
+            storage
+                .store_state(&tenant.into(), &sample_state(tenant))
+                .unwrap();
+        }
+
+        storage.rewrap_all(new_kek.clone()).unwrap();
+        // Running it again is a no-op
+        storage.rewrap_all(new_kek.clone()).unwrap();
+
+        let reopened_storage =
+            EncryptedStorage::new(FileStorage::new(&directory).unwrap(), new_kek).unwrap();
+        let stale_storage =
+            EncryptedStorage::new(FileStorage::new(&directory).unwrap(), old_kek).unwrap();
+
+        for tenant in ["tenant-a", "tenant-b"] {
+            assert_eq!(
+                reopened_storage.fetch_state(&tenant.into()).unwrap(),
+                sample_state(tenant)
+            );
+            assert!(stale_storage.fetch_state(&tenant.into()).is_err());
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_new_rejects_short_kek() {
+        let directory = temp_directory("encrypted_short_kek");
+        let result = EncryptedStorage::new(
+            FileStorage::new(&directory).unwrap(),
+            SecretBytes::from(vec![0x66; 16]),
+        );
+
+        assert!(matches!(result, Err(InvalidLength(_))));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_compare_and_swap_on_sealed_states() {
+        let directory = temp_directory("encrypted_swap");
+        let storage = EncryptedStorage::new(
+            FileStorage::new(&directory).unwrap(),
+            SecretBytes::from(vec![0x44; KEK_LENGTH]),
+        )
+        .unwrap();
+        let chain_id = ChainId::from("tenant-a");
+        let key_chain = HkdfKeyChain::new(HashFunc::Sha256, None, None, None)
+            .unwrap()
+            .with_chain_id("tenant-a");
+        let initial_state = sample_state("tenant-a");
+        let (state_1, _) = key_chain
+            .key_chain_update_ref(b"input", &initial_state, None, None)
+            .unwrap();
+
+        storage
+            .compare_and_swap(&chain_id, None, &initial_state)
+            .unwrap();
+        storage
+            .compare_and_swap(&chain_id, Some(0), &state_1)
+            .unwrap();
+
+        assert!(matches!(
+            storage.compare_and_swap(&chain_id, Some(0), &state_1),
+            Err(StateConflict(_))
+        ));
+        assert!(matches!(
+            storage
+                .get_inner()
+                .compare_and_swap_blob(&chain_id, None, b"blob"),
+            Err(StateConflict(_))
+        ));
+        assert_eq!(storage.fetch_state(&chain_id).unwrap(), state_1);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
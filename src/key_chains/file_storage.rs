@@ -0,0 +1,423 @@
+use super::{
+    ChainId, NewState,
+    state_encoding::{decode_state, encode_state, hex_decode, hex_encode},
+    storage_handler::{BlobStorage, Storage, check_expected_epoch},
+};
+use crate::{
+    errors::Errors::{self, InvalidLength, NoStoredState, StateConflict, StorageFailure},
+    secret_bytes::SecretBytes,
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+use subtle::ConstantTimeEq;
+
+const STATE_EXTENSION: &str = "state";
+const TEMP_EXTENSION: &str = "tmp";
+const LOCK_FILE_NAME: &str = ".lock";
+// Hex encoded IDs plus the extension must fit the 255 byte file name limit of
+// common file systems.
+const MAX_CHAIN_ID_LENGTH: usize = (255 - STATE_EXTENSION.len() - 1) / 2;
+
+// Keeps one file per chain in a directory. A new state is written to a temp
+// file, synced and renamed over the old one, so a crash leaves either the old
+// or the new state on disk, never a torn one. The replaced state is zeroed
+// afterwards. Note that journaling or copy-on-write file systems and SSD wear
+// leveling may still retain old blocks.
+//
+// An advisory lock on a lock file in the directory serializes writers across
+// processes, and readers take it shared.
+#[derive(Clone)]
+pub struct FileStorage {
+    directory: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(directory: impl Into<PathBuf>) -> Result<Self, Errors> {
+        let directory: PathBuf = directory.into();
+
+        fs::create_dir_all(&directory).map_err(storage_failure("create the storage directory"))?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(directory.join(LOCK_FILE_NAME))
+            .map_err(storage_failure("create the lock file"))?;
+
+        Ok(Self { directory })
+    }
+
+    pub fn get_directory(&self) -> &Path {
+        &self.directory
+    }
+
+    // Chain IDs are hex encoded, so any ID up to MAX_CHAIN_ID_LENGTH bytes
+    // maps to a valid file name. Longer IDs are rejected.
+    fn state_path(&self, chain_id: &ChainId) -> Result<PathBuf, Errors> {
+        if chain_id.as_str().len() > MAX_CHAIN_ID_LENGTH {
+            return Err(InvalidLength(format!(
+                "Chain ID of {} bytes. FileStorage accepts chain IDs of at most {} bytes.",
+                chain_id.as_str().len(),
+                MAX_CHAIN_ID_LENGTH
+            )));
+        }
+
+        Ok(self.directory.join(format!(
+            "{}.{}",
+            hex_encode(chain_id.as_str().as_bytes()),
+            STATE_EXTENSION
+        )))
+    }
+
+    // The lock is released when the returned file is dropped.
+    fn lock(&self, exclusive: bool) -> Result<File, Errors> {
+        let lock_file = File::open(self.directory.join(LOCK_FILE_NAME))
+            .map_err(storage_failure("open the lock file"))?;
+
+        if exclusive {
+            lock_file.lock()
+        } else {
+            lock_file.lock_shared()
+        }
+        .map_err(storage_failure("lock the storage directory"))?;
+
+        Ok(lock_file)
+    }
+
+    // Callers hold the exclusive lock.
+    fn replace_blob(&self, chain_id: &ChainId, blob: &[u8]) -> Result<(), Errors> {
+        let state_path: PathBuf = self.state_path(chain_id)?;
+        let temp_path: PathBuf = state_path.with_extension(TEMP_EXTENSION);
+
+        let mut temp_file: File =
+            create_private_file(&temp_path).map_err(storage_failure("create a temp file"))?;
+        temp_file
+            .write_all(blob)
+            .and_then(|_| temp_file.sync_all())
+            .map_err(storage_failure("write the temp file"))?;
+
+        // Keep the old file open, its contents stay reachable after the rename
+        let old_file: Option<File> = OpenOptions::new().write(true).open(&state_path).ok();
+
+        fs::rename(&temp_path, &state_path).map_err(storage_failure("replace the state file"))?;
+        self.sync_directory()?;
+
+        if let Some(old_file) = old_file {
+            overwrite_with_zeros(old_file).map_err(storage_failure("wipe the old state"))?;
+        }
+
+        Ok(())
+    }
+
+    // Callers hold the lock, shared or exclusive.
+    fn read_blob(&self, chain_id: &ChainId) -> Result<Option<SecretBytes>, Errors> {
+        match fs::read(self.state_path(chain_id)?) {
+            Ok(blob) => Ok(Some(SecretBytes::from(blob))),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(storage_failure("read the state file")(err)),
+        }
+    }
+
+    fn sync_directory(&self) -> Result<(), Errors> {
+        #[cfg(unix)]
+        File::open(&self.directory)
+            .and_then(|directory| directory.sync_all())
+            .map_err(storage_failure("sync the storage directory"))?;
+
+        Ok(())
+    }
+}
+
+impl BlobStorage for FileStorage {
+    fn store_blob(&self, chain_id: &ChainId, blob: &[u8]) -> Result<(), Errors> {
+        let _lock: File = self.lock(true)?;
+
+        self.replace_blob(chain_id, blob)
+    }
+
+    fn fetch_blob(&self, chain_id: &ChainId) -> Result<SecretBytes, Errors> {
+        let _lock: File = self.lock(false)?;
+
+        self.read_blob(chain_id)?
+            .ok_or_else(|| NoStoredState(format!("No state found for chain {}", chain_id)))
+    }
+
+    fn compare_and_swap_blob(
+        &self,
+        chain_id: &ChainId,
+        current_blob: Option<&[u8]>,
+        new_blob: &[u8],
+    ) -> Result<(), Errors> {
+        let _lock: File = self.lock(true)?;
+
+        let blob_is_current: bool = match (self.read_blob(chain_id)?, current_blob) {
+            (None, None) => true,
+            (Some(stored_blob), Some(current_blob)) => {
+                stored_blob.as_bytes().ct_eq(current_blob).into()
+            }
+            _ => false,
+        };
+
+        if !blob_is_current {
+            return Err(StateConflict(format!(
+                "Chain {} was changed by another writer",
+                chain_id
+            )));
+        }
+
+        self.replace_blob(chain_id, new_blob)
+    }
+
+    fn list_blobs(&self) -> Result<Vec<ChainId>, Errors> {
+        let _lock: File = self.lock(false)?;
+
+        let mut chain_ids: Vec<ChainId> = Vec::new();
+
+        for entry in
+            fs::read_dir(&self.directory).map_err(storage_failure("list the storage directory"))?
+        {
+            let path: PathBuf = entry
+                .map_err(storage_failure("list the storage directory"))?
+                .path();
+
+            if path.extension().and_then(|ext| ext.to_str()) != Some(STATE_EXTENSION) {
+                continue;
+            }
+
+            let chain_id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(hex_decode)
+                .and_then(|chain_id| String::from_utf8(chain_id).ok());
+
+            if let Some(chain_id) = chain_id {
+                chain_ids.push(ChainId::from(chain_id));
+            }
+        }
+        chain_ids.sort();
+
+        Ok(chain_ids)
+    }
+
+    fn delete_blob(&self, chain_id: &ChainId) -> Result<(), Errors> {
+        let _lock: File = self.lock(true)?;
+
+        let state_path: PathBuf = self.state_path(chain_id)?;
+
+        let state_file: File = match OpenOptions::new().write(true).open(&state_path) {
+            Ok(state_file) => state_file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(NoStoredState(format!(
+                    "No state found for chain {}",
+                    chain_id
+                )));
+            }
+            Err(err) => return Err(storage_failure("open the state file")(err)),
+        };
+
+        overwrite_with_zeros(state_file).map_err(storage_failure("wipe the state"))?;
+        fs::remove_file(&state_path).map_err(storage_failure("remove the state file"))?;
+        self.sync_directory()
+    }
+}
+
+impl Storage for FileStorage {
+    fn store_state(&self, chain_id: &ChainId, state_of_key_chain: &NewState) -> Result<(), Errors> {
+        self.store_blob(chain_id, &encode_state(state_of_key_chain))
+    }
+
+    fn fetch_state(&self, chain_id: &ChainId) -> Result<NewState, Errors> {
+        decode_state(&self.fetch_blob(chain_id)?)
+    }
+
+    fn compare_and_swap(
+        &self,
+        chain_id: &ChainId,
+        expected_epoch: Option<u64>,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors> {
+        let _lock: File = self.lock(true)?;
+
+        let stored_epoch: Option<u64> = match self.read_blob(chain_id)? {
+            Some(stored_blob) => Some(decode_state(&stored_blob)?.get_epoch()),
+            None => None,
+        };
+        check_expected_epoch(chain_id, expected_epoch, stored_epoch)?;
+
+        self.replace_blob(chain_id, &encode_state(state_of_key_chain))
+    }
+
+    fn list_chains(&self) -> Result<Vec<ChainId>, Errors> {
+        self.list_blobs()
+    }
+
+    fn delete_state(&self, chain_id: &ChainId) -> Result<(), Errors> {
+        self.delete_blob(chain_id)
+    }
+}
+
+fn storage_failure(action: &'static str) -> impl FnOnce(io::Error) -> Errors {
+    move |err| StorageFailure(format!("Failed to {}: {}", action, err))
+}
+
+fn create_private_file(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)
+}
+
+fn overwrite_with_zeros(mut file: File) -> io::Result<()> {
+    let file_length: usize = file.metadata()?.len() as usize;
+
+    file.write_all(&vec![0u8; file_length])?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        HashFunc, Xof,
+        errors::Errors::MalformedState,
+        key_chains::{
+            hkdf_keychain::HkdfKeyChain, keychain_session::KeyChainSession,
+            xdrbg_keychain::XdrbgKeyChain,
+        },
+    };
+    use std::sync::Arc;
+
+    fn temp_directory(test_name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("keychains_rs_{}_{}", test_name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    #[test]
+    fn test_chain_resumes_after_restart() {
+        let directory = temp_directory("resume");
+
+        let storage = Arc::new(FileStorage::new(&directory).unwrap());
+        let key_chain = HkdfKeyChain::new(HashFunc::Sha256, None, Some(true), Some(storage))
+            .unwrap()
+            .with_chain_id("tenant/a");
+        let mut session =
+            KeyChainSession::instantiate(key_chain, b"seed", Default::default()).unwrap();
+        session.next_key(b"input 1").unwrap();
+        let expected_key = session.next_key(b"input 2").unwrap();
+
+        // A fresh storage on the same directory, as after a process restart
+        let storage = Arc::new(FileStorage::new(&directory).unwrap());
+        let key_chain =
+            HkdfKeyChain::new(HashFunc::Sha256, None, Some(true), Some(storage.clone()))
+                .unwrap()
+                .with_chain_id("tenant/a");
+        let mut resumed_session = KeyChainSession::from_storage(key_chain).unwrap();
+
+        // The same chain, replayed without storage
+        let replayed_key_chain = HkdfKeyChain::new(HashFunc::Sha256, None, None, None)
+            .unwrap()
+            .with_chain_id("tenant/a");
+        let mut replayed_session =
+            KeyChainSession::instantiate(replayed_key_chain, b"seed", Default::default()).unwrap();
+        replayed_session.next_key(b"input 1").unwrap();
+        replayed_session.next_key(b"input 2").unwrap();
+
+        assert_eq!(resumed_session.get_epoch(), 2);
+        assert_eq!(
+            resumed_session.next_key(b"input 3").unwrap(),
+            replayed_session.next_key(b"input 3").unwrap()
+        );
+        assert_ne!(expected_key, resumed_session.next_key(b"input 4").unwrap());
+
+        // The session from before the restart is behind now
+        assert!(matches!(
+            session.next_key(b"input 3"),
+            Err(Errors::StateConflict(_))
+        ));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_list_and_delete_leave_no_files_behind() {
+        let directory = temp_directory("list_delete");
+        let storage = FileStorage::new(&directory).unwrap();
+
+        for tenant in ["tenant-b", "tenant-a"] {
+            let key_chain = XdrbgKeyChain::new(Xof::Shake128, None, None, None)
+                .unwrap()
+                .with_chain_id(tenant);
+            let state = key_chain
+                .key_chain_instantiate_ref(&[0x11; 24], None)
+                .unwrap();
+            storage.store_state(&tenant.into(), &state).unwrap();
+            storage.store_state(&tenant.into(), &state).unwrap();
+        }
+
+        assert_eq!(
+            storage.list_chains().unwrap(),
+            vec![ChainId::from("tenant-a"), ChainId::from("tenant-b")]
+        );
+
+        storage.delete_state(&"tenant-a".into()).unwrap();
+        storage.delete_state(&"tenant-b".into()).unwrap();
+
+        assert!(storage.list_chains().unwrap().is_empty());
+        assert!(matches!(
+            storage.fetch_state(&"tenant-a".into()),
+            Err(NoStoredState(_))
+        ));
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_file_is_rejected() {
+        let directory = temp_directory("corrupted");
+        let storage = FileStorage::new(&directory).unwrap();
+        let chain_id = ChainId::from("tenant-a");
+
+        fs::write(storage.state_path(&chain_id).unwrap(), b"not a state").unwrap();
+
+        assert!(matches!(
+            storage.fetch_state(&chain_id),
+            Err(MalformedState(_))
+        ));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+    #[test]
+    fn test_chain_id_too_long_for_file_name_is_rejected() {
+        let directory = temp_directory("long_chain_id");
+        let storage = Arc::new(FileStorage::new(&directory).unwrap());
+        let longest_chain_id = "a".repeat(MAX_CHAIN_ID_LENGTH);
+        let long_chain_id = "a".repeat(MAX_CHAIN_ID_LENGTH + 1);
+
+        let key_chain =
+            HkdfKeyChain::new(HashFunc::Sha256, None, Some(true), Some(storage.clone()))
+                .unwrap()
+                .with_chain_id(longest_chain_id.as_str());
+        key_chain
+            .key_chain_instantiate_ref(b"seed", None, None)
+            .unwrap();
+
+        let key_chain = key_chain.with_chain_id(long_chain_id.as_str());
+        assert!(matches!(
+            key_chain.key_chain_instantiate_ref(b"seed", None, None),
+            Err(InvalidLength(_))
+        ));
+        assert!(matches!(
+            storage.fetch_state(&ChainId::from(long_chain_id.as_str())),
+            Err(InvalidLength(_))
+        ));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,28 +1,50 @@
-use super::{InitialState, NewState, RandomOutput, storage_handler::Storage};
+use super::{
+    ChainId, FixedOutput, FixedState, HighWaterMark, InitialState, KeyChainAlgorithm, NewState,
+    RandomOutput, keychain_session::KeyChainSession, keychain_state::check_update_buffers,
+    keychain_trait::HkdfParams, keychain_transaction::PendingUpdate, storage_handler::Storage,
+};
 use crate::{
-    crypto_primitives::hkdf_wrap_ops::{HashFunc, HkdfWrap},
-    errors::Errors::{self, UninitializedStorage},
+    crypto_primitives::hkdf_wrap_ops::{HashFunc, HkdfDigest, TypedHkdf},
+    errors::Errors::{self, InvalidConfiguration, UninitializedStorage},
+    secret_bytes::SecretBytes,
 };
-use std::sync::Arc;
+use alloc::{format, string::ToString, sync::Arc, vec::Vec};
+use sha2::{Sha256, Sha512, digest::Output};
+use sha3::{Sha3_256, Sha3_512};
+use zeroize::{Zeroize, Zeroizing};
+
+// Dispatches once on the hash function to the monomorphized chain inside.
+macro_rules! with_typed_chain {
+    ($core:expr, $chain:ident => $body:expr) => {
+        match $core {
+            HkdfCore::Sha256($chain) => $body,
+            HkdfCore::Sha512($chain) => $body,
+            HkdfCore::Sha3_256($chain) => $body,
+            HkdfCore::Sha3_512($chain) => $body,
+        }
+    };
+}
 
 #[derive(Clone)]
 pub struct HkdfKeyChain {
-    hkdf_obj: HkdfWrap,
-    output_key_length: usize,
-    state_length: usize,
+    core: HkdfCore,
     store_persistently: bool,
     storage: Option<Arc<dyn Storage>>,
+    chain_id: Option<ChainId>,
+    high_water_mark: HighWaterMark,
 }
 
 impl HkdfKeyChain {
+    pub fn builder(hash_func: HashFunc) -> HkdfKeyChainBuilder {
+        HkdfKeyChainBuilder::new(hash_func)
+    }
+
     pub fn new(
         hash_func: HashFunc,
         output_key_length: Option<usize>,
         store_persistently: Option<bool>,
         storage: Option<Arc<dyn Storage>>,
     ) -> Result<Self, Errors> {
-        let hkdf_obj: HkdfWrap = HkdfWrap::new(hash_func);
-
         let store_persistently: bool = store_persistently.unwrap_or(false);
 
         let storage_choice: Option<Arc<dyn Storage>> = if store_persistently {
@@ -38,73 +60,675 @@ impl HkdfKeyChain {
         };
 
         Ok(Self {
-            hkdf_obj: hkdf_obj,
-            output_key_length: output_key_length.unwrap_or(hash_func.output_size()),
-            state_length: hash_func.output_size(),
+            core: HkdfCore::new(hash_func, output_key_length),
             store_persistently: store_persistently,
             storage: storage_choice,
+            chain_id: None,
+            high_water_mark: HighWaterMark::default(),
         })
     }
 
+    // A different chain, so it does not share the high water mark of this one.
+    pub fn with_chain_id(mut self, chain_id: impl Into<ChainId>) -> Self {
+        self.chain_id = Some(chain_id.into());
+        self.high_water_mark = HighWaterMark::default();
+        self
+    }
+
+    pub fn with_high_water_mark(mut self, epoch: u64) -> Self {
+        self.high_water_mark = HighWaterMark::new(epoch);
+        self
+    }
+
+    // Continues the chain stored under chain_id in the given storage, which
+    // this chain then stores persistently to. The configuration, including the
+    // builder defaults, is that of this chain, so the stored state must have
+    // been produced by the same algorithm. Fails with NoStoredState if nothing
+    // is stored there, and with RollbackDetected if the stored state is below
+    // the high water mark, e.g. one set with with_high_water_mark() from a
+    // trusted source.
+    pub fn resume(
+        self,
+        storage: Arc<dyn Storage>,
+        chain_id: impl Into<ChainId>,
+    ) -> Result<KeyChainSession<Self>, Errors> {
+        let chain_id: ChainId = chain_id.into();
+        let mut key_chain: Self = if self.chain_id.as_ref() == Some(&chain_id) {
+            self
+        } else {
+            self.with_chain_id(chain_id)
+        };
+        key_chain.store_persistently = true;
+        key_chain.storage = Some(storage);
+
+        KeyChainSession::from_storage(key_chain)
+    }
+
+    #[deprecated(
+        note = "Use instantiate_or_resume_ref, which borrows the salt and info parameter."
+    )]
+    pub fn instantiate_or_resume(
+        self,
+        initial_skm: &[u8],
+        extractor_salt: Option<Vec<u8>>,
+        info_param: Option<Vec<u8>>,
+    ) -> Result<KeyChainSession<Self>, Errors> {
+        self.instantiate_or_resume_ref(
+            initial_skm,
+            extractor_salt.as_deref(),
+            info_param.as_deref(),
+        )
+    }
+
+    // Resumes the chain from its storage, or instantiates it there on first
+    // boot. Requires the chain to store persistently.
+    pub fn instantiate_or_resume_ref(
+        self,
+        initial_skm: &[u8],
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+    ) -> Result<KeyChainSession<Self>, Errors> {
+        if self.persistent_storage().is_none() {
+            return Err(UninitializedStorage(
+                "Hkdf keychain storage not initialized.".to_string(),
+            ));
+        }
+
+        KeyChainSession::instantiate_or_resume(
+            self,
+            initial_skm,
+            HkdfParams {
+                extractor_salt: extractor_salt.map(<[u8]>::to_vec),
+                info_param: info_param.map(<[u8]>::to_vec),
+            },
+        )
+    }
+
+    #[deprecated(
+        note = "Use key_chain_instantiate_ref, which borrows the salt and info parameter."
+    )]
     pub fn key_chain_instantiate(
         &self,
         initial_skm: &[u8],
         extractor_salt: Option<Vec<u8>>,
         info_param: Option<Vec<u8>>,
     ) -> Result<InitialState, Errors> {
-        let pseudo_random_key: Vec<u8> = self.hkdf_obj.hkdf_extract(extractor_salt, initial_skm)?;
+        self.key_chain_instantiate_ref(
+            initial_skm,
+            extractor_salt.as_deref(),
+            info_param.as_deref(),
+        )
+    }
 
-        let initial_state: Vec<u8> =
-            self.hkdf_obj
-                .hkdf_expand(&pseudo_random_key, info_param, self.state_length)?;
+    // A persistent chain stores its initial state right away, and only into an
+    // empty slot, so instantiating twice cannot fork a stored chain.
+    pub fn key_chain_instantiate_ref(
+        &self,
+        initial_skm: &[u8],
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+    ) -> Result<InitialState, Errors> {
+        let initial_state: InitialState =
+            self.new_initial_state(initial_skm, extractor_salt, info_param)?;
+
+        if let Some(storage) = self.persistent_storage() {
+            storage.compare_and_swap(&initial_state.storage_chain_id(), None, &initial_state)?;
+        }
 
         Ok(initial_state)
     }
 
+    fn new_initial_state(
+        &self,
+        initial_skm: &[u8],
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+    ) -> Result<InitialState, Errors> {
+        let initial_state: SecretBytes = with_typed_chain!(&self.core, chain => {
+            chain.key_chain_instantiate(initial_skm, extractor_salt, info_param)
+        })?;
+
+        InitialState::new(
+            self.get_algorithm(),
+            self.chain_id.clone(),
+            0,
+            initial_state,
+        )
+    }
+
+    #[deprecated(note = "Use key_chain_update_ref, which borrows the salt and info parameter.")]
     pub fn key_chain_update(
         &self,
         arbitrary_input_param: &[u8],
-        keychain_state: &[u8],
+        keychain_state: &NewState,
         extractor_salt: Option<Vec<u8>>,
         info_param: Option<Vec<u8>>,
     ) -> Result<(NewState, RandomOutput), Errors> {
-        let source_key_material: Vec<u8> = [arbitrary_input_param, keychain_state].concat();
+        self.key_chain_update_ref(
+            arbitrary_input_param,
+            keychain_state,
+            extractor_salt.as_deref(),
+            info_param.as_deref(),
+        )
+    }
 
-        let pseudo_random_key = self
-            .hkdf_obj
-            .hkdf_extract(extractor_salt, &source_key_material)?;
+    pub fn key_chain_update_ref(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+    ) -> Result<(NewState, RandomOutput), Errors> {
+        self.begin_key_chain_update_ref(
+            arbitrary_input_param,
+            keychain_state,
+            extractor_salt,
+            info_param,
+        )?
+        .commit()
+    }
+
+    #[deprecated(
+        note = "Use begin_key_chain_update_ref, which borrows the salt and info parameter."
+    )]
+    pub fn begin_key_chain_update(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        extractor_salt: Option<Vec<u8>>,
+        info_param: Option<Vec<u8>>,
+    ) -> Result<PendingUpdate<'_>, Errors> {
+        self.begin_key_chain_update_ref(
+            arbitrary_input_param,
+            keychain_state,
+            extractor_salt.as_deref(),
+            info_param.as_deref(),
+        )
+    }
+
+    pub fn begin_key_chain_update_ref(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+    ) -> Result<PendingUpdate<'_>, Errors> {
+        keychain_state.check_belongs_to(self.get_algorithm(), self.chain_id.as_ref())?;
+
+        let (new_state_of_key_chain, random_output) = with_typed_chain!(&self.core, chain => {
+            chain.key_chain_update(
+                arbitrary_input_param,
+                keychain_state.as_bytes(),
+                extractor_salt,
+                info_param,
+            )
+        })?;
+
+        Ok(PendingUpdate::new(
+            self.persistent_storage(),
+            &self.high_water_mark,
+            keychain_state.advance(new_state_of_key_chain)?,
+            random_output,
+        ))
+    }
+
+    // Allocation free update on raw state bytes for chains that do not store
+    // persistently. The buffers must be get_state_length() and
+    // get_output_key_length() bytes long. Epochs and rollback checks are left
+    // to the caller.
+    pub fn key_chain_update_into(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8],
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+        new_state: &mut [u8],
+        output_key: &mut [u8],
+    ) -> Result<(), Errors> {
+        if self.store_persistently {
+            return Err(InvalidConfiguration(
+                "Hkdf keychain stores persistently, use key_chain_update_ref.".to_string(),
+            ));
+        }
 
-        let result: Result<Vec<u8>, Errors> = self.hkdf_obj.hkdf_expand(
-            &pseudo_random_key,
+        with_typed_chain!(&self.core, chain => {
+            chain.key_chain_update_into(
+                arbitrary_input_param,
+                current_state,
+                extractor_salt,
+                info_param,
+                new_state,
+                output_key,
+            )
+        })
+    }
+
+    // key_chain_update_into() for state and key lengths known at compile time.
+    pub fn key_chain_update_array<const S: usize, const K: usize>(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8; S],
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+    ) -> Result<(FixedState<S>, FixedOutput<K>), Errors> {
+        let mut new_state: FixedState<S> = Zeroizing::new([0u8; S]);
+        let mut output_key: FixedOutput<K> = Zeroizing::new([0u8; K]);
+        self.key_chain_update_into(
+            arbitrary_input_param,
+            current_state,
+            extractor_salt,
             info_param,
-            self.state_length + self.output_key_length,
-        );
+            new_state.as_mut_slice(),
+            output_key.as_mut_slice(),
+        )?;
 
-        match result {
-            Ok(total_output) => {
-                let (new_state_of_key_chain, random_output) =
-                    total_output.split_at(self.state_length);
-
-                if self.store_persistently {
-                    if let Some(storage) = &self.storage {
-                        storage.store_state_for_hkdf_keychain(
-                            new_state_of_key_chain,
-                            self.hkdf_obj.get_chosen_hash_func(),
-                        );
-                    }
-                }
-                Ok((new_state_of_key_chain.to_vec(), random_output.to_vec()))
-            }
-            Err(err) => Err(err),
+        Ok((new_state, output_key))
+    }
+
+    #[cfg(feature = "async")]
+    #[deprecated(
+        note = "Use key_chain_instantiate_async_ref, which borrows the salt and info parameter."
+    )]
+    pub async fn key_chain_instantiate_async(
+        &self,
+        initial_skm: &[u8],
+        extractor_salt: Option<Vec<u8>>,
+        info_param: Option<Vec<u8>>,
+        storage: &dyn super::async_storage::AsyncStorage,
+    ) -> Result<InitialState, Errors> {
+        self.key_chain_instantiate_async_ref(
+            initial_skm,
+            extractor_salt.as_deref(),
+            info_param.as_deref(),
+            storage,
+        )
+        .await
+    }
+
+    // The async variants persist to the given storage in place of the one
+    // passed to new(). The initial state is persisted as well, only into an
+    // empty slot like key_chain_instantiate.
+    #[cfg(feature = "async")]
+    pub async fn key_chain_instantiate_async_ref(
+        &self,
+        initial_skm: &[u8],
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+        storage: &dyn super::async_storage::AsyncStorage,
+    ) -> Result<InitialState, Errors> {
+        let initial_state: InitialState =
+            self.new_initial_state(initial_skm, extractor_salt, info_param)?;
+        storage
+            .compare_and_swap(&initial_state.storage_chain_id(), None, &initial_state)
+            .await?;
+
+        Ok(initial_state)
+    }
+
+    #[cfg(feature = "async")]
+    #[deprecated(
+        note = "Use key_chain_update_async_ref, which borrows the salt and info parameter."
+    )]
+    pub async fn key_chain_update_async(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        extractor_salt: Option<Vec<u8>>,
+        info_param: Option<Vec<u8>>,
+        storage: &dyn super::async_storage::AsyncStorage,
+    ) -> Result<(NewState, RandomOutput), Errors> {
+        self.key_chain_update_async_ref(
+            arbitrary_input_param,
+            keychain_state,
+            extractor_salt.as_deref(),
+            info_param.as_deref(),
+            storage,
+        )
+        .await
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn key_chain_update_async_ref(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+        storage: &dyn super::async_storage::AsyncStorage,
+    ) -> Result<(NewState, RandomOutput), Errors> {
+        self.begin_key_chain_update_ref(
+            arbitrary_input_param,
+            keychain_state,
+            extractor_salt,
+            info_param,
+        )?
+        .commit_async(storage)
+        .await
+    }
+
+    pub(crate) fn persistent_storage(&self) -> Option<&dyn Storage> {
+        if self.store_persistently {
+            self.storage.as_deref()
+        } else {
+            None
+        }
+    }
+
+    pub fn get_algorithm(&self) -> KeyChainAlgorithm {
+        KeyChainAlgorithm::Hkdf {
+            hash_func: self.get_chosen_hash_func(),
+            output_key_length: self.get_output_key_length(),
+        }
+    }
+
+    pub fn get_chain_id(&self) -> Option<&ChainId> {
+        self.chain_id.as_ref()
+    }
+
+    pub fn get_high_water_mark(&self) -> &HighWaterMark {
+        &self.high_water_mark
+    }
+
+    pub fn get_chosen_hash_func(&self) -> HashFunc {
+        match self.core {
+            HkdfCore::Sha256(_) => HashFunc::Sha256,
+            HkdfCore::Sha512(_) => HashFunc::Sha512,
+            HkdfCore::Sha3_256(_) => HashFunc::Sha3_256,
+            HkdfCore::Sha3_512(_) => HashFunc::Sha3_512,
+        }
+    }
+
+    pub fn get_output_key_length(&self) -> usize {
+        with_typed_chain!(&self.core, chain => chain.get_output_key_length())
+    }
+
+    pub fn get_state_length(&self) -> usize {
+        with_typed_chain!(&self.core, chain => chain.get_state_length())
+    }
+
+    pub fn get_default_params(&self) -> &HkdfParams {
+        with_typed_chain!(&self.core, chain => chain.get_default_params())
+    }
+}
+
+// Typed alternative to new(). Setting a storage makes the chain store its
+// states persistently. The salt and info parameter are used whenever a call
+// passes None for them.
+#[derive(Clone)]
+pub struct HkdfKeyChainBuilder {
+    hash_func: HashFunc,
+    output_key_length: Option<usize>,
+    default_params: HkdfParams,
+    storage: Option<Arc<dyn Storage>>,
+    chain_id: Option<ChainId>,
+    high_water_mark: Option<u64>,
+}
+
+impl HkdfKeyChainBuilder {
+    pub fn new(hash_func: HashFunc) -> Self {
+        Self {
+            hash_func,
+            output_key_length: None,
+            default_params: HkdfParams::default(),
+            storage: None,
+            chain_id: None,
+            high_water_mark: None,
+        }
+    }
+
+    pub fn with_output_key_length(mut self, output_key_length: usize) -> Self {
+        self.output_key_length = Some(output_key_length);
+        self
+    }
+
+    pub fn with_extractor_salt(mut self, extractor_salt: impl Into<Vec<u8>>) -> Self {
+        self.default_params.extractor_salt = Some(extractor_salt.into());
+        self
+    }
+
+    pub fn with_info_param(mut self, info_param: impl Into<Vec<u8>>) -> Self {
+        self.default_params.info_param = Some(info_param.into());
+        self
+    }
+
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn with_chain_id(mut self, chain_id: impl Into<ChainId>) -> Self {
+        self.chain_id = Some(chain_id.into());
+        self
+    }
+
+    pub fn with_high_water_mark(mut self, epoch: u64) -> Self {
+        self.high_water_mark = Some(epoch);
+        self
+    }
+
+    pub fn build(self) -> Result<HkdfKeyChain, Errors> {
+        let output_key_length: usize = self
+            .output_key_length
+            .unwrap_or(self.hash_func.output_size());
+
+        KeyChainAlgorithm::Hkdf {
+            hash_func: self.hash_func,
+            output_key_length,
         }
+        .check_parameters()?;
+
+        if self.chain_id.as_ref().is_some_and(ChainId::is_empty) {
+            return Err(InvalidConfiguration(
+                "Hkdf keychain chain ID must not be empty.".to_string(),
+            ));
+        }
+
+        let mut key_chain: HkdfKeyChain = HkdfKeyChain::new(
+            self.hash_func,
+            Some(output_key_length),
+            Some(self.storage.is_some()),
+            self.storage,
+        )?;
+        key_chain.chain_id = self.chain_id;
+        key_chain.high_water_mark = HighWaterMark::new(self.high_water_mark.unwrap_or(0));
+        with_typed_chain!(&mut key_chain.core, chain => chain.default_params = self.default_params);
+
+        Ok(key_chain)
     }
 }
 
-#[cfg(test)]
+// HKDF key chain monomorphized over the digest D, which may be any RustCrypto
+// hash. It works on raw state bytes: storage, epochs and rollback checks stay
+// with HkdfKeyChain, which dispatches to it for the digests of HashFunc.
+pub struct TypedHkdfKeyChain<D> {
+    hkdf_obj: TypedHkdf<D>,
+    output_key_length: usize,
+    default_params: HkdfParams,
+}
+
+impl<D> Clone for TypedHkdfKeyChain<D> {
+    fn clone(&self) -> Self {
+        Self {
+            hkdf_obj: self.hkdf_obj,
+            output_key_length: self.output_key_length,
+            default_params: self.default_params.clone(),
+        }
+    }
+}
+
+impl<D: HkdfDigest> TypedHkdfKeyChain<D> {
+    // The output key length defaults to the digest size.
+    pub fn new(output_key_length: Option<usize>) -> Self {
+        let hkdf_obj: TypedHkdf<D> = TypedHkdf::new();
+
+        Self {
+            hkdf_obj,
+            output_key_length: output_key_length.unwrap_or(hkdf_obj.output_size()),
+            default_params: HkdfParams::default(),
+        }
+    }
+
+    pub fn with_extractor_salt(mut self, extractor_salt: impl Into<Vec<u8>>) -> Self {
+        self.default_params.extractor_salt = Some(extractor_salt.into());
+        self
+    }
+
+    pub fn with_info_param(mut self, info_param: impl Into<Vec<u8>>) -> Self {
+        self.default_params.info_param = Some(info_param.into());
+        self
+    }
+
+    pub fn key_chain_instantiate(
+        &self,
+        initial_skm: &[u8],
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+    ) -> Result<SecretBytes, Errors> {
+        let mut initial_state: SecretBytes = SecretBytes::zeroed(self.get_state_length());
+        self.derive_into(
+            &[initial_skm],
+            extractor_salt,
+            info_param,
+            &mut [&mut initial_state],
+        )?;
+
+        Ok(initial_state)
+    }
+
+    pub fn key_chain_update(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8],
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+    ) -> Result<(SecretBytes, RandomOutput), Errors> {
+        let mut new_state: SecretBytes = SecretBytes::zeroed(self.get_state_length());
+        let mut random_output: RandomOutput = RandomOutput::zeroed(self.output_key_length);
+        self.derive_into(
+            &[arbitrary_input_param, current_state],
+            extractor_salt,
+            info_param,
+            &mut [&mut new_state, &mut random_output],
+        )?;
+
+        Ok((new_state, random_output))
+    }
+
+    // Allocation free update. The buffers must be get_state_length() and
+    // get_output_key_length() bytes long.
+    pub fn key_chain_update_into(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8],
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+        new_state: &mut [u8],
+        output_key: &mut [u8],
+    ) -> Result<(), Errors> {
+        check_update_buffers(
+            self.get_state_length(),
+            self.output_key_length,
+            current_state,
+            new_state,
+            output_key,
+        )?;
+
+        self.derive_into(
+            &[arbitrary_input_param, current_state],
+            extractor_salt,
+            info_param,
+            &mut [new_state, output_key],
+        )
+    }
+
+    // key_chain_update_into() for state and key lengths known at compile time.
+    pub fn key_chain_update_array<const S: usize, const K: usize>(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8; S],
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+    ) -> Result<(FixedState<S>, FixedOutput<K>), Errors> {
+        let mut new_state: FixedState<S> = Zeroizing::new([0u8; S]);
+        let mut output_key: FixedOutput<K> = Zeroizing::new([0u8; K]);
+        self.key_chain_update_into(
+            arbitrary_input_param,
+            current_state,
+            extractor_salt,
+            info_param,
+            new_state.as_mut_slice(),
+            output_key.as_mut_slice(),
+        )?;
+
+        Ok((new_state, output_key))
+    }
+
+    // Extracts from the concatenated source key parts and expands straight
+    // into the outputs. The pseudo random key lives on the stack.
+    fn derive_into(
+        &self,
+        source_key_parts: &[&[u8]],
+        extractor_salt: Option<&[u8]>,
+        info_param: Option<&[u8]>,
+        outputs: &mut [&mut [u8]],
+    ) -> Result<(), Errors> {
+        let extractor_salt: Option<&[u8]> =
+            extractor_salt.or(self.default_params.extractor_salt.as_deref());
+        let info_param: Option<&[u8]> = info_param.or(self.default_params.info_param.as_deref());
+
+        let mut pseudo_random_key: Output<D> = Output::<D>::default();
+        let result: Result<(), Errors> = self
+            .hkdf_obj
+            .hkdf_extract_parts_into(extractor_salt, source_key_parts, &mut pseudo_random_key)
+            .and_then(|_| {
+                self.hkdf_obj
+                    .hkdf_expand_parts_into(&pseudo_random_key, info_param, outputs)
+            });
+        pseudo_random_key.as_mut_slice().zeroize();
+
+        result
+    }
+
+    pub fn get_output_key_length(&self) -> usize {
+        self.output_key_length
+    }
+
+    pub fn get_state_length(&self) -> usize {
+        self.hkdf_obj.output_size()
+    }
+
+    pub fn get_default_params(&self) -> &HkdfParams {
+        &self.default_params
+    }
+}
+
+#[derive(Clone)]
+enum HkdfCore {
+    Sha256(TypedHkdfKeyChain<Sha256>),
+    Sha512(TypedHkdfKeyChain<Sha512>),
+    Sha3_256(TypedHkdfKeyChain<Sha3_256>),
+    Sha3_512(TypedHkdfKeyChain<Sha3_512>),
+}
+
+impl HkdfCore {
+    fn new(hash_func: HashFunc, output_key_length: Option<usize>) -> Self {
+        match hash_func {
+            HashFunc::Sha256 => Self::Sha256(TypedHkdfKeyChain::new(output_key_length)),
+            HashFunc::Sha512 => Self::Sha512(TypedHkdfKeyChain::new(output_key_length)),
+            HashFunc::Sha3_256 => Self::Sha3_256(TypedHkdfKeyChain::new(output_key_length)),
+            HashFunc::Sha3_512 => Self::Sha3_512(TypedHkdfKeyChain::new(output_key_length)),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
+    use crate::Xof;
     use crate::crypto_primitives::hkdf_wrap_ops::HashFunc;
+    use crate::key_chains::keychain_trait::KeyChainInfo;
     use crate::key_chains::storage_handler::{DefaultStorage, KeyChainType, Storage};
+    use crate::key_chains::xdrbg_keychain::XdrbgKeyChain;
     use std::sync::Arc;
 
     fn sample_input() -> Vec<u8> {
@@ -115,7 +739,7 @@ mod tests {
     fn test_instantiate_without_storage() {
         let keychain = HkdfKeyChain::new(HashFunc::Sha256, None, Some(false), None).unwrap();
         let initial_state = keychain
-            .key_chain_instantiate(&sample_input(), None, None)
+            .key_chain_instantiate_ref(&sample_input(), None, None)
             .unwrap();
         assert_eq!(initial_state.len(), HashFunc::Sha256.output_size());
     }
@@ -138,11 +762,11 @@ mod tests {
             HkdfKeyChain::new(HashFunc::Sha256, None, Some(true), Some(storage.clone())).unwrap();
 
         let initial_state = keychain
-            .key_chain_instantiate(&sample_input(), None, None)
+            .key_chain_instantiate_ref(&sample_input(), None, None)
             .unwrap();
 
         let (new_state, random_output) = keychain
-            .key_chain_update(b"update", &initial_state, None, None)
+            .key_chain_update_ref(b"update", &initial_state, None, None)
             .unwrap();
 
         // Check output lengths
@@ -150,7 +774,7 @@ mod tests {
         assert_eq!(random_output.len(), HashFunc::Sha256.output_size());
 
         // Check that state is stored in storage
-        let fetched = storage.fetch_hkdf_keychain_state(HashFunc::Sha256).unwrap();
+        let fetched = storage.fetch_state(&keychain.storage_chain_id()).unwrap();
         assert_eq!(fetched, new_state);
     }
 
@@ -158,11 +782,11 @@ mod tests {
     fn test_update_non_persistent_storage() {
         let keychain = HkdfKeyChain::new(HashFunc::Sha256, None, Some(false), None).unwrap();
         let initial_state = keychain
-            .key_chain_instantiate(&sample_input(), None, None)
+            .key_chain_instantiate_ref(&sample_input(), None, None)
             .unwrap();
 
         let (new_state, random_output) = keychain
-            .key_chain_update(b"update", &initial_state, None, None)
+            .key_chain_update_ref(b"update", &initial_state, None, None)
             .unwrap();
 
         assert_eq!(new_state.len(), HashFunc::Sha256.output_size());
@@ -172,12 +796,324 @@ mod tests {
     #[test]
     fn test_storage_fetch_error() {
         let storage = Arc::new(DefaultStorage::new(KeyChainType::HkdfKeyChain));
-        let err = storage
-            .fetch_hkdf_keychain_state(HashFunc::Sha512)
-            .unwrap_err();
+        let err = storage.fetch_state(&"hkdf/Sha512".into()).unwrap_err();
         match err {
-            Errors::NoStoredState(msg) => assert!(msg.contains("No Hkdf state found")),
+            Errors::NoStoredState(msg) => assert!(msg.contains("No state found for chain")),
             _ => panic!("Expected NoStoredState error"),
         }
     }
+
+    #[test]
+    fn test_update_rejects_state_of_other_algorithm() {
+        let xdrbg_kc = XdrbgKeyChain::new(Xof::Shake128, None, None, None).unwrap();
+        let xdrbg_state = xdrbg_kc
+            .key_chain_instantiate_ref(&[0x11; 24], None)
+            .unwrap();
+
+        // Both states are 32 bytes long, only the tag tells them apart
+        let keychain = HkdfKeyChain::new(HashFunc::Sha256, None, None, None).unwrap();
+        let result = keychain.key_chain_update_ref(b"update", &xdrbg_state, None, None);
+
+        assert!(matches!(result, Err(Errors::StateMismatch(_))));
+    }
+
+    #[test]
+    fn test_resume_restores_config_and_state() {
+        let storage = Arc::new(DefaultStorage::new_all());
+        let builder = || {
+            HkdfKeyChain::builder(HashFunc::Sha512)
+                .with_output_key_length(48)
+                .with_extractor_salt(b"salt".to_vec())
+                .with_info_param(b"info".to_vec())
+        };
+        let keychain = builder()
+            .with_storage(storage.clone())
+            .with_chain_id("tenant-a")
+            .build()
+            .unwrap();
+        let mut session = keychain
+            .instantiate_or_resume_ref(b"seed", None, None)
+            .unwrap();
+        session.next_key(b"input 1").unwrap();
+
+        let stored_state: NewState = storage.fetch_state(&"tenant-a".into()).unwrap();
+        let (_, expected_key) = builder()
+            .with_chain_id("tenant-a")
+            .build()
+            .unwrap()
+            .key_chain_update_ref(b"input 2", &stored_state, None, None)
+            .unwrap();
+
+        let mut resumed_session = builder()
+            .build()
+            .unwrap()
+            .resume(storage.clone(), "tenant-a")
+            .unwrap();
+
+        assert_eq!(resumed_session.get_epoch(), 1);
+        assert_eq!(resumed_session.next_key(b"input 2").unwrap(), expected_key);
+        assert!(matches!(
+            session.next_key(b"input 2"),
+            Err(Errors::StateConflict(_))
+        ));
+        assert!(matches!(
+            builder().build().unwrap().resume(storage, "tenant-b"),
+            Err(Errors::NoStoredState(_))
+        ));
+    }
+
+    #[test]
+    fn test_resume_checks_high_water_mark_and_algorithm() {
+        let storage = Arc::new(DefaultStorage::new_all());
+        let keychain = HkdfKeyChain::new(HashFunc::Sha256, None, Some(true), Some(storage.clone()))
+            .unwrap()
+            .with_chain_id("tenant-a");
+        let mut session = keychain
+            .instantiate_or_resume_ref(b"seed", None, None)
+            .unwrap();
+        session.next_key(b"input 1").unwrap();
+
+        // Storage was rolled back below the mark the caller trusts
+        let restarted_keychain = HkdfKeyChain::new(HashFunc::Sha256, None, None, None)
+            .unwrap()
+            .with_chain_id("tenant-a")
+            .with_high_water_mark(2);
+        assert!(matches!(
+            restarted_keychain.resume(storage.clone(), "tenant-a"),
+            Err(Errors::RollbackDetected(_))
+        ));
+
+        let resumed_session = HkdfKeyChain::new(HashFunc::Sha256, None, None, None)
+            .unwrap()
+            .resume(storage.clone(), "tenant-a")
+            .unwrap();
+        assert_eq!(
+            resumed_session.get_key_chain().get_high_water_mark().get(),
+            1
+        );
+
+        // A stored state of another configuration is rejected before use
+        let crafted_state = NewState::new(
+            KeyChainAlgorithm::Hkdf {
+                hash_func: HashFunc::Sha256,
+                output_key_length: 1 << 40,
+            },
+            Some(ChainId::from("tenant-b")),
+            0,
+            SecretBytes::zeroed(32),
+        )
+        .unwrap();
+        storage
+            .store_state(&ChainId::from("tenant-b"), &crafted_state)
+            .unwrap();
+        assert!(matches!(
+            HkdfKeyChain::new(HashFunc::Sha256, None, None, None)
+                .unwrap()
+                .resume(storage, "tenant-b"),
+            Err(Errors::StateMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_builder_applies_defaults_and_storage() {
+        let storage = Arc::new(DefaultStorage::new_all());
+        let keychain = HkdfKeyChain::builder(HashFunc::Sha256)
+            .with_output_key_length(16)
+            .with_extractor_salt(b"salt".to_vec())
+            .with_info_param(b"info".to_vec())
+            .with_storage(storage.clone())
+            .with_chain_id("tenant-a")
+            .build()
+            .unwrap();
+        let plain_keychain = HkdfKeyChain::new(HashFunc::Sha256, Some(16), None, None)
+            .unwrap()
+            .with_chain_id("tenant-a");
+
+        let initial_state = keychain
+            .key_chain_instantiate_ref(&sample_input(), None, None)
+            .unwrap();
+        assert_eq!(
+            initial_state,
+            plain_keychain
+                .key_chain_instantiate_ref(
+                    &sample_input(),
+                    Some(b"salt".as_slice()),
+                    Some(b"info".as_slice())
+                )
+                .unwrap()
+        );
+
+        let (new_state, random_output) = keychain
+            .key_chain_update_ref(b"update", &initial_state, None, None)
+            .unwrap();
+        assert_eq!(random_output.len(), 16);
+        assert_eq!(storage.fetch_state(&"tenant-a".into()).unwrap(), new_state);
+
+        // An explicit parameter wins over the default, and a stored chain is
+        // not instantiated a second time. The clone for another chain ID does
+        // not inherit the high water mark of the advanced chain.
+        let keychain_b = keychain.clone().with_chain_id("tenant-b");
+        let state_b = keychain_b
+            .key_chain_instantiate_ref(&sample_input(), Some(b"other salt".as_slice()), None)
+            .unwrap();
+        assert_ne!(state_b.as_bytes(), initial_state.as_bytes());
+        assert!(matches!(
+            keychain_b.key_chain_instantiate_ref(&sample_input(), None, None),
+            Err(Errors::StateConflict(_))
+        ));
+        let (state_b_1, _) = keychain_b
+            .key_chain_update_ref(b"update", &state_b, None, None)
+            .unwrap();
+        assert_eq!(state_b_1.get_epoch(), 1);
+        assert_eq!(keychain.get_high_water_mark().get(), 1);
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_configuration() {
+        let too_long = HkdfKeyChain::builder(HashFunc::Sha256)
+            .with_output_key_length(254 * 32 + 1)
+            .build();
+        assert!(matches!(too_long, Err(Errors::InvalidConfiguration(msg)) if msg.contains("8128")));
+
+        let empty = HkdfKeyChain::builder(HashFunc::Sha256)
+            .with_output_key_length(0)
+            .build();
+        assert!(matches!(empty, Err(Errors::InvalidConfiguration(_))));
+
+        let empty_chain_id = HkdfKeyChain::builder(HashFunc::Sha256)
+            .with_chain_id("")
+            .build();
+        assert!(matches!(
+            empty_chain_id,
+            Err(Errors::InvalidConfiguration(_))
+        ));
+
+        let keychain = HkdfKeyChain::builder(HashFunc::Sha256)
+            .with_output_key_length(254 * 32)
+            .build()
+            .unwrap();
+        let initial_state = keychain
+            .key_chain_instantiate_ref(&sample_input(), None, None)
+            .unwrap();
+        assert!(
+            keychain
+                .key_chain_update_ref(b"update", &initial_state, None, None)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_owned_parameters_match_borrowed_parameters() {
+        let keychain = HkdfKeyChain::new(HashFunc::Sha3_256, None, None, None).unwrap();
+        let initial_state = keychain
+            .key_chain_instantiate(&sample_input(), Some(b"salt".to_vec()), None)
+            .unwrap();
+        assert_eq!(
+            initial_state,
+            keychain
+                .key_chain_instantiate_ref(&sample_input(), Some(b"salt"), None)
+                .unwrap()
+        );
+
+        let replay_keychain = HkdfKeyChain::new(HashFunc::Sha3_256, None, None, None).unwrap();
+        assert_eq!(
+            keychain
+                .key_chain_update(b"update", &initial_state, None, Some(b"info".to_vec()))
+                .unwrap(),
+            replay_keychain
+                .key_chain_update_ref(b"update", &initial_state, None, Some(b"info"))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_update_into_matches_update() {
+        let keychain = HkdfKeyChain::new(HashFunc::Sha256, Some(48), None, None).unwrap();
+        let initial_state = keychain
+            .key_chain_instantiate_ref(&sample_input(), None, None)
+            .unwrap();
+        let (expected_state, expected_output) = keychain
+            .key_chain_update_ref(b"update", &initial_state, Some(b"salt"), Some(b"info"))
+            .unwrap();
+
+        let (mut new_state, mut output_key) = ([0u8; 32], [0u8; 48]);
+        keychain
+            .key_chain_update_into(
+                b"update",
+                initial_state.as_bytes(),
+                Some(b"salt"),
+                Some(b"info"),
+                &mut new_state,
+                &mut output_key,
+            )
+            .unwrap();
+        assert_eq!(&new_state[..], expected_state.as_bytes());
+        assert_eq!(&output_key[..], expected_output.as_bytes());
+
+        let current_state: [u8; 32] = initial_state.as_bytes().try_into().unwrap();
+        let (new_state, output_key) = keychain
+            .key_chain_update_array::<32, 48>(
+                b"update",
+                &current_state,
+                Some(b"salt"),
+                Some(b"info"),
+            )
+            .unwrap();
+        assert_eq!(&new_state[..], expected_state.as_bytes());
+        assert_eq!(&output_key[..], expected_output.as_bytes());
+
+        // Buffer lengths must match the chain
+        let result =
+            keychain.key_chain_update_array::<32, 32>(b"update", &current_state, None, None);
+        assert!(matches!(result, Err(Errors::InvalidLength(_))));
+
+        // Persistent chains have to go through storage
+        let storage = Arc::new(DefaultStorage::new(KeyChainType::HkdfKeyChain));
+        let keychain =
+            HkdfKeyChain::new(HashFunc::Sha256, Some(48), Some(true), Some(storage)).unwrap();
+        let result =
+            keychain.key_chain_update_array::<32, 48>(b"update", &current_state, None, None);
+        assert!(matches!(result, Err(Errors::InvalidConfiguration(_))));
+    }
+
+    #[test]
+    fn test_typed_chain_matches_dynamic_chain() {
+        let keychain = HkdfKeyChain::new(HashFunc::Sha512, Some(48), None, None).unwrap();
+        let typed_keychain = TypedHkdfKeyChain::<Sha512>::new(Some(48)).with_info_param(b"info");
+
+        let initial_state = keychain
+            .key_chain_instantiate_ref(&sample_input(), None, Some(b"info"))
+            .unwrap();
+        let typed_initial_state = typed_keychain
+            .key_chain_instantiate(&sample_input(), None, None)
+            .unwrap();
+        assert_eq!(typed_initial_state.as_bytes(), initial_state.as_bytes());
+
+        let (new_state, random_output) = keychain
+            .key_chain_update_ref(b"update", &initial_state, None, Some(b"info"))
+            .unwrap();
+        let (typed_new_state, typed_random_output) = typed_keychain
+            .key_chain_update(b"update", &typed_initial_state, None, None)
+            .unwrap();
+        assert_eq!(typed_new_state.as_bytes(), new_state.as_bytes());
+        assert_eq!(typed_random_output, random_output);
+    }
+
+    #[test]
+    fn test_typed_chain_accepts_digests_outside_hash_func() {
+        let keychain = TypedHkdfKeyChain::<sha2::Sha384>::new(None);
+        assert_eq!(keychain.get_state_length(), 48);
+
+        let initial_state = keychain
+            .key_chain_instantiate(&sample_input(), None, None)
+            .unwrap();
+        let current_state: [u8; 48] = initial_state.as_bytes().try_into().unwrap();
+        let (new_state, output_key) = keychain
+            .key_chain_update_array::<48, 48>(b"update", &current_state, None, None)
+            .unwrap();
+
+        assert_ne!(*new_state, current_state);
+        assert_ne!(&output_key[..], &new_state[..]);
+    }
 }
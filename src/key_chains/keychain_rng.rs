@@ -0,0 +1,292 @@
+use super::{
+    InitialState, NewState, RandomOutput, keychain_trait::KeyChain, prg_keychain::PrgKeyChain,
+    xdrbg_keychain::XdrbgKeyChain,
+};
+use crate::{Xof, errors::Errors};
+#[cfg(not(feature = "std"))]
+use core::num::NonZeroU32;
+use rand_core::{CryptoRng, RngCore, SeedableRng, impls};
+use zeroize::Zeroize;
+
+// Key chains whose state can be stepped forward without fresh input, i.e. the
+// ones that can back a random number generator.
+pub trait RngKeyChain: KeyChain {
+    fn max_block_length(&self) -> usize;
+
+    fn generate_block(
+        &self,
+        keychain_state: &NewState,
+        block_length: usize,
+    ) -> Result<(NewState, RandomOutput), Errors>;
+}
+
+impl RngKeyChain for XdrbgKeyChain {
+    fn max_block_length(&self) -> usize {
+        self.get_chosen_xof().max_output_key_length()
+    }
+
+    fn generate_block(
+        &self,
+        keychain_state: &NewState,
+        block_length: usize,
+    ) -> Result<(NewState, RandomOutput), Errors> {
+        self.begin_key_chain_generate(keychain_state, block_length)?
+            .commit()
+    }
+}
+
+impl RngKeyChain for PrgKeyChain {
+    fn max_block_length(&self) -> usize {
+        self.get_chosen_security_param_lambda()
+    }
+
+    // The Barak-Halevi next() step always yields lambda bytes, so a shorter
+    // block simply truncates its output.
+    fn generate_block(
+        &self,
+        keychain_state: &NewState,
+        block_length: usize,
+    ) -> Result<(NewState, RandomOutput), Errors> {
+        let (new_state, random_output) = self.begin_key_chain_next(keychain_state)?.commit()?;
+
+        Ok((
+            new_state,
+            RandomOutput::from(&random_output[..block_length]),
+        ))
+    }
+}
+
+// Every block is committed like an update, so a chain that stores persistently
+// has the state after a fill in storage before the output is handed out.
+pub struct KeyChainRng<K: RngKeyChain> {
+    key_chain: K,
+    current_state: NewState,
+}
+
+pub type XdrbgRng = KeyChainRng<XdrbgKeyChain>;
+pub type PrgRng = KeyChainRng<PrgKeyChain>;
+
+impl<K: RngKeyChain> KeyChainRng<K> {
+    pub fn new(key_chain: K, initial_state: InitialState) -> Result<Self, Errors> {
+        initial_state.check_belongs_to(key_chain.algorithm(), key_chain.chain_id())?;
+
+        Ok(Self {
+            key_chain,
+            current_state: initial_state,
+        })
+    }
+
+    pub fn from_key_chain_seed(key_chain: K, seed: &[u8]) -> Result<Self, Errors> {
+        let initial_state: InitialState =
+            key_chain.instantiate(seed, K::InstantiateParams::default())?;
+
+        Self::new(key_chain, initial_state)
+    }
+
+    // Injects fresh entropy through key_chain_update(). The output key of that
+    // update is never handed out.
+    pub fn reseed(&mut self, entropy: &[u8]) -> Result<(), Errors> {
+        let (new_state, _) =
+            self.key_chain
+                .update(entropy, &self.current_state, K::UpdateParams::default())?;
+
+        self.current_state = new_state;
+
+        Ok(())
+    }
+
+    pub fn generate(&mut self, dest: &mut [u8]) -> Result<(), Errors> {
+        for chunk in dest.chunks_mut(self.key_chain.max_block_length()) {
+            let (new_state, random_output) = self
+                .key_chain
+                .generate_block(&self.current_state, chunk.len())?;
+
+            chunk.copy_from_slice(&random_output);
+            self.current_state = new_state;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_key_chain(&self) -> &K {
+        &self.key_chain
+    }
+}
+
+impl<K: RngKeyChain> RngCore for KeyChainRng<K> {
+    fn next_u32(&mut self) -> u32 {
+        impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_fill(self)
+    }
+
+    // Panics if the key chain fails, e.g. when a persistent chain cannot store
+    // its state. Prefer try_fill_bytes() with persistent chains.
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.generate(dest)
+            .expect("Key chain failed to generate random output");
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.generate(dest).map_err(to_rand_error)
+    }
+}
+
+#[cfg(feature = "std")]
+fn to_rand_error(err: Errors) -> rand_core::Error {
+    rand_core::Error::new(err)
+}
+
+// Without std, rand_core errors carry a code only, so the message is lost.
+#[cfg(not(feature = "std"))]
+fn to_rand_error(_err: Errors) -> rand_core::Error {
+    rand_core::Error::from(NonZeroU32::new(rand_core::Error::CUSTOM_START).unwrap())
+}
+
+impl<K: RngKeyChain> CryptoRng for KeyChainRng<K> {}
+
+// SHAKE128 needs at least 24 bytes of seed during instantiation.
+impl SeedableRng for XdrbgRng {
+    type Seed = [u8; 32];
+
+    fn from_seed(mut seed: Self::Seed) -> Self {
+        let key_chain = XdrbgKeyChain::new(Xof::Shake128, None, None, None)
+            .expect("Xdrbg key chain without storage is always constructible");
+        let rng = Self::from_key_chain_seed(key_chain, &seed)
+            .expect("A 32 byte seed is valid for Shake128");
+        seed.zeroize();
+
+        rng
+    }
+}
+
+// Lambda of 32 bytes, i.e. AES-256 in counter mode underneath.
+impl SeedableRng for PrgRng {
+    type Seed = [u8; 32];
+
+    fn from_seed(mut seed: Self::Seed) -> Self {
+        let key_chain = PrgKeyChain::new(32, None, None)
+            .expect("Prg key chain without storage is always constructible");
+        let rng = Self::from_key_chain_seed(key_chain, &seed)
+            .expect("A 32 byte seed is valid for lambda = 32");
+        seed.zeroize();
+
+        rng
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill_from_crypto_rng(rng: &mut (impl RngCore + CryptoRng), length: usize) -> Vec<u8> {
+        let mut output = vec![0u8; length];
+        rng.fill_bytes(&mut output);
+        output
+    }
+
+    #[test]
+    fn test_seedable_rngs_are_deterministic() {
+        let mut xdrbg_rng_1 = XdrbgRng::from_seed([0x11; 32]);
+        let mut xdrbg_rng_2 = XdrbgRng::from_seed([0x11; 32]);
+        assert_eq!(
+            fill_from_crypto_rng(&mut xdrbg_rng_1, 100),
+            fill_from_crypto_rng(&mut xdrbg_rng_2, 100)
+        );
+
+        let mut prg_rng_1 = PrgRng::from_seed([0x22; 32]);
+        let mut prg_rng_2 = PrgRng::from_seed([0x22; 32]);
+        assert_eq!(prg_rng_1.next_u64(), prg_rng_2.next_u64());
+    }
+
+    #[test]
+    fn test_fill_spans_multiple_blocks() {
+        let key_chain = XdrbgKeyChain::new(Xof::Ascon, None, None, None).unwrap();
+        let max_block_length = key_chain.max_block_length();
+        let mut rng = KeyChainRng::from_key_chain_seed(key_chain, &[0x33; 24]).unwrap();
+
+        let output = fill_from_crypto_rng(&mut rng, 2 * max_block_length + 7);
+        let (first_block, rest) = output.split_at(max_block_length);
+
+        assert_ne!(first_block, &rest[..max_block_length]);
+    }
+
+    #[test]
+    fn test_consecutive_fills_differ() {
+        let mut rng = PrgRng::from_seed([0x44; 32]);
+
+        let output_1 = fill_from_crypto_rng(&mut rng, 40);
+        let output_2 = fill_from_crypto_rng(&mut rng, 40);
+
+        assert_ne!(output_1, output_2);
+    }
+
+    #[test]
+    fn test_reseed_changes_output() {
+        let mut rng_1 = XdrbgRng::from_seed([0x55; 32]);
+        let mut rng_2 = XdrbgRng::from_seed([0x55; 32]);
+
+        rng_2.reseed(&[0x66; 32]).unwrap();
+
+        assert_ne!(
+            fill_from_crypto_rng(&mut rng_1, 32),
+            fill_from_crypto_rng(&mut rng_2, 32)
+        );
+    }
+
+    #[test]
+    fn test_reseed_with_invalid_entropy() {
+        let mut rng = PrgRng::from_seed([0x77; 32]);
+
+        // Prg refresh needs lambda bytes of entropy
+        let result = rng.reseed(&[0x88; 16]);
+        assert!(matches!(result, Err(Errors::InvalidLength(_))));
+
+        let mut buffer = [0u8; 8];
+        assert!(rng.try_fill_bytes(&mut buffer).is_ok());
+    }
+
+    #[test]
+    fn test_new_rejects_state_of_other_chain() {
+        let shake128_kc = XdrbgKeyChain::new(Xof::Shake128, None, None, None).unwrap();
+        let shake256_kc = XdrbgKeyChain::new(Xof::Shake256, None, None, None).unwrap();
+        let shake256_state = shake256_kc
+            .key_chain_instantiate_ref(&[0x99; 48], None)
+            .unwrap();
+
+        let result = KeyChainRng::new(shake128_kc, shake256_state);
+
+        assert!(matches!(result, Err(Errors::StateMismatch(_))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_persistent_chain_stores_every_block() {
+        use crate::key_chains::storage_handler::{DefaultStorage, Storage};
+        use std::sync::Arc;
+
+        let storage = Arc::new(DefaultStorage::new_all());
+        let key_chain = XdrbgKeyChain::builder(Xof::Shake128)
+            .with_storage(storage.clone())
+            .with_chain_id("tenant-a")
+            .build()
+            .unwrap();
+        let mut rng = KeyChainRng::from_key_chain_seed(key_chain, &[0xaa; 24]).unwrap();
+        let max_block_length = rng.get_key_chain().max_block_length();
+
+        let mut buffer = vec![0u8; max_block_length + 1];
+        rng.try_fill_bytes(&mut buffer).unwrap();
+        assert_eq!(
+            storage.fetch_state(&"tenant-a".into()).unwrap().get_epoch(),
+            2
+        );
+
+        rng.reseed(&[0xbb; 32]).unwrap();
+        assert_eq!(
+            storage.fetch_state(&"tenant-a".into()).unwrap().get_epoch(),
+            3
+        );
+    }
+}
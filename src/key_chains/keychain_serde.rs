@@ -0,0 +1,425 @@
+use super::{
+    ChainId, KeyChainAlgorithm, KeyChainState,
+    any_keychain::AnyKeyChain,
+    hkdf_keychain::{HkdfKeyChain, HkdfKeyChainBuilder},
+    keychain_trait::{HkdfParams, XdrbgInstantiateParams, XdrbgUpdateParams},
+    prg_keychain::{PrgKeyChain, PrgKeyChainBuilder},
+    state_encoding::{hex_decode, hex_encode},
+    storage_handler::Storage,
+    xdrbg_keychain::{XdrbgKeyChain, XdrbgKeyChainBuilder},
+};
+use crate::{
+    HashFunc, Xof,
+    errors::Errors::{self, MalformedState},
+    secret_bytes::SecretBytes,
+};
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+// Serializable key chain configurations, e.g. read from JSON or TOML, and the
+// serialized form of a KeyChainState. Byte strings are hex encoded. Storage
+// handles are runtime objects and are passed in when a chain is built. The
+// chains are built through their builders, so a configuration is validated
+// the same way and its defaults apply whenever a call passes None.
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct HexBytes(pub Vec<u8>);
+
+impl TryFrom<String> for HexBytes {
+    type Error = Errors;
+
+    fn try_from(hex: String) -> Result<Self, Errors> {
+        hex_decode(&hex).map(Self).ok_or_else(|| {
+            MalformedState(format!("Invalid hex string of {} characters", hex.len()))
+        })
+    }
+}
+
+impl From<HexBytes> for String {
+    fn from(bytes: HexBytes) -> Self {
+        hex_encode(&bytes.0)
+    }
+}
+
+impl From<Vec<u8>> for HexBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+// The salt and info parameter are the defaults for instantiate and update.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct HkdfKeyChainConfig {
+    pub hash_func: HashFunc,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_key_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<ChainId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extractor_salt: Option<HexBytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info_param: Option<HexBytes>,
+}
+
+impl HkdfKeyChainConfig {
+    pub fn from_key_chain(key_chain: &HkdfKeyChain) -> Self {
+        Self {
+            hash_func: key_chain.get_chosen_hash_func(),
+            output_key_length: Some(key_chain.get_output_key_length()),
+            chain_id: key_chain.get_chain_id().cloned(),
+            extractor_salt: key_chain
+                .get_default_params()
+                .extractor_salt
+                .clone()
+                .map(HexBytes),
+            info_param: key_chain
+                .get_default_params()
+                .info_param
+                .clone()
+                .map(HexBytes),
+        }
+    }
+
+    pub fn to_builder(&self) -> HkdfKeyChainBuilder {
+        let mut builder: HkdfKeyChainBuilder = HkdfKeyChain::builder(self.hash_func);
+
+        if let Some(output_key_length) = self.output_key_length {
+            builder = builder.with_output_key_length(output_key_length);
+        }
+        if let Some(chain_id) = &self.chain_id {
+            builder = builder.with_chain_id(chain_id.clone());
+        }
+        if let Some(extractor_salt) = &self.extractor_salt {
+            builder = builder.with_extractor_salt(extractor_salt.0.clone());
+        }
+        if let Some(info_param) = &self.info_param {
+            builder = builder.with_info_param(info_param.0.clone());
+        }
+
+        builder
+    }
+
+    pub fn to_key_chain(&self, storage: Option<Arc<dyn Storage>>) -> Result<HkdfKeyChain, Errors> {
+        match storage {
+            Some(storage) => self.to_builder().with_storage(storage).build(),
+            None => self.to_builder().build(),
+        }
+    }
+
+    pub fn params(&self) -> HkdfParams {
+        HkdfParams {
+            extractor_salt: self.extractor_salt.clone().map(|salt| salt.0),
+            info_param: self.info_param.clone().map(|info| info.0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PrgKeyChainConfig {
+    pub security_param_lambda: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<ChainId>,
+}
+
+impl PrgKeyChainConfig {
+    pub fn from_key_chain(key_chain: &PrgKeyChain) -> Self {
+        Self {
+            security_param_lambda: key_chain.get_chosen_security_param_lambda(),
+            chain_id: key_chain.get_chain_id().cloned(),
+        }
+    }
+
+    pub fn to_builder(&self) -> PrgKeyChainBuilder {
+        let builder: PrgKeyChainBuilder = PrgKeyChain::builder(self.security_param_lambda);
+
+        match &self.chain_id {
+            Some(chain_id) => builder.with_chain_id(chain_id.clone()),
+            None => builder,
+        }
+    }
+
+    pub fn to_key_chain(&self, storage: Option<Arc<dyn Storage>>) -> Result<PrgKeyChain, Errors> {
+        match storage {
+            Some(storage) => self.to_builder().with_storage(storage).build(),
+            None => self.to_builder().build(),
+        }
+    }
+}
+
+// alpha is the default for instantiate, alpha_reseed and alpha_generate the
+// defaults for update.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct XdrbgKeyChainConfig {
+    pub xof: Xof,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_key_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<ChainId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpha: Option<HexBytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpha_reseed: Option<HexBytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpha_generate: Option<HexBytes>,
+}
+
+impl XdrbgKeyChainConfig {
+    pub fn from_key_chain(key_chain: &XdrbgKeyChain) -> Self {
+        Self {
+            xof: key_chain.get_chosen_xof(),
+            output_key_length: Some(key_chain.get_output_key_length()),
+            chain_id: key_chain.get_chain_id().cloned(),
+            alpha: key_chain
+                .get_default_instantiate_params()
+                .alpha
+                .clone()
+                .map(HexBytes),
+            alpha_reseed: key_chain
+                .get_default_update_params()
+                .alpha_reseed
+                .clone()
+                .map(HexBytes),
+            alpha_generate: key_chain
+                .get_default_update_params()
+                .alpha_generate
+                .clone()
+                .map(HexBytes),
+        }
+    }
+
+    pub fn to_builder(&self) -> XdrbgKeyChainBuilder {
+        let mut builder: XdrbgKeyChainBuilder = XdrbgKeyChain::builder(self.xof);
+
+        if let Some(output_key_length) = self.output_key_length {
+            builder = builder.with_output_key_length(output_key_length);
+        }
+        if let Some(chain_id) = &self.chain_id {
+            builder = builder.with_chain_id(chain_id.clone());
+        }
+        if let Some(alpha) = &self.alpha {
+            builder = builder.with_personalization(alpha.0.clone());
+        }
+        if let Some(alpha_reseed) = &self.alpha_reseed {
+            builder = builder.with_alpha_reseed(alpha_reseed.0.clone());
+        }
+        if let Some(alpha_generate) = &self.alpha_generate {
+            builder = builder.with_alpha_generate(alpha_generate.0.clone());
+        }
+
+        builder
+    }
+
+    pub fn to_key_chain(&self, storage: Option<Arc<dyn Storage>>) -> Result<XdrbgKeyChain, Errors> {
+        match storage {
+            Some(storage) => self.to_builder().with_storage(storage).build(),
+            None => self.to_builder().build(),
+        }
+    }
+
+    pub fn instantiate_params(&self) -> XdrbgInstantiateParams {
+        XdrbgInstantiateParams {
+            alpha: self.alpha.clone().map(|alpha| alpha.0),
+        }
+    }
+
+    pub fn update_params(&self) -> XdrbgUpdateParams {
+        XdrbgUpdateParams {
+            alpha_reseed: self.alpha_reseed.clone().map(|alpha| alpha.0),
+            alpha_generate: self.alpha_generate.clone().map(|alpha| alpha.0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum KeyChainConfig {
+    Hkdf(HkdfKeyChainConfig),
+    Prg(PrgKeyChainConfig),
+    Xdrbg(XdrbgKeyChainConfig),
+}
+
+impl KeyChainConfig {
+    pub fn from_key_chain(key_chain: &AnyKeyChain) -> Self {
+        match key_chain {
+            AnyKeyChain::Hkdf(key_chain) => {
+                Self::Hkdf(HkdfKeyChainConfig::from_key_chain(key_chain))
+            }
+            AnyKeyChain::Prg(key_chain) => Self::Prg(PrgKeyChainConfig::from_key_chain(key_chain)),
+            AnyKeyChain::Xdrbg(key_chain) => {
+                Self::Xdrbg(XdrbgKeyChainConfig::from_key_chain(key_chain))
+            }
+        }
+    }
+
+    pub fn to_key_chain(&self, storage: Option<Arc<dyn Storage>>) -> Result<AnyKeyChain, Errors> {
+        Ok(match self {
+            Self::Hkdf(config) => AnyKeyChain::Hkdf(config.to_key_chain(storage)?),
+            Self::Prg(config) => AnyKeyChain::Prg(config.to_key_chain(storage)?),
+            Self::Xdrbg(config) => AnyKeyChain::Xdrbg(config.to_key_chain(storage)?),
+        })
+    }
+}
+
+// Serialized form of a KeyChainState, which serializes through it. The state
+// bytes are hex encoded, or left out by without_secrets(), e.g. for logs and
+// monitoring. A state without its bytes cannot be deserialized back into a
+// KeyChainState.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct SerializedState {
+    pub algorithm: KeyChainAlgorithm,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<ChainId>,
+    pub epoch: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+}
+
+impl SerializedState {
+    pub fn without_secrets(state: &KeyChainState) -> Self {
+        Self {
+            algorithm: state.get_algorithm(),
+            chain_id: state.get_chain_id().cloned(),
+            epoch: state.get_epoch(),
+            state: None,
+        }
+    }
+}
+
+impl From<&KeyChainState> for SerializedState {
+    fn from(state: &KeyChainState) -> Self {
+        Self {
+            algorithm: state.get_algorithm(),
+            chain_id: state.get_chain_id().cloned(),
+            epoch: state.get_epoch(),
+            state: Some(hex_encode(state.as_bytes())),
+        }
+    }
+}
+
+impl From<KeyChainState> for SerializedState {
+    fn from(state: KeyChainState) -> Self {
+        Self::from(&state)
+    }
+}
+
+impl TryFrom<SerializedState> for KeyChainState {
+    type Error = Errors;
+
+    fn try_from(serialized: SerializedState) -> Result<Self, Errors> {
+        serialized
+            .algorithm
+            .check_parameters()
+            .map_err(|err| MalformedState(err.to_string()))?;
+
+        let state_bytes: SecretBytes = serialized
+            .state
+            .as_deref()
+            .and_then(hex_decode)
+            .map(SecretBytes::from)
+            .ok_or_else(|| {
+                MalformedState("Serialized state is missing or has invalid state bytes".to_string())
+            })?;
+
+        KeyChainState::new(
+            serialized.algorithm,
+            serialized.chain_id.clone(),
+            serialized.epoch,
+            state_bytes,
+        )
+    }
+}
+
+impl Drop for SerializedState {
+    fn drop(&mut self) {
+        self.state.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_chains::keychain_trait::KeyChain;
+
+    #[test]
+    fn test_config_round_trip_builds_same_chain() {
+        let config: KeyChainConfig = serde_json::from_str(
+            r#"{"Xdrbg": {"xof": "Shake128", "output_key_length": 24, "chain_id": "tenant-a", "alpha": "0102"}}"#,
+        )
+        .unwrap();
+        let KeyChainConfig::Xdrbg(xdrbg_config) = &config else {
+            panic!("Expected an Xdrbg config");
+        };
+        assert_eq!(xdrbg_config.instantiate_params().alpha, Some(vec![1, 2]));
+
+        let key_chain = config.to_key_chain(None).unwrap();
+        assert_eq!(KeyChainConfig::from_key_chain(&key_chain), config);
+
+        // The alpha of the config is the default of the built chain
+        let AnyKeyChain::Xdrbg(xdrbg_kc) = key_chain else {
+            panic!("Expected an Xdrbg key chain");
+        };
+        assert_eq!(
+            xdrbg_kc
+                .instantiate(&[0x11; 24], Default::default())
+                .unwrap(),
+            XdrbgKeyChain::new(Xof::Shake128, Some(24), None, None)
+                .unwrap()
+                .with_chain_id("tenant-a")
+                .key_chain_instantiate_ref(&[0x11; 24], Some(&[1, 2][..]))
+                .unwrap()
+        );
+
+        let hkdf_config = HkdfKeyChainConfig::from_key_chain(
+            &HkdfKeyChain::new(HashFunc::Sha512, Some(48), None, None).unwrap(),
+        );
+        let json: String = serde_json::to_string(&hkdf_config).unwrap();
+        assert_eq!(
+            serde_json::from_str::<HkdfKeyChainConfig>(&json).unwrap(),
+            hkdf_config
+        );
+
+        assert!(
+            serde_json::from_str::<KeyChainConfig>(
+                r#"{"Hkdf": {"hash_func": "Sha256", "extractor_salt": "0g"}}"#
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_state_round_trip_and_without_secrets() {
+        let key_chain = PrgKeyChain::new(16, None, None)
+            .unwrap()
+            .with_chain_id("tenant-a");
+        let state = key_chain.key_chain_instantiate(&[0x22; 16]).unwrap();
+
+        let json: String = serde_json::to_string(&state).unwrap();
+        assert!(json.contains(&hex_encode(state.as_bytes())));
+        assert_eq!(serde_json::from_str::<KeyChainState>(&json).unwrap(), state);
+
+        let redacted: String =
+            serde_json::to_string(&SerializedState::without_secrets(&state)).unwrap();
+        assert!(!redacted.contains(&hex_encode(state.as_bytes())));
+        assert!(redacted.contains("tenant-a"));
+        assert!(serde_json::from_str::<KeyChainState>(&redacted).is_err());
+
+        // The state bytes must match the length of the algorithm
+        let mut serialized = SerializedState::from(&state);
+        serialized.state = Some("00".to_string());
+        assert!(matches!(
+            KeyChainState::try_from(serialized),
+            Err(Errors::InvalidLength(_))
+        ));
+
+        // Invalid hex is reported without echoing the (possibly secret) input
+        let invalid_hex: String = format!("{}zz", hex_encode(state.as_bytes()));
+        let err = HexBytes::try_from(invalid_hex.clone()).unwrap_err();
+        assert!(!err.to_string().contains(&invalid_hex[..8]));
+    }
+}
@@ -0,0 +1,259 @@
+use super::{
+    InitialState, NewState, RandomOutput, keychain_trait::KeyChain, storage_handler::Storage,
+};
+use crate::errors::Errors::{self, NoStoredState, StateConflict, UninitializedStorage};
+use alloc::string::ToString;
+
+// A session owns the current state of a key chain and advances it on every
+// call to next_key(). Superseded states are wiped when they are replaced, so
+// they cannot be fed back into the chain. Deliberately not Clone, as a clone
+// would share the state.
+pub struct KeyChainSession<K: KeyChain> {
+    key_chain: K,
+    current_state: NewState,
+}
+
+impl<K: KeyChain> KeyChainSession<K> {
+    pub fn new(key_chain: K, initial_state: InitialState) -> Result<Self, Errors> {
+        initial_state.check_belongs_to(key_chain.algorithm(), key_chain.chain_id())?;
+
+        Ok(Self {
+            key_chain,
+            current_state: initial_state,
+        })
+    }
+
+    pub fn instantiate(
+        key_chain: K,
+        seed: &[u8],
+        params: K::InstantiateParams,
+    ) -> Result<Self, Errors> {
+        let initial_state: InitialState = key_chain.instantiate(seed, params)?;
+
+        Self::new(key_chain, initial_state)
+    }
+
+    // Resumes from the storage the chain persists to, so that every update of
+    // the session is stored where the next start looks for it.
+    pub fn from_storage(key_chain: K) -> Result<Self, Errors> {
+        let stored_state: NewState =
+            key_chain.fetch_stored_state(persistent_storage(&key_chain)?)?;
+
+        Self::new(key_chain, stored_state)
+    }
+
+    // Resumes the stored chain, or instantiates it on first use. The chain
+    // persists its initial state in instantiate(), so the next start resumes
+    // it. If another process instantiated the chain first, its state wins.
+    pub fn instantiate_or_resume(
+        key_chain: K,
+        seed: &[u8],
+        params: K::InstantiateParams,
+    ) -> Result<Self, Errors> {
+        match key_chain.fetch_stored_state(persistent_storage(&key_chain)?) {
+            Ok(stored_state) => Self::new(key_chain, stored_state),
+            Err(NoStoredState(_)) => match key_chain.instantiate(seed, params) {
+                Ok(initial_state) => Self::new(key_chain, initial_state),
+                Err(StateConflict(_)) => Self::from_storage(key_chain),
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn next_key(&mut self, arbitrary_input_param: &[u8]) -> Result<RandomOutput, Errors> {
+        self.next_key_with(arbitrary_input_param, K::UpdateParams::default())
+    }
+
+    pub fn next_key_with(
+        &mut self,
+        arbitrary_input_param: &[u8],
+        params: K::UpdateParams,
+    ) -> Result<RandomOutput, Errors> {
+        let (new_state, random_output) =
+            self.key_chain
+                .update(arbitrary_input_param, &self.current_state, params)?;
+
+        self.current_state = new_state;
+
+        Ok(random_output)
+    }
+
+    pub fn get_key_chain(&self) -> &K {
+        &self.key_chain
+    }
+
+    pub fn get_epoch(&self) -> u64 {
+        self.current_state.get_epoch()
+    }
+}
+
+fn persistent_storage<K: KeyChain>(key_chain: &K) -> Result<&dyn Storage, Errors> {
+    key_chain.storage().ok_or_else(|| {
+        UninitializedStorage("Key chain does not store its states persistently.".to_string())
+    })
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::key_chains::{
+        hkdf_keychain::HkdfKeyChain,
+        keychain_trait::XdrbgUpdateParams,
+        prg_keychain::PrgKeyChain,
+        storage_handler::{DefaultStorage, KeyChainType},
+        xdrbg_keychain::XdrbgKeyChain,
+    };
+    use crate::{HashFunc, Xof};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_session_matches_manual_state_threading() {
+        let key_chain = HkdfKeyChain::new(HashFunc::Sha256, None, None, None).unwrap();
+        let initial_state = key_chain
+            .key_chain_instantiate_ref(b"initial skm", None, None)
+            .unwrap();
+
+        let (state_1, key_1) = key_chain
+            .key_chain_update_ref(b"input 1", &initial_state, None, None)
+            .unwrap();
+        let (_state_2, key_2) = key_chain
+            .key_chain_update_ref(b"input 2", &state_1, None, None)
+            .unwrap();
+
+        // A fresh key chain, this one is past the initial state now
+        let key_chain = HkdfKeyChain::new(HashFunc::Sha256, None, None, None).unwrap();
+        let mut session = KeyChainSession::new(key_chain, initial_state).unwrap();
+
+        assert_eq!(session.next_key(b"input 1").unwrap(), key_1);
+        assert_eq!(session.next_key(b"input 2").unwrap(), key_2);
+    }
+
+    #[test]
+    fn test_session_with_update_params() {
+        let key_chain = XdrbgKeyChain::new(Xof::Shake128, Some(48), None, None).unwrap();
+        let mut session =
+            KeyChainSession::instantiate(key_chain, &[0x11; 24], Default::default()).unwrap();
+
+        let params = XdrbgUpdateParams {
+            alpha_reseed: Some(b"reseed".to_vec()),
+            alpha_generate: Some(b"generate".to_vec()),
+        };
+
+        let key_1 = session.next_key_with(&[0x22; 16], params).unwrap();
+        let key_2 = session.next_key(&[0x22; 16]).unwrap();
+
+        assert_eq!(key_1.len(), 48);
+        assert_ne!(key_1, key_2);
+    }
+
+    #[test]
+    fn test_session_rejects_state_of_other_chain() {
+        let key_chain_16 = PrgKeyChain::new(16, None, None).unwrap();
+        let key_chain_32 = PrgKeyChain::new(32, None, None).unwrap();
+        let state_32 = key_chain_32.key_chain_instantiate(&[0x11; 32]).unwrap();
+
+        let result = KeyChainSession::new(key_chain_16, state_32);
+
+        assert!(matches!(result, Err(Errors::StateMismatch(_))));
+    }
+
+    #[test]
+    fn test_session_from_storage() {
+        let storage = Arc::new(DefaultStorage::new(KeyChainType::PrgKeyChain));
+        let key_chain = PrgKeyChain::new(16, Some(true), Some(storage.clone())).unwrap();
+
+        let mut session = KeyChainSession::instantiate(key_chain.clone(), &[0x33; 16], ()).unwrap();
+        session.next_key(&[0x44; 16]).unwrap();
+
+        // Key chains of two processes sharing the storage
+        let new_key_chain = || PrgKeyChain::new(16, Some(true), Some(storage.clone())).unwrap();
+        let mut resumed_session = KeyChainSession::from_storage(new_key_chain()).unwrap();
+        let mut other_resumed_session = KeyChainSession::from_storage(new_key_chain()).unwrap();
+        resumed_session.next_key(&[0x55; 16]).unwrap();
+
+        // Both read the same state, only the first update may persist its successor
+        assert!(matches!(
+            other_resumed_session.next_key(&[0x55; 16]),
+            Err(Errors::StateConflict(_))
+        ));
+
+        // Retrying from the fresh state continues the chain
+        let mut retried_session = KeyChainSession::from_storage(new_key_chain()).unwrap();
+        assert_eq!(retried_session.get_epoch(), 2);
+        retried_session.next_key(&[0x66; 16]).unwrap();
+        assert_eq!(retried_session.get_epoch(), 3);
+    }
+
+    #[test]
+    fn test_session_from_empty_storage() {
+        let storage = Arc::new(DefaultStorage::new(KeyChainType::HkdfKeyChain));
+        let key_chain =
+            HkdfKeyChain::new(HashFunc::Sha512, None, Some(true), Some(storage)).unwrap();
+
+        let result = KeyChainSession::from_storage(key_chain);
+
+        assert!(matches!(result, Err(Errors::NoStoredState(_))));
+    }
+
+    #[test]
+    fn test_session_requires_persistent_storage() {
+        let storage = Arc::new(DefaultStorage::new(KeyChainType::HkdfKeyChain));
+        let key_chain =
+            HkdfKeyChain::new(HashFunc::Sha512, None, Some(false), Some(storage)).unwrap();
+
+        assert!(matches!(
+            KeyChainSession::from_storage(key_chain.clone()),
+            Err(Errors::UninitializedStorage(_))
+        ));
+        assert!(matches!(
+            KeyChainSession::instantiate_or_resume(key_chain, &[0x11; 64], Default::default()),
+            Err(Errors::UninitializedStorage(_))
+        ));
+    }
+
+    #[test]
+    fn test_instantiate_or_resume() {
+        let storage = Arc::new(DefaultStorage::new_all());
+        let new_key_chain = || {
+            XdrbgKeyChain::new(Xof::Ascon, None, Some(true), Some(storage.clone()))
+                .unwrap()
+                .with_chain_id("tenant-a")
+        };
+
+        let mut first_boot = KeyChainSession::instantiate_or_resume(
+            new_key_chain(),
+            &[0x11; 24],
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(first_boot.get_epoch(), 0);
+
+        // A different seed on the second start is ignored
+        let mut second_boot = KeyChainSession::instantiate_or_resume(
+            new_key_chain(),
+            &[0x22; 24],
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(second_boot.get_epoch(), 0);
+
+        // Both resumed the same state, so only one of them may advance it
+        first_boot.next_key(&[0x33; 16]).unwrap();
+        assert!(matches!(
+            second_boot.next_key(&[0x33; 16]),
+            Err(Errors::StateConflict(_) | Errors::RollbackDetected(_))
+        ));
+
+        let other_key_chain =
+            XdrbgKeyChain::new(Xof::Ascon, Some(64), Some(true), Some(storage.clone()))
+                .unwrap()
+                .with_chain_id("tenant-a");
+        let result = KeyChainSession::instantiate_or_resume(
+            other_key_chain,
+            &[0x11; 24],
+            Default::default(),
+        );
+        assert!(matches!(result, Err(Errors::StateMismatch(_))));
+    }
+}
@@ -0,0 +1,126 @@
+use super::{RandomOutput, keychain_session::KeyChainSession, keychain_trait::KeyChain};
+use crate::errors::Errors;
+use std::sync::{Arc, Mutex};
+
+// Cloneable handle to one logical key chain. All clones share a single session
+// and every update holds its lock, so two callers can never derive keys from
+// the same state.
+pub struct SharedKeyChain<K: KeyChain> {
+    session: Arc<Mutex<KeyChainSession<K>>>,
+}
+
+impl<K: KeyChain> SharedKeyChain<K> {
+    pub fn new(session: KeyChainSession<K>) -> Self {
+        Self {
+            session: Arc::new(Mutex::new(session)),
+        }
+    }
+
+    pub fn next_key(&self, arbitrary_input_param: &[u8]) -> Result<RandomOutput, Errors> {
+        self.session.lock().unwrap().next_key(arbitrary_input_param)
+    }
+
+    pub fn next_key_with(
+        &self,
+        arbitrary_input_param: &[u8],
+        params: K::UpdateParams,
+    ) -> Result<RandomOutput, Errors> {
+        self.session
+            .lock()
+            .unwrap()
+            .next_key_with(arbitrary_input_param, params)
+    }
+
+    pub fn get_epoch(&self) -> u64 {
+        self.session.lock().unwrap().get_epoch()
+    }
+}
+
+impl<K: KeyChain> Clone for SharedKeyChain<K> {
+    fn clone(&self) -> Self {
+        Self {
+            session: Arc::clone(&self.session),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        HashFunc, Xof,
+        key_chains::{
+            hkdf_keychain::HkdfKeyChain,
+            keychain_trait::KeyChainInfo,
+            prg_keychain::PrgKeyChain,
+            storage_handler::{DefaultStorage, Storage},
+            xdrbg_keychain::XdrbgKeyChain,
+        },
+    };
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_key_chains_are_send_sync() {
+        assert_send_sync::<HkdfKeyChain>();
+        assert_send_sync::<PrgKeyChain>();
+        assert_send_sync::<XdrbgKeyChain>();
+        assert_send_sync::<Arc<dyn Storage>>();
+        assert_send_sync::<SharedKeyChain<XdrbgKeyChain>>();
+    }
+
+    #[test]
+    fn test_concurrent_callers_get_distinct_keys() {
+        let storage = Arc::new(DefaultStorage::new_all());
+        let key_chain =
+            HkdfKeyChain::new(HashFunc::Sha256, None, Some(true), Some(storage.clone()))
+                .unwrap()
+                .with_chain_id("tenant-a");
+        let session =
+            KeyChainSession::instantiate(key_chain.clone(), b"seed", Default::default()).unwrap();
+        let shared = SharedKeyChain::new(session);
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || shared.next_key(b"input").unwrap())
+            })
+            .collect();
+        let mut keys: Vec<Vec<u8>> = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap().to_vec())
+            .collect();
+
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 8);
+        assert_eq!(shared.get_epoch(), 8);
+        assert_eq!(
+            storage
+                .fetch_state(&key_chain.storage_chain_id())
+                .unwrap()
+                .get_epoch(),
+            8
+        );
+    }
+
+    #[test]
+    fn test_key_chain_moves_into_thread() {
+        let key_chain = XdrbgKeyChain::new(Xof::Shake256, None, None, None).unwrap();
+
+        let output_key = thread::spawn(move || {
+            let state = key_chain
+                .key_chain_instantiate_ref(&[0x11; 48], None)
+                .unwrap();
+            key_chain
+                .key_chain_update_ref(&[0x22; 32], &state, None, None)
+                .unwrap()
+                .1
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(output_key.len(), Xof::Shake256.state_size());
+    }
+}
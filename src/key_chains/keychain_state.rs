@@ -0,0 +1,468 @@
+use super::storage_handler::KeyChainType;
+use crate::{
+    HashFunc, Xof,
+    errors::Errors::{
+        self, InvalidConfiguration, InvalidLength, MalformedState, RollbackDetected, StateMismatch,
+    },
+    secret_bytes::SecretBytes,
+};
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+};
+use core::fmt;
+use portable_atomic::{AtomicU64, Ordering};
+
+// The raw buffers of an allocation free update must match the chain exactly.
+pub(crate) fn check_update_buffers(
+    state_length: usize,
+    output_key_length: usize,
+    current_state: &[u8],
+    new_state: &[u8],
+    output_key: &[u8],
+) -> Result<(), Errors> {
+    for (name, buffer, expected_length) in [
+        ("current state", current_state, state_length),
+        ("new state", new_state, state_length),
+        ("output key", output_key, output_key_length),
+    ] {
+        if buffer.len() != expected_length {
+            return Err(InvalidLength(format!(
+                "Provided a {} buffer of {} bytes. Expected {} bytes.",
+                name,
+                buffer.len(),
+                expected_length
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum KeyChainAlgorithm {
+    Hkdf {
+        hash_func: HashFunc,
+        output_key_length: usize,
+    },
+    Prg {
+        security_param_lambda: usize,
+    },
+    Xdrbg {
+        xof: Xof,
+        output_key_length: usize,
+    },
+}
+
+impl KeyChainAlgorithm {
+    pub fn key_chain_type(&self) -> KeyChainType {
+        match self {
+            Self::Hkdf { .. } => KeyChainType::HkdfKeyChain,
+            Self::Prg { .. } => KeyChainType::PrgKeyChain,
+            Self::Xdrbg { .. } => KeyChainType::XdrbgKeyChain,
+        }
+    }
+
+    pub fn state_length(&self) -> usize {
+        match self {
+            Self::Hkdf { hash_func, .. } => hash_func.output_size(),
+            Self::Prg {
+                security_param_lambda,
+            } => *security_param_lambda,
+            Self::Xdrbg { xof, .. } => xof.state_size(),
+        }
+    }
+
+    pub fn output_key_length(&self) -> usize {
+        match self {
+            Self::Hkdf {
+                output_key_length, ..
+            } => *output_key_length,
+            Self::Prg {
+                security_param_lambda,
+            } => *security_param_lambda,
+            Self::Xdrbg {
+                output_key_length, ..
+            } => *output_key_length,
+        }
+    }
+
+    // Rejects parameters no key chain can be built with, e.g. from a decoded
+    // state, before they size any allocation.
+    pub fn check_parameters(&self) -> Result<(), Errors> {
+        match *self {
+            Self::Hkdf {
+                hash_func,
+                output_key_length,
+            } => {
+                if output_key_length == 0 || output_key_length > hash_func.max_output_key_length() {
+                    return Err(InvalidConfiguration(format!(
+                        "Output key length of {} bytes. Acceptable length is 1 to {} bytes for the hash function {:?}.",
+                        output_key_length,
+                        hash_func.max_output_key_length(),
+                        hash_func
+                    )));
+                }
+            }
+            Self::Prg {
+                security_param_lambda,
+            } => {
+                if ![16, 24, 32].contains(&security_param_lambda) {
+                    return Err(InvalidConfiguration(format!(
+                        "Security parameter lambda of {} bytes. Acceptable values are 16, 24 or 32 bytes.",
+                        security_param_lambda
+                    )));
+                }
+            }
+            Self::Xdrbg {
+                xof,
+                output_key_length,
+            } => {
+                if output_key_length == 0 || output_key_length > xof.max_output_key_length() {
+                    return Err(InvalidConfiguration(format!(
+                        "Output key length of {} bytes. Acceptable length is 1 to {} bytes for the XOF {:?}.",
+                        output_key_length,
+                        xof.max_output_key_length(),
+                        xof
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Storage key of chains that were not given a chain ID. Chains of the same
+    // algorithm share it, so distinct chains need distinct IDs.
+    pub fn default_chain_id(&self) -> ChainId {
+        match self {
+            Self::Hkdf { hash_func, .. } => ChainId(format!("hkdf/{:?}", hash_func)),
+            Self::Prg {
+                security_param_lambda,
+            } => ChainId(format!("prg/{}", security_param_lambda)),
+            Self::Xdrbg { xof, .. } => ChainId(format!("xdrbg/{:?}", xof)),
+        }
+    }
+}
+
+// Opaque name of a logical key chain, e.g. a tenant name or a UUID.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(transparent)
+)]
+pub struct ChainId(String);
+
+impl ChainId {
+    pub fn new(chain_id: impl Into<String>) -> Self {
+        Self(chain_id.into())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for ChainId {
+    fn from(chain_id: &str) -> Self {
+        Self(chain_id.to_string())
+    }
+}
+
+impl From<String> for ChainId {
+    fn from(chain_id: String) -> Self {
+        Self(chain_id)
+    }
+}
+
+impl fmt::Display for ChainId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Highest epoch a key chain has persisted or loaded from storage. Persisted
+// updates from states below the mark are rejected, so a restored backup or a
+// replayed state cannot repeat earlier keys. Chains that do not persist their
+// states leave the mark alone, so one such chain can drive any number of
+// independently instantiated states. Clones of a key chain share the mark. It
+// lives in memory only, so seed it with with_high_water_mark() from a trusted
+// source after a restart.
+#[derive(Clone, Debug, Default)]
+pub struct HighWaterMark(Arc<AtomicU64>);
+
+impl HighWaterMark {
+    pub fn new(epoch: u64) -> Self {
+        Self(Arc::new(AtomicU64::new(epoch)))
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn check(&self, state: &KeyChainState) -> Result<(), Errors> {
+        let high_water_mark: u64 = self.get();
+
+        if state.epoch < high_water_mark {
+            return Err(RollbackDetected(format!(
+                "State of chain {} is at epoch {}, but the key chain already reached epoch {}.",
+                state.storage_chain_id(),
+                state.epoch,
+                high_water_mark
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn raise(&self, epoch: u64) {
+        self.0.fetch_max(epoch, Ordering::SeqCst);
+    }
+
+    // Moves the mark up to a new state in one step, as long as no other update
+    // has moved it past the state the new one was derived from. Of several
+    // updates from the same state only the first claim succeeds. Returns the
+    // mark the claim replaced, for release().
+    pub(crate) fn claim(&self, new_state: &KeyChainState) -> Result<u64, Errors> {
+        let previous_epoch: u64 = new_state.previous_epoch()?;
+
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |high_water_mark| {
+                (high_water_mark <= previous_epoch).then_some(new_state.epoch)
+            })
+            .map_err(|high_water_mark| {
+                RollbackDetected(format!(
+                    "State of chain {} at epoch {} was already advanced, the key chain reached epoch {}.",
+                    new_state.storage_chain_id(),
+                    previous_epoch,
+                    high_water_mark
+                ))
+            })
+    }
+
+    // Undoes a claim whose state could not be persisted, unless the mark has
+    // moved on since.
+    pub(crate) fn release(&self, claimed_epoch: u64, previous_high_water_mark: u64) {
+        let _ = self.0.compare_exchange(
+            claimed_epoch,
+            previous_high_water_mark,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+    }
+
+    // Checks a state that is loaded into a key chain and moves the mark up to it.
+    pub(crate) fn observe(&self, state: &KeyChainState) -> Result<(), Errors> {
+        self.check(state)?;
+        self.raise(state.epoch);
+
+        Ok(())
+    }
+}
+
+// A key chain state tagged with the configuration that produced it. The epoch
+// counts the state transitions since instantiation. With the serde feature it
+// serializes through keychain_serde::SerializedState.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(
+        try_from = "super::keychain_serde::SerializedState",
+        into = "super::keychain_serde::SerializedState"
+    )
+)]
+pub struct KeyChainState {
+    algorithm: KeyChainAlgorithm,
+    chain_id: Option<ChainId>,
+    epoch: u64,
+    state_bytes: SecretBytes,
+}
+
+impl KeyChainState {
+    pub fn new(
+        algorithm: KeyChainAlgorithm,
+        chain_id: Option<ChainId>,
+        epoch: u64,
+        state_bytes: SecretBytes,
+    ) -> Result<Self, Errors> {
+        if state_bytes.len() != algorithm.state_length() {
+            return Err(InvalidLength(format!(
+                "Provided a state of {} bytes. Expected state length is {} bytes for {:?}.",
+                state_bytes.len(),
+                algorithm.state_length(),
+                algorithm
+            )));
+        }
+
+        Ok(Self {
+            algorithm,
+            chain_id,
+            epoch,
+            state_bytes,
+        })
+    }
+
+    // Successor of this state, produced by one step of the same chain.
+    pub(crate) fn advance(&self, state_bytes: SecretBytes) -> Result<Self, Errors> {
+        let epoch: u64 = self.epoch.checked_add(1).ok_or_else(|| {
+            MalformedState(format!(
+                "State of chain {} is at the last epoch and cannot be advanced.",
+                self.storage_chain_id()
+            ))
+        })?;
+
+        Ok(Self {
+            algorithm: self.algorithm,
+            chain_id: self.chain_id.clone(),
+            epoch,
+            state_bytes,
+        })
+    }
+
+    // Epoch of the state this one was advanced from.
+    pub(crate) fn previous_epoch(&self) -> Result<u64, Errors> {
+        self.epoch.checked_sub(1).ok_or_else(|| {
+            MalformedState(format!(
+                "State of chain {} is an initial state and was not advanced.",
+                self.storage_chain_id()
+            ))
+        })
+    }
+
+    pub fn check_belongs_to(
+        &self,
+        algorithm: KeyChainAlgorithm,
+        chain_id: Option<&ChainId>,
+    ) -> Result<(), Errors> {
+        if self.algorithm != algorithm {
+            return Err(StateMismatch(format!(
+                "State was produced by {:?} but the key chain is configured as {:?}.",
+                self.algorithm, algorithm
+            )));
+        }
+
+        if self.chain_id.as_ref() != chain_id {
+            return Err(StateMismatch(format!(
+                "State belongs to chain {:?} but the key chain is {:?}.",
+                self.chain_id, chain_id
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn get_algorithm(&self) -> KeyChainAlgorithm {
+        self.algorithm
+    }
+
+    pub fn get_chain_id(&self) -> Option<&ChainId> {
+        self.chain_id.as_ref()
+    }
+
+    pub fn get_epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn storage_chain_id(&self) -> ChainId {
+        self.chain_id
+            .clone()
+            .unwrap_or_else(|| self.algorithm.default_chain_id())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.state_bytes.as_bytes()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state_bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state_bytes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HKDF_SHA256: KeyChainAlgorithm = KeyChainAlgorithm::Hkdf {
+        hash_func: HashFunc::Sha256,
+        output_key_length: 32,
+    };
+
+    #[test]
+    fn test_new_rejects_wrong_state_length() {
+        let result = KeyChainState::new(HKDF_SHA256, None, 0, SecretBytes::zeroed(64));
+        assert!(matches!(result, Err(InvalidLength(_))));
+    }
+
+    #[test]
+    fn test_advance_keeps_tags_and_counts_epochs() {
+        let state = KeyChainState::new(
+            HKDF_SHA256,
+            Some(ChainId::from("tenant-a")),
+            0,
+            SecretBytes::zeroed(32),
+        )
+        .unwrap();
+
+        let next_state = state.advance(SecretBytes::from(vec![1u8; 32])).unwrap();
+
+        assert_eq!(next_state.get_epoch(), 1);
+        assert_eq!(next_state.get_algorithm(), HKDF_SHA256);
+        assert_eq!(next_state.get_chain_id(), Some(&ChainId::from("tenant-a")));
+        assert_eq!(next_state.previous_epoch().unwrap(), 0);
+        assert!(matches!(state.previous_epoch(), Err(MalformedState(_))));
+
+        // A decoded state at the last epoch cannot be advanced
+        let last_state =
+            KeyChainState::new(HKDF_SHA256, None, u64::MAX, SecretBytes::zeroed(32)).unwrap();
+        assert!(matches!(
+            last_state.advance(SecretBytes::zeroed(32)),
+            Err(MalformedState(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_belongs_to() {
+        let state = KeyChainState::new(
+            HKDF_SHA256,
+            Some(ChainId::from("tenant-a")),
+            0,
+            SecretBytes::zeroed(32),
+        )
+        .unwrap();
+
+        assert!(
+            state
+                .check_belongs_to(HKDF_SHA256, Some(&ChainId::from("tenant-a")))
+                .is_ok()
+        );
+
+        let other_algorithm = KeyChainAlgorithm::Xdrbg {
+            xof: Xof::Shake128,
+            output_key_length: 32,
+        };
+        assert!(matches!(
+            state.check_belongs_to(other_algorithm, Some(&ChainId::from("tenant-a"))),
+            Err(StateMismatch(_))
+        ));
+        assert!(matches!(
+            state.check_belongs_to(HKDF_SHA256, Some(&ChainId::from("tenant-b"))),
+            Err(StateMismatch(_))
+        ));
+        assert!(matches!(
+            state.check_belongs_to(HKDF_SHA256, None),
+            Err(StateMismatch(_))
+        ));
+    }
+}
@@ -0,0 +1,340 @@
+use super::{
+    ChainId, HighWaterMark, InitialState, KeyChainAlgorithm, NewState, RandomOutput,
+    hkdf_keychain::HkdfKeyChain,
+    keychain_transaction::PendingUpdate,
+    prg_keychain::PrgKeyChain,
+    storage_handler::{KeyChainType, Storage},
+    xdrbg_keychain::XdrbgKeyChain,
+};
+use crate::errors::Errors;
+use alloc::{boxed::Box, vec::Vec};
+
+pub trait KeyChainInfo {
+    fn algorithm(&self) -> KeyChainAlgorithm;
+
+    fn chain_id(&self) -> Option<&ChainId>;
+
+    fn high_water_mark(&self) -> &HighWaterMark;
+
+    // The storage the chain persists its states to, if it stores persistently.
+    fn storage(&self) -> Option<&dyn Storage>;
+
+    fn key_chain_type(&self) -> KeyChainType {
+        self.algorithm().key_chain_type()
+    }
+
+    fn state_length(&self) -> usize {
+        self.algorithm().state_length()
+    }
+
+    fn output_key_length(&self) -> usize {
+        self.algorithm().output_key_length()
+    }
+
+    fn storage_chain_id(&self) -> ChainId {
+        self.chain_id()
+            .cloned()
+            .unwrap_or_else(|| self.algorithm().default_chain_id())
+    }
+}
+
+pub trait KeyChain: KeyChainInfo {
+    type InstantiateParams: Default;
+    type UpdateParams: Default;
+
+    fn instantiate(
+        &self,
+        seed: &[u8],
+        params: Self::InstantiateParams,
+    ) -> Result<InitialState, Errors>;
+
+    fn update(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        params: Self::UpdateParams,
+    ) -> Result<(NewState, RandomOutput), Errors> {
+        self.begin_update(arbitrary_input_param, keychain_state, params)?
+            .commit()
+    }
+
+    fn begin_update(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        params: Self::UpdateParams,
+    ) -> Result<PendingUpdate<'_>, Errors>;
+
+    fn fetch_stored_state(&self, storage: &dyn Storage) -> Result<NewState, Errors> {
+        let stored_state: NewState = storage.fetch_state(&self.storage_chain_id())?;
+        stored_state.check_belongs_to(self.algorithm(), self.chain_id())?;
+        self.high_water_mark().observe(&stored_state)?;
+
+        Ok(stored_state)
+    }
+}
+
+// Dyn compatible counterpart of KeyChain. Every KeyChain gets it for free, using
+// the default instantiate/update parameters of the underlying chain.
+pub trait DynKeyChain: KeyChainInfo {
+    fn instantiate_dyn(&self, seed: &[u8]) -> Result<InitialState, Errors>;
+
+    fn update_dyn(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+    ) -> Result<(NewState, RandomOutput), Errors>;
+}
+
+pub type BoxedKeyChain = Box<dyn DynKeyChain>;
+
+impl<K: KeyChain> DynKeyChain for K {
+    fn instantiate_dyn(&self, seed: &[u8]) -> Result<InitialState, Errors> {
+        self.instantiate(seed, K::InstantiateParams::default())
+    }
+
+    fn update_dyn(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+    ) -> Result<(NewState, RandomOutput), Errors> {
+        self.update(
+            arbitrary_input_param,
+            keychain_state,
+            K::UpdateParams::default(),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HkdfParams {
+    pub extractor_salt: Option<Vec<u8>>,
+    pub info_param: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct XdrbgInstantiateParams {
+    pub alpha: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct XdrbgUpdateParams {
+    pub alpha_reseed: Option<Vec<u8>>,
+    pub alpha_generate: Option<Vec<u8>>,
+}
+
+impl KeyChainInfo for HkdfKeyChain {
+    fn algorithm(&self) -> KeyChainAlgorithm {
+        self.get_algorithm()
+    }
+
+    fn chain_id(&self) -> Option<&ChainId> {
+        self.get_chain_id()
+    }
+
+    fn high_water_mark(&self) -> &HighWaterMark {
+        self.get_high_water_mark()
+    }
+
+    fn storage(&self) -> Option<&dyn Storage> {
+        self.persistent_storage()
+    }
+}
+
+impl KeyChain for HkdfKeyChain {
+    type InstantiateParams = HkdfParams;
+    type UpdateParams = HkdfParams;
+
+    fn instantiate(&self, seed: &[u8], params: HkdfParams) -> Result<InitialState, Errors> {
+        self.key_chain_instantiate_ref(
+            seed,
+            params.extractor_salt.as_deref(),
+            params.info_param.as_deref(),
+        )
+    }
+
+    fn begin_update(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        params: HkdfParams,
+    ) -> Result<PendingUpdate<'_>, Errors> {
+        self.begin_key_chain_update_ref(
+            arbitrary_input_param,
+            keychain_state,
+            params.extractor_salt.as_deref(),
+            params.info_param.as_deref(),
+        )
+    }
+}
+
+impl KeyChainInfo for PrgKeyChain {
+    fn algorithm(&self) -> KeyChainAlgorithm {
+        self.get_algorithm()
+    }
+
+    fn chain_id(&self) -> Option<&ChainId> {
+        self.get_chain_id()
+    }
+
+    fn high_water_mark(&self) -> &HighWaterMark {
+        self.get_high_water_mark()
+    }
+
+    fn storage(&self) -> Option<&dyn Storage> {
+        self.persistent_storage()
+    }
+}
+
+impl KeyChain for PrgKeyChain {
+    type InstantiateParams = ();
+    type UpdateParams = ();
+
+    fn instantiate(&self, seed: &[u8], _params: ()) -> Result<InitialState, Errors> {
+        self.key_chain_instantiate(seed)
+    }
+
+    fn begin_update(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        _params: (),
+    ) -> Result<PendingUpdate<'_>, Errors> {
+        self.begin_key_chain_update(arbitrary_input_param, keychain_state)
+    }
+}
+
+impl KeyChainInfo for XdrbgKeyChain {
+    fn algorithm(&self) -> KeyChainAlgorithm {
+        self.get_algorithm()
+    }
+
+    fn chain_id(&self) -> Option<&ChainId> {
+        self.get_chain_id()
+    }
+
+    fn high_water_mark(&self) -> &HighWaterMark {
+        self.get_high_water_mark()
+    }
+
+    fn storage(&self) -> Option<&dyn Storage> {
+        self.persistent_storage()
+    }
+}
+
+impl KeyChain for XdrbgKeyChain {
+    type InstantiateParams = XdrbgInstantiateParams;
+    type UpdateParams = XdrbgUpdateParams;
+
+    fn instantiate(
+        &self,
+        seed: &[u8],
+        params: XdrbgInstantiateParams,
+    ) -> Result<InitialState, Errors> {
+        self.key_chain_instantiate_ref(seed, params.alpha.as_deref())
+    }
+
+    fn begin_update(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        params: XdrbgUpdateParams,
+    ) -> Result<PendingUpdate<'_>, Errors> {
+        self.begin_key_chain_update_ref(
+            arbitrary_input_param,
+            keychain_state,
+            params.alpha_reseed.as_deref(),
+            params.alpha_generate.as_deref(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HashFunc, Xof};
+
+    fn drive_key_chain<K: KeyChain>(
+        key_chain: &K,
+        seed: &[u8],
+        inputs: &[Vec<u8>],
+    ) -> Vec<RandomOutput> {
+        let mut state = key_chain
+            .instantiate(seed, K::InstantiateParams::default())
+            .unwrap();
+        let mut output_keys = Vec::new();
+
+        for input in inputs {
+            let (new_state, output_key) = key_chain
+                .update(input, &state, K::UpdateParams::default())
+                .unwrap();
+            state = new_state;
+            output_keys.push(output_key);
+        }
+
+        output_keys
+    }
+
+    #[test]
+    fn test_generic_driver_for_all_key_chains() {
+        let hkdf_kc = HkdfKeyChain::new(HashFunc::Sha256, Some(48), None, None).unwrap();
+        let prg_kc = PrgKeyChain::new(16, None, None).unwrap();
+        let xdrbg_kc = XdrbgKeyChain::new(Xof::Shake128, Some(40), None, None).unwrap();
+
+        let hkdf_keys = drive_key_chain(&hkdf_kc, &[0x11; 32], &[vec![0x01; 32], vec![0x02; 32]]);
+        let prg_keys = drive_key_chain(&prg_kc, &[0x22; 16], &[vec![0x01; 16], vec![0x02; 16]]);
+        let xdrbg_keys = drive_key_chain(&xdrbg_kc, &[0x33; 24], &[vec![0x01; 16], vec![0x02; 16]]);
+
+        assert!(hkdf_keys.iter().all(|key| key.len() == 48));
+        assert!(prg_keys.iter().all(|key| key.len() == 16));
+        assert!(xdrbg_keys.iter().all(|key| key.len() == 40));
+        assert_ne!(hkdf_keys[0], hkdf_keys[1]);
+    }
+
+    #[test]
+    fn test_trait_matches_inherent_api() {
+        let hkdf_kc = HkdfKeyChain::new(HashFunc::Sha512, None, None, None).unwrap();
+        let params = HkdfParams {
+            extractor_salt: Some(b"salt".to_vec()),
+            info_param: Some(b"info".to_vec()),
+        };
+
+        let via_trait = hkdf_kc.instantiate(b"seed", params.clone()).unwrap();
+        let via_inherent = hkdf_kc
+            .key_chain_instantiate_ref(
+                b"seed",
+                params.extractor_salt.as_deref(),
+                params.info_param.as_deref(),
+            )
+            .unwrap();
+
+        assert_eq!(via_trait, via_inherent);
+    }
+
+    #[test]
+    fn test_boxed_key_chains() {
+        let key_chains: Vec<(BoxedKeyChain, Vec<u8>)> = vec![
+            (
+                Box::new(HkdfKeyChain::new(HashFunc::Sha3_256, None, None, None).unwrap()),
+                vec![0x44; 32],
+            ),
+            (
+                Box::new(PrgKeyChain::new(24, None, None).unwrap()),
+                vec![0x55; 24],
+            ),
+            (
+                Box::new(XdrbgKeyChain::new(Xof::Shake256, None, None, None).unwrap()),
+                vec![0x66; 48],
+            ),
+        ];
+
+        for (key_chain, seed) in key_chains.iter() {
+            let initial_state = key_chain.instantiate_dyn(seed).unwrap();
+            let (new_state, output_key) = key_chain.update_dyn(seed, &initial_state).unwrap();
+
+            assert_eq!(initial_state.len(), key_chain.state_length());
+            assert_eq!(new_state.len(), key_chain.state_length());
+            assert_eq!(output_key.len(), key_chain.output_key_length());
+        }
+    }
+}
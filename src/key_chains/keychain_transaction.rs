@@ -0,0 +1,288 @@
+use super::{HighWaterMark, NewState, RandomOutput, storage_handler::Storage};
+use crate::errors::Errors;
+
+// An update whose new state has been computed but neither persisted nor handed
+// out yet. commit() writes the state to storage before the output key is
+// released, so a failed write never leaves a key in use that storage cannot
+// reproduce. Dropping or aborting the update discards (and wipes) both.
+//
+// The write is a compare-and-swap against the epoch the update started from.
+// If another writer advanced the chain in the meantime, commit() fails with
+// StateConflict instead of forking the chain, and the caller can retry from
+// the freshly fetched state. Within one process the high water mark is claimed
+// first, so of two persisted updates from the same state only one commits. The
+// other fails with RollbackDetected. Updates that are not persisted leave the
+// mark alone.
+#[must_use = "the output key is only released by commit()"]
+pub struct PendingUpdate<'a> {
+    storage: Option<&'a dyn Storage>,
+    high_water_mark: &'a HighWaterMark,
+    new_state: NewState,
+    random_output: RandomOutput,
+}
+
+impl<'a> PendingUpdate<'a> {
+    pub(crate) fn new(
+        storage: Option<&'a dyn Storage>,
+        high_water_mark: &'a HighWaterMark,
+        new_state: NewState,
+        random_output: RandomOutput,
+    ) -> Self {
+        Self {
+            storage,
+            high_water_mark,
+            new_state,
+            random_output,
+        }
+    }
+
+    pub fn new_state(&self) -> &NewState {
+        &self.new_state
+    }
+
+    pub fn commit(self) -> Result<(NewState, RandomOutput), Errors> {
+        if let Some(storage) = self.storage {
+            let previous_high_water_mark: u64 = self.high_water_mark.claim(&self.new_state)?;
+            let stored: Result<(), Errors> = storage.compare_and_swap(
+                &self.new_state.storage_chain_id(),
+                Some(self.new_state.previous_epoch()?),
+                &self.new_state,
+            );
+            self.release_on_error(stored, previous_high_water_mark)?;
+        }
+
+        Ok((self.new_state, self.random_output))
+    }
+
+    // Persists to the given async storage. The storage passed to the key
+    // chain, if any, is not written.
+    #[cfg(feature = "async")]
+    pub async fn commit_async(
+        self,
+        storage: &dyn super::async_storage::AsyncStorage,
+    ) -> Result<(NewState, RandomOutput), Errors> {
+        let previous_high_water_mark: u64 = self.high_water_mark.claim(&self.new_state)?;
+
+        let stored: Result<(), Errors> = storage
+            .compare_and_swap(
+                &self.new_state.storage_chain_id(),
+                Some(self.new_state.previous_epoch()?),
+                &self.new_state,
+            )
+            .await;
+        self.release_on_error(stored, previous_high_water_mark)?;
+
+        Ok((self.new_state, self.random_output))
+    }
+
+    pub fn abort(self) {}
+
+    // A failed write leaves the update retryable from the same state.
+    fn release_on_error(
+        &self,
+        stored: Result<(), Errors>,
+        previous_high_water_mark: u64,
+    ) -> Result<(), Errors> {
+        if stored.is_err() {
+            self.high_water_mark
+                .release(self.new_state.get_epoch(), previous_high_water_mark);
+        }
+
+        stored
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{
+        HashFunc, Xof,
+        errors::Errors::StorageFailure,
+        key_chains::{
+            ChainId,
+            hkdf_keychain::HkdfKeyChain,
+            prg_keychain::PrgKeyChain,
+            storage_handler::{DefaultStorage, KeyChainType},
+            xdrbg_keychain::XdrbgKeyChain,
+        },
+    };
+    use std::sync::Arc;
+
+    // Accepts nothing, e.g. a full disk.
+    struct FailingStorage;
+
+    impl Storage for FailingStorage {
+        fn store_state(&self, _: &ChainId, _: &NewState) -> Result<(), Errors> {
+            Err(StorageFailure("Disk full.".to_string()))
+        }
+
+        fn fetch_state(&self, _: &ChainId) -> Result<NewState, Errors> {
+            Err(StorageFailure("Disk full.".to_string()))
+        }
+
+        fn compare_and_swap(
+            &self,
+            _: &ChainId,
+            _: Option<u64>,
+            _: &NewState,
+        ) -> Result<(), Errors> {
+            Err(StorageFailure("Disk full.".to_string()))
+        }
+
+        fn list_chains(&self) -> Result<Vec<ChainId>, Errors> {
+            Err(StorageFailure("Disk full.".to_string()))
+        }
+
+        fn delete_state(&self, _: &ChainId) -> Result<(), Errors> {
+            Err(StorageFailure("Disk full.".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_update_propagates_storage_failure() {
+        let hkdf_kc = HkdfKeyChain::new(
+            HashFunc::Sha256,
+            None,
+            Some(true),
+            Some(Arc::new(FailingStorage)),
+        )
+        .unwrap();
+        let result = hkdf_kc.key_chain_instantiate_ref(b"seed", None, None);
+        assert!(matches!(result, Err(StorageFailure(_))));
+        let hkdf_state = HkdfKeyChain::new(HashFunc::Sha256, None, None, None)
+            .unwrap()
+            .key_chain_instantiate_ref(b"seed", None, None)
+            .unwrap();
+        let result = hkdf_kc.key_chain_update_ref(b"input", &hkdf_state, None, None);
+        assert!(matches!(result, Err(StorageFailure(_))));
+        assert_eq!(hkdf_kc.get_high_water_mark().get(), 0);
+
+        let prg_kc = PrgKeyChain::new(16, Some(true), Some(Arc::new(FailingStorage))).unwrap();
+        let result = prg_kc.key_chain_instantiate(&[0x11; 16]);
+        assert!(matches!(result, Err(StorageFailure(_))));
+        let prg_state = PrgKeyChain::new(16, None, None)
+            .unwrap()
+            .key_chain_instantiate(&[0x11; 16])
+            .unwrap();
+        let result = prg_kc.key_chain_update(&[0x22; 16], &prg_state);
+        assert!(matches!(result, Err(StorageFailure(_))));
+
+        let xdrbg_kc = XdrbgKeyChain::new(
+            Xof::Shake128,
+            None,
+            Some(true),
+            Some(Arc::new(FailingStorage)),
+        )
+        .unwrap();
+        let result = xdrbg_kc.key_chain_instantiate_ref(&[0x33; 24], None);
+        assert!(matches!(result, Err(StorageFailure(_))));
+        let xdrbg_state = XdrbgKeyChain::new(Xof::Shake128, None, None, None)
+            .unwrap()
+            .key_chain_instantiate_ref(&[0x33; 24], None)
+            .unwrap();
+        let result = xdrbg_kc.key_chain_update_ref(&[0x44; 16], &xdrbg_state, None, None);
+        assert!(matches!(result, Err(StorageFailure(_))));
+    }
+
+    #[test]
+    fn test_commit_persists_before_release() {
+        let storage = Arc::new(DefaultStorage::new(KeyChainType::XdrbgKeyChain));
+        let key_chain =
+            XdrbgKeyChain::new(Xof::Ascon, None, Some(true), Some(storage.clone())).unwrap();
+        let initial_state = key_chain
+            .key_chain_instantiate_ref(&[0x55; 24], None)
+            .unwrap();
+
+        let pending = key_chain
+            .begin_key_chain_update_ref(&[0x66; 16], &initial_state, None, None)
+            .unwrap();
+        let chain_id = initial_state.storage_chain_id();
+        assert_eq!(storage.fetch_state(&chain_id).unwrap(), initial_state);
+
+        let (new_state, _) = pending.commit().unwrap();
+        assert_eq!(storage.fetch_state(&chain_id).unwrap(), new_state);
+    }
+
+    #[test]
+    fn test_abort_leaves_storage_untouched() {
+        let storage = Arc::new(DefaultStorage::new(KeyChainType::PrgKeyChain));
+        let key_chain = PrgKeyChain::new(16, Some(true), Some(storage.clone())).unwrap();
+        let initial_state = key_chain.key_chain_instantiate(&[0x77; 16]).unwrap();
+
+        let (stored_state, _) = key_chain
+            .key_chain_update(&[0x88; 16], &initial_state)
+            .unwrap();
+
+        let pending = key_chain
+            .begin_key_chain_update(&[0x99; 16], &stored_state)
+            .unwrap();
+        assert_eq!(pending.new_state().get_epoch(), 2);
+        pending.abort();
+
+        assert_eq!(
+            storage
+                .fetch_state(&stored_state.storage_chain_id())
+                .unwrap(),
+            stored_state
+        );
+    }
+
+    #[test]
+    fn test_concurrent_updates_from_one_state_commit_once() {
+        let storage = Arc::new(DefaultStorage::new_all());
+        let key_chain =
+            HkdfKeyChain::new(HashFunc::Sha256, None, Some(true), Some(storage)).unwrap();
+        let initial_state = key_chain
+            .key_chain_instantiate_ref(b"seed", None, None)
+            .unwrap();
+        let barrier = std::sync::Barrier::new(2);
+
+        let results: Vec<Result<(NewState, RandomOutput), Errors>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..2)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let pending = key_chain
+                            .begin_key_chain_update_ref(b"input", &initial_state, None, None)
+                            .unwrap();
+                        barrier.wait();
+                        pending.commit()
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(
+            results
+                .iter()
+                .any(|result| matches!(result, Err(Errors::RollbackDetected(_))))
+        );
+        assert_eq!(key_chain.get_high_water_mark().get(), 1);
+    }
+
+    #[test]
+    fn test_chain_without_storage_drives_independent_states() {
+        let key_chain = HkdfKeyChain::new(HashFunc::Sha256, None, None, None).unwrap();
+        let state_a = key_chain
+            .key_chain_instantiate_ref(b"seed a", None, None)
+            .unwrap();
+        let state_b = key_chain
+            .key_chain_instantiate_ref(b"seed b", None, None)
+            .unwrap();
+
+        let (state_a_1, _) = key_chain
+            .key_chain_update_ref(b"input", &state_a, None, None)
+            .unwrap();
+        assert_eq!(state_a_1.get_epoch(), 1);
+        let (state_b_1, _) = key_chain
+            .key_chain_update_ref(b"input", &state_b, None, None)
+            .unwrap();
+        assert_eq!(state_b_1.get_epoch(), 1);
+        assert_eq!(key_chain.get_high_water_mark().get(), 0);
+    }
+}
@@ -0,0 +1,451 @@
+use super::{
+    KeyChainState,
+    any_keychain::AnyKeyChain,
+    keychain_serde::KeyChainConfig,
+    keychain_trait::KeyChainInfo,
+    state_encoding::{decode_state, encode_state, hex_decode, hex_encode},
+    storage_handler::Storage,
+};
+use crate::{
+    errors::Errors::{
+        self, AuthenticationFailure, InvalidConfiguration, KeyDerivationFailure, MalformedState,
+    },
+    secret_bytes::SecretBytes,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// Passphrase protected, portable file for a single key chain state, modelled
+// after Ethereum keystore v3 files. The state is sealed with XChaCha20-Poly1305
+// under a key derived by Argon2id. The key chain configuration, including the
+// defaults set on its builder, stays readable and is bound as associated data,
+// together with the format version.
+
+pub const KEYSTORE_VERSION: u32 = 1;
+
+const KDF_NAME: &str = "argon2id";
+const CIPHER_NAME: &str = "xchacha20-poly1305";
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+const DERIVED_KEY_LENGTH: usize = 32;
+
+// Argon2id costs, memory in KiB. The defaults follow the OWASP recommendation
+// of 19 MiB, two passes and one lane.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KeystoreParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+// Highest costs import_keystore() accepts. The costs are read from the file
+// before it is authenticated, so a crafted keystore could otherwise demand any
+// amount of memory and time. Allows 256 MiB, eight passes and four lanes.
+pub const MAX_KEYSTORE_PARAMS: KeystoreParams = KeystoreParams {
+    m_cost: 256 * 1024,
+    t_cost: 8,
+    p_cost: 4,
+};
+
+impl KeystoreParams {
+    fn check_within(&self, limits: KeystoreParams) -> Result<(), Errors> {
+        if self.m_cost > limits.m_cost || self.t_cost > limits.t_cost || self.p_cost > limits.p_cost
+        {
+            return Err(InvalidConfiguration(format!(
+                "Argon2id costs {:?} exceed the accepted limits {:?}.",
+                self, limits
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for KeystoreParams {
+    fn default() -> Self {
+        Self {
+            m_cost: 19 * 1024,
+            t_cost: 2,
+            p_cost: 1,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct KeystoreFile {
+    version: u32,
+    keychain: KeyChainConfig,
+    crypto: CryptoSection,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CryptoSection {
+    cipher: String,
+    nonce: String,
+    ciphertext: String,
+    kdf: String,
+    kdfparams: KdfParams,
+}
+
+#[derive(Deserialize, Serialize)]
+struct KdfParams {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: String,
+}
+
+pub fn export_keystore(
+    key_chain: &AnyKeyChain,
+    state: &KeyChainState,
+    passphrase: &[u8],
+    params: KeystoreParams,
+) -> Result<String, Errors> {
+    state.check_belongs_to(key_chain.algorithm(), key_chain.chain_id())?;
+    let config: KeyChainConfig = KeyChainConfig::from_key_chain(key_chain);
+
+    let mut salt = [0u8; SALT_LENGTH];
+    OsRng.fill_bytes(&mut salt);
+    let mut nonce = [0u8; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+
+    let derived_key: SecretBytes = derive_key(passphrase, &salt, params)?;
+    let associated_data: Vec<u8> = associated_data(KEYSTORE_VERSION, &config)?;
+
+    let ciphertext: Vec<u8> = XChaCha20Poly1305::new_from_slice(&derived_key)
+        .map_err(|_| KeyDerivationFailure("Derived key has the wrong length".to_string()))?
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: &encode_state(state),
+                aad: &associated_data,
+            },
+        )
+        .map_err(|_| AuthenticationFailure("Failed to seal the key chain state".to_string()))?;
+
+    let keystore_file = KeystoreFile {
+        version: KEYSTORE_VERSION,
+        keychain: config,
+        crypto: CryptoSection {
+            cipher: CIPHER_NAME.to_string(),
+            nonce: hex_encode(&nonce),
+            ciphertext: hex_encode(&ciphertext),
+            kdf: KDF_NAME.to_string(),
+            kdfparams: KdfParams {
+                m_cost: params.m_cost,
+                t_cost: params.t_cost,
+                p_cost: params.p_cost,
+                salt: hex_encode(&salt),
+            },
+        },
+    };
+
+    serde_json::to_string_pretty(&keystore_file)
+        .map_err(|err| MalformedState(format!("Failed to serialize the keystore: {}", err)))
+}
+
+// Rebuilds the key chain described by the keystore together with its state.
+// Given a storage, the key chain stores persistently to it and the imported
+// state is stored there as well, only into an empty slot. Keystores with
+// Argon2id costs above MAX_KEYSTORE_PARAMS are rejected.
+pub fn import_keystore(
+    keystore: &str,
+    passphrase: &[u8],
+    storage: Option<Arc<dyn Storage>>,
+) -> Result<(AnyKeyChain, KeyChainState), Errors> {
+    import_keystore_with_limits(keystore, passphrase, storage, MAX_KEYSTORE_PARAMS)
+}
+
+// import_keystore() with caller chosen limits on the Argon2id costs, checked
+// before any key is derived.
+pub fn import_keystore_with_limits(
+    keystore: &str,
+    passphrase: &[u8],
+    storage: Option<Arc<dyn Storage>>,
+    limits: KeystoreParams,
+) -> Result<(AnyKeyChain, KeyChainState), Errors> {
+    let keystore_file: KeystoreFile = serde_json::from_str(keystore)
+        .map_err(|err| MalformedState(format!("Failed to parse the keystore: {}", err)))?;
+
+    if keystore_file.version != KEYSTORE_VERSION {
+        return Err(MalformedState(format!(
+            "Unsupported keystore version {}. Supported version is {}.",
+            keystore_file.version, KEYSTORE_VERSION
+        )));
+    }
+
+    let crypto: &CryptoSection = &keystore_file.crypto;
+    if crypto.kdf != KDF_NAME || crypto.cipher != CIPHER_NAME {
+        return Err(MalformedState(format!(
+            "Unsupported kdf {} or cipher {}",
+            crypto.kdf, crypto.cipher
+        )));
+    }
+
+    let key_chain: AnyKeyChain = keystore_file.keychain.to_key_chain(storage.clone())?;
+
+    let salt: Vec<u8> = decode_hex_field(&crypto.kdfparams.salt, "salt")?;
+    let nonce: Vec<u8> = decode_hex_field(&crypto.nonce, "nonce")?;
+    let ciphertext: Vec<u8> = decode_hex_field(&crypto.ciphertext, "ciphertext")?;
+
+    if nonce.len() != NONCE_LENGTH {
+        return Err(MalformedState(format!(
+            "Nonce of {} bytes. Expected nonce length is {} bytes.",
+            nonce.len(),
+            NONCE_LENGTH
+        )));
+    }
+
+    let params = KeystoreParams {
+        m_cost: crypto.kdfparams.m_cost,
+        t_cost: crypto.kdfparams.t_cost,
+        p_cost: crypto.kdfparams.p_cost,
+    };
+    params.check_within(limits)?;
+    let derived_key: SecretBytes = derive_key(passphrase, &salt, params)?;
+
+    let encoded_state: SecretBytes = XChaCha20Poly1305::new_from_slice(&derived_key)
+        .map_err(|_| KeyDerivationFailure("Derived key has the wrong length".to_string()))?
+        .decrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: &associated_data(keystore_file.version, &keystore_file.keychain)?,
+            },
+        )
+        .map(SecretBytes::from)
+        .map_err(|_| AuthenticationFailure("Wrong passphrase or tampered keystore".to_string()))?;
+
+    let state: KeyChainState = decode_state(&encoded_state)?;
+    state.check_belongs_to(key_chain.algorithm(), key_chain.chain_id())?;
+
+    if let Some(storage) = storage {
+        storage.compare_and_swap(&state.storage_chain_id(), None, &state)?;
+    }
+
+    Ok((key_chain, state))
+}
+
+fn derive_key(
+    passphrase: &[u8],
+    salt: &[u8],
+    params: KeystoreParams,
+) -> Result<SecretBytes, Errors> {
+    let argon2_params = Params::new(
+        params.m_cost,
+        params.t_cost,
+        params.p_cost,
+        Some(DERIVED_KEY_LENGTH),
+    )
+    .map_err(|err| KeyDerivationFailure(format!("Invalid Argon2id parameters: {}", err)))?;
+
+    let mut derived_key = SecretBytes::zeroed(DERIVED_KEY_LENGTH);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params)
+        .hash_password_into(passphrase, salt, derived_key.as_mut_bytes())
+        .map_err(|err| KeyDerivationFailure(format!("Argon2id failed: {}", err)))?;
+
+    Ok(derived_key)
+}
+
+fn associated_data(version: u32, config: &KeyChainConfig) -> Result<Vec<u8>, Errors> {
+    let mut associated_data: Vec<u8> = version.to_be_bytes().to_vec();
+    let encoded_config: Vec<u8> = serde_json::to_vec(config)
+        .map_err(|err| MalformedState(format!("Failed to serialize the config: {}", err)))?;
+    associated_data.extend_from_slice(&encoded_config);
+
+    Ok(associated_data)
+}
+
+fn decode_hex_field(hex: &str, field_name: &str) -> Result<Vec<u8>, Errors> {
+    hex_decode(hex).ok_or_else(|| MalformedState(format!("Field {} is not valid hex", field_name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_chains::{
+        hkdf_keychain::HkdfKeyChain, prg_keychain::PrgKeyChain, storage_handler::DefaultStorage,
+        xdrbg_keychain::XdrbgKeyChain,
+    };
+    use crate::{HashFunc, Xof};
+
+    // Cheap costs, so the tests stay fast
+    const TEST_PARAMS: KeystoreParams = KeystoreParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn test_round_trip_rebuilds_key_chain() {
+        let hkdf_kc = HkdfKeyChain::builder(HashFunc::Sha512)
+            .with_output_key_length(40)
+            .with_extractor_salt(b"salt".to_vec())
+            .with_info_param(b"info".to_vec())
+            .with_chain_id("tenant-a")
+            .build()
+            .unwrap();
+        let (hkdf_state, _) = hkdf_kc
+            .key_chain_update_ref(
+                b"input",
+                &hkdf_kc
+                    .key_chain_instantiate_ref(b"seed", None, None)
+                    .unwrap(),
+                None,
+                None,
+            )
+            .unwrap();
+        let prg_kc = PrgKeyChain::new(24, None, None).unwrap();
+        let prg_state = prg_kc.key_chain_instantiate(&[0x11; 24]).unwrap();
+        let xdrbg_kc = XdrbgKeyChain::builder(Xof::Ascon)
+            .with_output_key_length(16)
+            .with_alpha_generate(b"generate".to_vec())
+            .with_chain_id("tenant-b")
+            .build()
+            .unwrap();
+        let xdrbg_state = xdrbg_kc
+            .key_chain_instantiate_ref(&[0x22; 24], None)
+            .unwrap();
+
+        for (exported_key_chain, state) in [
+            (AnyKeyChain::Hkdf(hkdf_kc), hkdf_state),
+            (AnyKeyChain::Prg(prg_kc), prg_state),
+            (AnyKeyChain::Xdrbg(xdrbg_kc), xdrbg_state),
+        ] {
+            let keystore =
+                export_keystore(&exported_key_chain, &state, b"correct horse", TEST_PARAMS)
+                    .unwrap();
+            let (key_chain, imported_state) =
+                import_keystore(&keystore, b"correct horse", None).unwrap();
+
+            assert_eq!(imported_state, state);
+            assert_eq!(imported_state.get_epoch(), state.get_epoch());
+            assert_eq!(key_chain.algorithm(), state.get_algorithm());
+            assert_eq!(key_chain.chain_id(), state.get_chain_id());
+            assert_eq!(
+                KeyChainConfig::from_key_chain(&key_chain),
+                KeyChainConfig::from_key_chain(&exported_key_chain)
+            );
+
+            // The builder defaults are restored, so both derive the same keys
+            assert_eq!(
+                key_chain
+                    .as_dyn()
+                    .update_dyn(&[0x33; 24], &imported_state)
+                    .unwrap(),
+                exported_key_chain
+                    .as_dyn()
+                    .update_dyn(&[0x33; 24], &state)
+                    .unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_import_binds_storage_and_stores_state() {
+        let key_chain = AnyKeyChain::Hkdf(
+            HkdfKeyChain::builder(HashFunc::Sha256)
+                .with_chain_id("tenant-a")
+                .build()
+                .unwrap(),
+        );
+        let state = key_chain.as_dyn().instantiate_dyn(b"seed").unwrap();
+        let keystore = export_keystore(&key_chain, &state, b"correct horse", TEST_PARAMS).unwrap();
+
+        let storage: Arc<DefaultStorage> = Arc::new(DefaultStorage::new_all());
+        let (imported_key_chain, imported_state) =
+            import_keystore(&keystore, b"correct horse", Some(storage.clone())).unwrap();
+        assert_eq!(storage.fetch_state(&"tenant-a".into()).unwrap(), state);
+
+        let (new_state, _) = imported_key_chain
+            .as_dyn()
+            .update_dyn(b"input", &imported_state)
+            .unwrap();
+        assert_eq!(storage.fetch_state(&"tenant-a".into()).unwrap(), new_state);
+
+        // A second import does not roll the stored chain back
+        assert!(matches!(
+            import_keystore(&keystore, b"correct horse", Some(storage.clone())),
+            Err(Errors::StateConflict(_))
+        ));
+    }
+
+    #[test]
+    fn test_wrong_passphrase_and_tampering_are_detected() {
+        let key_chain = XdrbgKeyChain::builder(Xof::Shake128)
+            .with_alpha_reseed(b"reseed".to_vec())
+            .build()
+            .unwrap();
+        let state = key_chain
+            .key_chain_instantiate_ref(&[0x44; 24], None)
+            .unwrap();
+        let key_chain = AnyKeyChain::Xdrbg(key_chain);
+        let keystore = export_keystore(&key_chain, &state, b"correct horse", TEST_PARAMS).unwrap();
+
+        assert!(matches!(
+            import_keystore(&keystore, b"battery staple", None),
+            Err(AuthenticationFailure(_))
+        ));
+
+        let relabelled_keystore = keystore.replace("\"Shake128\"", "\"Ascon\"");
+        assert!(matches!(
+            import_keystore(&relabelled_keystore, b"correct horse", None),
+            Err(AuthenticationFailure(_))
+        ));
+
+        // The builder defaults are authenticated as well
+        let reconfigured_keystore = keystore.replace(
+            &format!("\"{}\"", hex_encode(b"reseed")),
+            &format!("\"{}\"", hex_encode(b"other")),
+        );
+        assert_ne!(reconfigured_keystore, keystore);
+        assert!(matches!(
+            import_keystore(&reconfigured_keystore, b"correct horse", None),
+            Err(AuthenticationFailure(_))
+        ));
+
+        assert!(matches!(
+            import_keystore("{\"version\": 1}", b"correct horse", None),
+            Err(MalformedState(_))
+        ));
+    }
+
+    #[test]
+    fn test_costs_above_limits_are_rejected_before_deriving() {
+        let key_chain = PrgKeyChain::new(16, None, None).unwrap();
+        let state = key_chain.key_chain_instantiate(&[0x55; 16]).unwrap();
+        let keystore = export_keystore(
+            &AnyKeyChain::Prg(key_chain),
+            &state,
+            b"correct horse",
+            TEST_PARAMS,
+        )
+        .unwrap();
+
+        let limits = KeystoreParams {
+            m_cost: TEST_PARAMS.m_cost - 1,
+            ..MAX_KEYSTORE_PARAMS
+        };
+        assert!(matches!(
+            import_keystore_with_limits(&keystore, b"correct horse", None, limits),
+            Err(InvalidConfiguration(_))
+        ));
+
+        // A crafted file asking for 4 TiB of memory fails fast
+        let crafted_keystore = keystore.replace(
+            &format!("\"m_cost\": {}", TEST_PARAMS.m_cost),
+            &format!("\"m_cost\": {}", u32::MAX),
+        );
+        assert_ne!(crafted_keystore, keystore);
+        assert!(matches!(
+            import_keystore(&crafted_keystore, b"correct horse", None),
+            Err(InvalidConfiguration(_))
+        ));
+    }
+}
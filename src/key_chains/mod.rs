@@ -1,8 +1,40 @@
+pub mod any_keychain;
+#[cfg(feature = "async")]
+pub mod async_storage;
+pub mod authenticated_storage;
+#[cfg(feature = "std")]
+pub mod encrypted_storage;
+#[cfg(feature = "std")]
+pub mod file_storage;
 pub mod hkdf_keychain;
+pub mod keychain_rng;
+#[cfg(feature = "serde")]
+pub mod keychain_serde;
+pub mod keychain_session;
+#[cfg(feature = "std")]
+pub mod keychain_shared;
+pub mod keychain_state;
+pub mod keychain_trait;
+pub mod keychain_transaction;
+#[cfg(feature = "keystore")]
+pub mod keystore;
 pub mod prg_keychain;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_storage;
+pub mod state_encoding;
 pub mod storage_handler;
 pub mod xdrbg_keychain;
 
-pub type InitialState = Vec<u8>;
-pub type NewState = Vec<u8>;
-pub type RandomOutput = Vec<u8>;
+use crate::secret_bytes::SecretBytes;
+use zeroize::Zeroizing;
+
+pub use keychain_state::{ChainId, HighWaterMark, KeyChainAlgorithm, KeyChainState};
+
+pub type InitialState = KeyChainState;
+pub type NewState = KeyChainState;
+pub type RandomOutput = SecretBytes;
+
+// Stack counterparts of NewState and RandomOutput for the allocation free
+// key_chain_update_array() variants.
+pub type FixedState<const N: usize> = Zeroizing<[u8; N]>;
+pub type FixedOutput<const N: usize> = Zeroizing<[u8; N]>;
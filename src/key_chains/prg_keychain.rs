@@ -1,19 +1,31 @@
-use super::{InitialState, NewState, RandomOutput, storage_handler::Storage};
+use super::{
+    ChainId, FixedOutput, FixedState, HighWaterMark, InitialState, KeyChainAlgorithm, NewState,
+    RandomOutput, keychain_session::KeyChainSession, keychain_state::check_update_buffers,
+    keychain_transaction::PendingUpdate, storage_handler::Storage,
+};
 use crate::{
-    crypto_primitives::prg_ops::Prg,
-    errors::Errors::{self, UninitializedStorage},
+    crypto_primitives::prg_ops::{MAX_KEY_LEN, Prg},
+    errors::Errors::{self, InvalidConfiguration, UninitializedStorage},
+    secret_bytes::SecretBytes,
 };
-use std::sync::Arc;
+use alloc::{format, string::ToString, sync::Arc};
+use zeroize::{Zeroize, Zeroizing};
 
 #[derive(Clone)]
 pub struct PrgKeyChain {
     prg_obj: Prg,
     store_persistently: bool,
-    init_state: InitialState,
+    init_state: SecretBytes,
     storage: Option<Arc<dyn Storage>>,
+    chain_id: Option<ChainId>,
+    high_water_mark: HighWaterMark,
 }
 
 impl PrgKeyChain {
+    pub fn builder(security_param_lambda: usize) -> PrgKeyChainBuilder {
+        PrgKeyChainBuilder::new(security_param_lambda)
+    }
+
     pub fn new(
         security_param_lambda: usize,
         store_persistently: Option<bool>,
@@ -38,16 +50,81 @@ impl PrgKeyChain {
         Ok(Self {
             prg_obj: prg_obj,
             store_persistently: store_persistently,
-            init_state: vec![0u8; security_param_lambda],
+            init_state: SecretBytes::zeroed(security_param_lambda),
             storage: storage_choice,
+            chain_id: None,
+            high_water_mark: HighWaterMark::default(),
         })
     }
 
+    // A different chain, so it does not share the high water mark of this one.
+    pub fn with_chain_id(mut self, chain_id: impl Into<ChainId>) -> Self {
+        self.chain_id = Some(chain_id.into());
+        self.high_water_mark = HighWaterMark::default();
+        self
+    }
+
+    pub fn with_high_water_mark(mut self, epoch: u64) -> Self {
+        self.high_water_mark = HighWaterMark::new(epoch);
+        self
+    }
+
+    // Continues the chain stored under chain_id in the given storage, which
+    // this chain then stores persistently to. The configuration, including the
+    // builder defaults, is that of this chain, so the stored state must have
+    // been produced by the same algorithm. Fails with NoStoredState if nothing
+    // is stored there, and with RollbackDetected if the stored state is below
+    // the high water mark, e.g. one set with with_high_water_mark() from a
+    // trusted source.
+    pub fn resume(
+        self,
+        storage: Arc<dyn Storage>,
+        chain_id: impl Into<ChainId>,
+    ) -> Result<KeyChainSession<Self>, Errors> {
+        let chain_id: ChainId = chain_id.into();
+        let mut key_chain: Self = if self.chain_id.as_ref() == Some(&chain_id) {
+            self
+        } else {
+            self.with_chain_id(chain_id)
+        };
+        key_chain.store_persistently = true;
+        key_chain.storage = Some(storage);
+
+        KeyChainSession::from_storage(key_chain)
+    }
+
+    // Resumes the chain from its storage, or instantiates it there on first
+    // boot. Requires the chain to store persistently.
+    pub fn instantiate_or_resume(
+        self,
+        seed_for_prg_refreshing: &[u8],
+    ) -> Result<KeyChainSession<Self>, Errors> {
+        if self.persistent_storage().is_none() {
+            return Err(UninitializedStorage(
+                "Prg keychain storage not initialized.".to_string(),
+            ));
+        }
+
+        KeyChainSession::instantiate_or_resume(self, seed_for_prg_refreshing, ())
+    }
+
+    // A persistent chain stores its initial state right away, and only into an
+    // empty slot, so instantiating twice cannot fork a stored chain.
     pub fn key_chain_instantiate(
         &self,
         seed_for_prg_refreshing: &[u8],
     ) -> Result<InitialState, Errors> {
-        let initial_state: Vec<u8> = match self
+        let initial_state: InitialState = self.new_initial_state(seed_for_prg_refreshing)?;
+
+        if let Some(storage) = self.persistent_storage() {
+            storage.compare_and_swap(&initial_state.storage_chain_id(), None, &initial_state)?;
+        }
+
+        Ok(initial_state)
+    }
+
+    fn new_initial_state(&self, seed_for_prg_refreshing: &[u8]) -> Result<InitialState, Errors> {
+        let initial_state: SecretBytes = match self
             .prg_obj
             .prg_refresh(&self.init_state, seed_for_prg_refreshing)
         {
@@ -55,45 +132,254 @@ impl PrgKeyChain {
             Err(err) => return Err(err),
         };
 
-        Ok(initial_state)
+        InitialState::new(
+            self.get_algorithm(),
+            self.chain_id.clone(),
+            0,
+            initial_state,
+        )
     }
 
     pub fn key_chain_update(
         &self,
         arbitrary_input_param: &[u8],
-        keychain_state: &[u8],
+        keychain_state: &NewState,
     ) -> Result<(NewState, RandomOutput), Errors> {
-        let refreshed_prg_state: Vec<u8> = match self
-            .prg_obj
-            .prg_refresh(keychain_state, arbitrary_input_param)
-        {
-            Ok(prg_state_after_refreshing) => prg_state_after_refreshing,
-            Err(err) => return Err(err),
-        };
+        self.begin_key_chain_update(arbitrary_input_param, keychain_state)?
+            .commit()
+    }
+
+    pub fn begin_key_chain_update(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+    ) -> Result<PendingUpdate<'_>, Errors> {
+        keychain_state.check_belongs_to(self.get_algorithm(), self.chain_id.as_ref())?;
+
+        let lambda: usize = self.get_chosen_security_param_lambda();
+        let mut new_state_of_key_chain: SecretBytes = SecretBytes::zeroed(lambda);
+        let mut random_output: RandomOutput = RandomOutput::zeroed(lambda);
+        self.derive_update_into(
+            arbitrary_input_param,
+            keychain_state.as_bytes(),
+            &mut new_state_of_key_chain,
+            &mut random_output,
+        )?;
+
+        Ok(PendingUpdate::new(
+            self.persistent_storage(),
+            &self.high_water_mark,
+            keychain_state.advance(new_state_of_key_chain)?,
+            random_output,
+        ))
+    }
+
+    // Allocation free update on raw state bytes for chains that do not store
+    // persistently. State and output key are both lambda bytes long. Epochs
+    // and rollback checks are left to the caller.
+    pub fn key_chain_update_into(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8],
+        new_state: &mut [u8],
+        output_key: &mut [u8],
+    ) -> Result<(), Errors> {
+        if self.store_persistently {
+            return Err(InvalidConfiguration(
+                "Prg keychain stores persistently, use key_chain_update.".to_string(),
+            ));
+        }
+
+        let lambda: usize = self.get_chosen_security_param_lambda();
+        check_update_buffers(lambda, lambda, current_state, new_state, output_key)?;
+
+        self.derive_update_into(arbitrary_input_param, current_state, new_state, output_key)
+    }
+
+    // key_chain_update_into() for a lambda known at compile time.
+    pub fn key_chain_update_array<const L: usize>(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8; L],
+    ) -> Result<(FixedState<L>, FixedOutput<L>), Errors> {
+        let mut new_state: FixedState<L> = Zeroizing::new([0u8; L]);
+        let mut output_key: FixedOutput<L> = Zeroizing::new([0u8; L]);
+        self.key_chain_update_into(
+            arbitrary_input_param,
+            current_state,
+            new_state.as_mut_slice(),
+            output_key.as_mut_slice(),
+        )?;
+
+        Ok((new_state, output_key))
+    }
+
+    // The async variants persist to the given storage in place of the one
+    // passed to new(). The initial state is persisted as well, only into an
+    // empty slot like key_chain_instantiate.
+    #[cfg(feature = "async")]
+    pub async fn key_chain_instantiate_async(
+        &self,
+        seed_for_prg_refreshing: &[u8],
+        storage: &dyn super::async_storage::AsyncStorage,
+    ) -> Result<InitialState, Errors> {
+        let initial_state: InitialState = self.new_initial_state(seed_for_prg_refreshing)?;
+        storage
+            .compare_and_swap(&initial_state.storage_chain_id(), None, &initial_state)
+            .await?;
+
+        Ok(initial_state)
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn key_chain_update_async(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        storage: &dyn super::async_storage::AsyncStorage,
+    ) -> Result<(NewState, RandomOutput), Errors> {
+        self.begin_key_chain_update(arbitrary_input_param, keychain_state)?
+            .commit_async(storage)
+            .await
+    }
+
+    // One next() step without fresh input, persisted on commit like an update.
+    pub(crate) fn begin_key_chain_next(
+        &self,
+        keychain_state: &NewState,
+    ) -> Result<PendingUpdate<'_>, Errors> {
+        keychain_state.check_belongs_to(self.get_algorithm(), self.chain_id.as_ref())?;
 
         let (random_output, new_state_of_key_chain) =
-            match self.prg_obj.prg_next(&refreshed_prg_state) {
-                Ok(total_output) => total_output,
-                Err(err) => return Err(err),
-            };
+            self.prg_obj.prg_next(keychain_state.as_bytes())?;
+
+        Ok(PendingUpdate::new(
+            self.persistent_storage(),
+            &self.high_water_mark,
+            keychain_state.advance(new_state_of_key_chain)?,
+            random_output,
+        ))
+    }
+
+    // Refreshes with the input and runs next() straight into the new state and
+    // the output key. The refreshed state lives on the stack.
+    fn derive_update_into(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8],
+        new_state: &mut [u8],
+        output_key: &mut [u8],
+    ) -> Result<(), Errors> {
+        let mut refreshed_buffer: [u8; MAX_KEY_LEN] = [0u8; MAX_KEY_LEN];
+        let refreshed_prg_state: &mut [u8] =
+            &mut refreshed_buffer[..self.get_chosen_security_param_lambda().min(MAX_KEY_LEN)];
 
+        let result: Result<(), Errors> = self
+            .prg_obj
+            .prg_refresh_into(current_state, arbitrary_input_param, refreshed_prg_state)
+            .and_then(|_| {
+                self.prg_obj
+                    .prg_next_into(refreshed_prg_state, output_key, new_state)
+            });
+        refreshed_buffer.zeroize();
+
+        result
+    }
+
+    pub(crate) fn persistent_storage(&self) -> Option<&dyn Storage> {
         if self.store_persistently {
-            if let Some(storage) = &self.storage {
-                storage.store_state_for_prg_keychain(
-                    &new_state_of_key_chain,
-                    self.prg_obj.get_chosen_security_param_lambda(),
-                );
-            }
+            self.storage.as_deref()
+        } else {
+            None
+        }
+    }
+
+    pub fn get_algorithm(&self) -> KeyChainAlgorithm {
+        KeyChainAlgorithm::Prg {
+            security_param_lambda: self.prg_obj.get_chosen_security_param_lambda(),
+        }
+    }
+
+    pub fn get_chain_id(&self) -> Option<&ChainId> {
+        self.chain_id.as_ref()
+    }
+
+    pub fn get_high_water_mark(&self) -> &HighWaterMark {
+        &self.high_water_mark
+    }
+
+    pub fn get_chosen_security_param_lambda(&self) -> usize {
+        self.prg_obj.get_chosen_security_param_lambda()
+    }
+}
+
+// Typed alternative to new(). Setting a storage makes the chain store its
+// states persistently.
+#[derive(Clone)]
+pub struct PrgKeyChainBuilder {
+    security_param_lambda: usize,
+    storage: Option<Arc<dyn Storage>>,
+    chain_id: Option<ChainId>,
+    high_water_mark: Option<u64>,
+}
+
+impl PrgKeyChainBuilder {
+    pub fn new(security_param_lambda: usize) -> Self {
+        Self {
+            security_param_lambda,
+            storage: None,
+            chain_id: None,
+            high_water_mark: None,
+        }
+    }
+
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn with_chain_id(mut self, chain_id: impl Into<ChainId>) -> Self {
+        self.chain_id = Some(chain_id.into());
+        self
+    }
+
+    pub fn with_high_water_mark(mut self, epoch: u64) -> Self {
+        self.high_water_mark = Some(epoch);
+        self
+    }
+
+    // Lambda is the AES key size underneath.
+    pub fn build(self) -> Result<PrgKeyChain, Errors> {
+        KeyChainAlgorithm::Prg {
+            security_param_lambda: self.security_param_lambda,
+        }
+        .check_parameters()?;
+
+        if self.chain_id.as_ref().is_some_and(ChainId::is_empty) {
+            return Err(InvalidConfiguration(
+                "Prg keychain chain ID must not be empty.".to_string(),
+            ));
         }
 
-        Ok((new_state_of_key_chain, random_output))
+        let mut key_chain: PrgKeyChain = PrgKeyChain::new(
+            self.security_param_lambda,
+            Some(self.storage.is_some()),
+            self.storage,
+        )?;
+        key_chain.chain_id = self.chain_id;
+        key_chain.high_water_mark = HighWaterMark::new(self.high_water_mark.unwrap_or(0));
+
+        Ok(key_chain)
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
+    use crate::HashFunc;
     use crate::errors::Errors;
+    use crate::key_chains::hkdf_keychain::HkdfKeyChain;
+    use crate::key_chains::keychain_trait::KeyChainInfo;
     use crate::key_chains::storage_handler::{DefaultStorage, KeyChainType, Storage};
     use std::sync::Arc;
 
@@ -141,7 +427,7 @@ mod tests {
         assert_eq!(random_output.len(), lambda);
 
         // Check that state is stored in storage
-        let fetched_state = storage.fetch_prg_keychain_state(lambda).unwrap();
+        let fetched_state = storage.fetch_state(&keychain.storage_chain_id()).unwrap();
         assert_eq!(fetched_state, new_state);
     }
 
@@ -164,10 +450,110 @@ mod tests {
     #[test]
     fn test_storage_fetch_error() {
         let storage = Arc::new(DefaultStorage::new(KeyChainType::PrgKeyChain));
-        let err = storage.fetch_prg_keychain_state(42).unwrap_err();
+        let err = storage.fetch_state(&"prg/42".into()).unwrap_err();
         match err {
-            Errors::NoStoredState(msg) => assert!(msg.contains("No Prg state found")),
+            Errors::NoStoredState(msg) => assert!(msg.contains("No state found for chain")),
             _ => panic!("Expected NoStoredState error"),
         }
     }
+
+    #[test]
+    fn test_update_rejects_state_of_other_lambda() {
+        let keychain_16 = PrgKeyChain::new(16, None, None).unwrap();
+        let keychain_24 = PrgKeyChain::new(24, None, None).unwrap();
+        let state_24 = keychain_24.key_chain_instantiate(&sample_seed(24)).unwrap();
+
+        let result = keychain_16.key_chain_update(&sample_seed(16), &state_24);
+
+        assert!(matches!(result, Err(Errors::StateMismatch(_))));
+    }
+
+    #[test]
+    fn test_resume_rejects_state_of_other_algorithm() {
+        let storage = Arc::new(DefaultStorage::new_all());
+        let hkdf_keychain =
+            HkdfKeyChain::new(HashFunc::Sha256, None, Some(true), Some(storage.clone()))
+                .unwrap()
+                .with_chain_id("tenant-a");
+        hkdf_keychain
+            .instantiate_or_resume_ref(b"seed", None, None)
+            .unwrap();
+
+        let result = PrgKeyChain::new(16, None, None)
+            .unwrap()
+            .resume(storage, "tenant-a");
+
+        assert!(matches!(result, Err(Errors::StateMismatch(_))));
+    }
+
+    #[test]
+    fn test_builder() {
+        let storage = Arc::new(DefaultStorage::new_all());
+        let keychain = PrgKeyChain::builder(24)
+            .with_storage(storage.clone())
+            .with_chain_id("tenant-a")
+            .build()
+            .unwrap();
+
+        let mut session = keychain.instantiate_or_resume(&sample_seed(24)).unwrap();
+        session.next_key(&sample_seed(24)).unwrap();
+        assert_eq!(
+            storage.fetch_state(&"tenant-a".into()).unwrap().get_epoch(),
+            1
+        );
+
+        let restarted_keychain = PrgKeyChain::builder(24)
+            .with_storage(storage.clone())
+            .with_chain_id("tenant-a")
+            .with_high_water_mark(2)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            restarted_keychain.instantiate_or_resume(&sample_seed(24)),
+            Err(Errors::RollbackDetected(_))
+        ));
+
+        assert!(matches!(
+            PrgKeyChain::builder(20).build(),
+            Err(Errors::InvalidConfiguration(_))
+        ));
+        assert!(matches!(
+            PrgKeyChain::builder(16).with_chain_id("").build(),
+            Err(Errors::InvalidConfiguration(_))
+        ));
+    }
+
+    #[test]
+    fn test_update_into_matches_update() {
+        let lambda = 24;
+        let keychain = PrgKeyChain::new(lambda, None, None).unwrap();
+        let initial_state = keychain
+            .key_chain_instantiate(&sample_seed(lambda))
+            .unwrap();
+        let (expected_state, expected_output) = keychain
+            .key_chain_update(&sample_seed(lambda), &initial_state)
+            .unwrap();
+
+        let (mut new_state, mut output_key) = ([0u8; 24], [0u8; 24]);
+        keychain
+            .key_chain_update_into(
+                &sample_seed(lambda),
+                initial_state.as_bytes(),
+                &mut new_state,
+                &mut output_key,
+            )
+            .unwrap();
+        assert_eq!(&new_state[..], expected_state.as_bytes());
+        assert_eq!(&output_key[..], expected_output.as_bytes());
+
+        let current_state: [u8; 24] = initial_state.as_bytes().try_into().unwrap();
+        let (new_state, output_key) = keychain
+            .key_chain_update_array(&sample_seed(lambda), &current_state)
+            .unwrap();
+        assert_eq!(&new_state[..], expected_state.as_bytes());
+        assert_eq!(&output_key[..], expected_output.as_bytes());
+
+        let result = keychain.key_chain_update_array(&sample_seed(16), &[0u8; 16]);
+        assert!(matches!(result, Err(Errors::InvalidLength(_))));
+    }
 }
@@ -0,0 +1,446 @@
+use super::{
+    ChainId, KeyChainAlgorithm, KeyChainState, NewState,
+    encrypted_storage::{check_kek_length, open, seal},
+    state_encoding::{decode_algorithm, decode_state, encode_algorithm, encode_state},
+    storage_handler::{Storage, check_expected_epoch},
+};
+use crate::{
+    errors::Errors::{self, AuthenticationFailure, NoStoredState, StorageFailure},
+    secret_bytes::SecretBytes,
+};
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+use sha2::{Digest, Sha256};
+use std::{
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use subtle::ConstantTimeEq;
+
+const COMMITMENT_DOMAIN: &[u8] = b"keychains_rs/state-commitment";
+
+const SCHEMA: &str = "
+    PRAGMA secure_delete = ON;
+    CREATE TABLE IF NOT EXISTS key_chain_states (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        chain_id TEXT NOT NULL,
+        epoch INTEGER NOT NULL,
+        recorded_at INTEGER NOT NULL,
+        algorithm BLOB NOT NULL,
+        commitment BLOB NOT NULL,
+        state BLOB,
+        encrypted INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS key_chain_states_by_chain
+        ON key_chain_states (chain_id, id);
+";
+
+// Audit entry of one stored state. The commitment is a SHA-256 hash of the
+// encoded state, so a state can later be matched against its entry without
+// the entry revealing it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StateRecord {
+    pub chain_id: ChainId,
+    pub epoch: u64,
+    pub recorded_at: u64,
+    pub algorithm: KeyChainAlgorithm,
+    pub commitment: [u8; 32],
+}
+
+impl StateRecord {
+    pub fn commits_to(&self, state: &KeyChainState) -> bool {
+        state_commitment(state).ct_eq(&self.commitment).into()
+    }
+}
+
+// Records every stored state as a row in an embedded SQLite database. Only the
+// latest row of a chain keeps the state itself. Older rows keep the epoch,
+// timestamp and commitment, because retaining superseded states would defeat
+// the forward security of the chain. With a KEK the states are sealed like in
+// EncryptedStorage.
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+    kek: Option<SecretBytes>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Errors> {
+        Self::from_connection(Connection::open(path).map_err(sqlite_failure)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, Errors> {
+        Self::from_connection(Connection::open_in_memory().map_err(sqlite_failure)?)
+    }
+
+    fn from_connection(connection: Connection) -> Result<Self, Errors> {
+        connection.execute_batch(SCHEMA).map_err(sqlite_failure)?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+            kek: None,
+        })
+    }
+
+    pub fn with_kek(mut self, kek: SecretBytes) -> Result<Self, Errors> {
+        check_kek_length(&kek)?;
+        self.kek = Some(kek);
+
+        Ok(self)
+    }
+
+    // All recorded states of a chain, oldest first.
+    pub fn lineage(&self, chain_id: &ChainId) -> Result<Vec<StateRecord>, Errors> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut statement = connection
+            .prepare(
+                "SELECT epoch, recorded_at, algorithm, commitment FROM key_chain_states
+                 WHERE chain_id = ?1 ORDER BY id",
+            )
+            .map_err(sqlite_failure)?;
+
+        let rows = statement
+            .query_map(params![chain_id.as_str()], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                    row.get::<_, Vec<u8>>(3)?,
+                ))
+            })
+            .map_err(sqlite_failure)?;
+
+        let mut records: Vec<StateRecord> = Vec::new();
+        for row in rows {
+            let (epoch, recorded_at, algorithm, commitment) = row.map_err(sqlite_failure)?;
+
+            records.push(StateRecord {
+                chain_id: chain_id.clone(),
+                epoch: epoch as u64,
+                recorded_at: recorded_at as u64,
+                algorithm: decode_algorithm(&algorithm)?,
+                commitment: commitment.try_into().map_err(|_| {
+                    StorageFailure(format!("Malformed commitment for chain {}", chain_id))
+                })?,
+            });
+        }
+
+        Ok(records)
+    }
+
+    // Drops all but the latest keep_latest records of a chain and returns how
+    // many were removed. The current state is always kept.
+    pub fn prune_history(&self, chain_id: &ChainId, keep_latest: usize) -> Result<usize, Errors> {
+        let connection = self.connection.lock().unwrap();
+
+        connection
+            .execute(
+                "DELETE FROM key_chain_states WHERE chain_id = ?1 AND id NOT IN (
+                     SELECT id FROM key_chain_states WHERE chain_id = ?1
+                     ORDER BY id DESC LIMIT ?2
+                 )",
+                params![chain_id.as_str(), keep_latest.max(1) as i64],
+            )
+            .map_err(sqlite_failure)
+    }
+
+    // Appends the state as the chain's latest row, once check_stored_epoch has
+    // accepted the epoch of the current one. Check and write share one
+    // transaction.
+    fn write_state(
+        &self,
+        chain_id: &ChainId,
+        state_of_key_chain: &NewState,
+        check_stored_epoch: impl FnOnce(Option<u64>) -> Result<(), Errors>,
+    ) -> Result<(), Errors> {
+        let encoded_state: SecretBytes = encode_state(state_of_key_chain);
+        let stored_state: Vec<u8> = match &self.kek {
+            Some(kek) => seal(
+                kek,
+                chain_id,
+                state_of_key_chain.get_epoch(),
+                &encoded_state,
+            )?,
+            None => encoded_state.to_vec(),
+        };
+
+        let mut algorithm: Vec<u8> = Vec::new();
+        encode_algorithm(state_of_key_chain.get_algorithm(), &mut algorithm);
+
+        let recorded_at: u64 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(sqlite_failure)?;
+
+        let stored_epoch: Option<i64> = transaction
+            .query_row(
+                "SELECT epoch FROM key_chain_states
+                 WHERE chain_id = ?1 ORDER BY id DESC LIMIT 1",
+                params![chain_id.as_str()],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sqlite_failure)?;
+        check_stored_epoch(stored_epoch.map(|epoch| epoch as u64))?;
+
+        transaction
+            .execute(
+                "UPDATE key_chain_states SET state = NULL WHERE chain_id = ?1",
+                params![chain_id.as_str()],
+            )
+            .map_err(sqlite_failure)?;
+        transaction
+            .execute(
+                "INSERT INTO key_chain_states
+                     (chain_id, epoch, recorded_at, algorithm, commitment, state, encrypted)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    chain_id.as_str(),
+                    state_of_key_chain.get_epoch() as i64,
+                    recorded_at as i64,
+                    algorithm,
+                    state_commitment(state_of_key_chain).to_vec(),
+                    SecretBytes::from(stored_state).as_bytes(),
+                    self.kek.is_some()
+                ],
+            )
+            .map_err(sqlite_failure)?;
+
+        transaction.commit().map_err(sqlite_failure)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn store_state(&self, chain_id: &ChainId, state_of_key_chain: &NewState) -> Result<(), Errors> {
+        self.write_state(chain_id, state_of_key_chain, |_| Ok(()))
+    }
+
+    fn compare_and_swap(
+        &self,
+        chain_id: &ChainId,
+        expected_epoch: Option<u64>,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors> {
+        self.write_state(chain_id, state_of_key_chain, |stored_epoch| {
+            check_expected_epoch(chain_id, expected_epoch, stored_epoch)
+        })
+    }
+
+    fn fetch_state(&self, chain_id: &ChainId) -> Result<NewState, Errors> {
+        let connection = self.connection.lock().unwrap();
+
+        let latest_row: Option<(Option<Vec<u8>>, bool)> = connection
+            .query_row(
+                "SELECT state, encrypted FROM key_chain_states
+                 WHERE chain_id = ?1 ORDER BY id DESC LIMIT 1",
+                params![chain_id.as_str()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(sqlite_failure)?;
+
+        let (stored_state, encrypted) = match latest_row {
+            Some((Some(stored_state), encrypted)) => (SecretBytes::from(stored_state), encrypted),
+            _ => {
+                return Err(NoStoredState(format!(
+                    "No state found for chain {}",
+                    chain_id
+                )));
+            }
+        };
+
+        if !encrypted {
+            return decode_state(&stored_state);
+        }
+
+        match &self.kek {
+            Some(kek) => decode_state(&open(kek, chain_id, &stored_state)?),
+            None => Err(AuthenticationFailure(format!(
+                "State of chain {} is encrypted but no KEK was provided",
+                chain_id
+            ))),
+        }
+    }
+
+    fn list_chains(&self) -> Result<Vec<ChainId>, Errors> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut statement = connection
+            .prepare("SELECT DISTINCT chain_id FROM key_chain_states ORDER BY chain_id")
+            .map_err(sqlite_failure)?;
+
+        statement
+            .query_map(params![], |row| row.get::<_, String>(0))
+            .map_err(sqlite_failure)?
+            .map(|chain_id| chain_id.map(ChainId::from).map_err(sqlite_failure))
+            .collect()
+    }
+
+    fn delete_state(&self, chain_id: &ChainId) -> Result<(), Errors> {
+        let connection = self.connection.lock().unwrap();
+
+        let deleted_rows: usize = connection
+            .execute(
+                "DELETE FROM key_chain_states WHERE chain_id = ?1",
+                params![chain_id.as_str()],
+            )
+            .map_err(sqlite_failure)?;
+
+        if deleted_rows == 0 {
+            return Err(NoStoredState(format!(
+                "No state found for chain {}",
+                chain_id
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+fn state_commitment(state: &KeyChainState) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(COMMITMENT_DOMAIN)
+        .chain_update(encode_state(state))
+        .finalize()
+        .into()
+}
+
+fn sqlite_failure(err: rusqlite::Error) -> Errors {
+    StorageFailure(format!("SQLite error: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        HashFunc, Xof,
+        key_chains::{
+            hkdf_keychain::HkdfKeyChain, keychain_session::KeyChainSession,
+            xdrbg_keychain::XdrbgKeyChain,
+        },
+    };
+    use std::{fs, sync::Arc};
+
+    fn run_chain(storage: Arc<SqliteStorage>, chain_id: &str, updates: usize) -> Vec<NewState> {
+        let key_chain = XdrbgKeyChain::new(Xof::Shake128, None, Some(true), Some(storage.clone()))
+            .unwrap()
+            .with_chain_id(chain_id);
+        let mut session =
+            KeyChainSession::instantiate(key_chain.clone(), &[0x11; 24], Default::default())
+                .unwrap();
+
+        let mut states: Vec<NewState> = vec![storage.fetch_state(&chain_id.into()).unwrap()];
+        for _ in 0..updates {
+            session.next_key(&[0x22; 16]).unwrap();
+            states.push(storage.fetch_state(&chain_id.into()).unwrap());
+        }
+
+        states
+    }
+
+    #[test]
+    fn test_lineage_records_every_update() {
+        let storage = Arc::new(SqliteStorage::open_in_memory().unwrap());
+        let states = run_chain(storage.clone(), "tenant-a", 3);
+        run_chain(storage.clone(), "tenant-b", 1);
+
+        let lineage = storage.lineage(&"tenant-a".into()).unwrap();
+
+        assert_eq!(
+            lineage
+                .iter()
+                .map(|record| record.epoch)
+                .collect::<Vec<u64>>(),
+            vec![0, 1, 2, 3]
+        );
+        for (record, state) in lineage.iter().zip(states.iter()) {
+            assert!(record.commits_to(state));
+            assert_eq!(record.algorithm, state.get_algorithm());
+        }
+        assert!(!lineage[0].commits_to(&states[1]));
+        assert_eq!(
+            storage.list_chains().unwrap(),
+            vec![ChainId::from("tenant-a"), ChainId::from("tenant-b")]
+        );
+    }
+
+    #[test]
+    fn test_prune_history_keeps_current_state() {
+        let storage = Arc::new(SqliteStorage::open_in_memory().unwrap());
+        let states = run_chain(storage.clone(), "tenant-a", 5);
+
+        assert_eq!(storage.prune_history(&"tenant-a".into(), 2).unwrap(), 4);
+        assert_eq!(storage.prune_history(&"tenant-a".into(), 0).unwrap(), 1);
+
+        let lineage = storage.lineage(&"tenant-a".into()).unwrap();
+        assert_eq!(lineage.len(), 1);
+        assert_eq!(lineage[0].epoch, 5);
+        assert_eq!(storage.fetch_state(&"tenant-a".into()).unwrap(), states[5]);
+
+        storage.delete_state(&"tenant-a".into()).unwrap();
+        assert!(matches!(
+            storage.fetch_state(&"tenant-a".into()),
+            Err(NoStoredState(_))
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_states_survive_reopening() {
+        let path =
+            std::env::temp_dir().join(format!("keychains_rs_sqlite_{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let kek = SecretBytes::from(vec![0x33; 32]);
+
+        let key_chain = HkdfKeyChain::new(HashFunc::Sha256, None, None, None)
+            .unwrap()
+            .with_chain_id("tenant-a");
+        let state = key_chain
+            .key_chain_instantiate_ref(b"seed", None, None)
+            .unwrap();
+
+        SqliteStorage::open(&path)
+            .unwrap()
+            .with_kek(kek.clone())
+            .unwrap()
+            .store_state(&"tenant-a".into(), &state)
+            .unwrap();
+
+        let reopened_storage = SqliteStorage::open(&path).unwrap();
+        assert!(matches!(
+            reopened_storage.fetch_state(&"tenant-a".into()),
+            Err(AuthenticationFailure(_))
+        ));
+
+        let reopened_storage = reopened_storage.with_kek(kek).unwrap();
+        assert_eq!(
+            reopened_storage.fetch_state(&"tenant-a".into()).unwrap(),
+            state
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_stale_update_is_rejected() {
+        let storage = Arc::new(SqliteStorage::open_in_memory().unwrap());
+        let states = run_chain(storage.clone(), "tenant-a", 2);
+
+        let stale_state: NewState = states[1].clone();
+        let key_chain = XdrbgKeyChain::new(Xof::Shake128, None, None, None)
+            .unwrap()
+            .with_chain_id("tenant-a");
+        let (forked_state, _) = key_chain
+            .key_chain_update_ref(&[0x33; 16], &stale_state, None, None)
+            .unwrap();
+
+        assert!(matches!(
+            storage.compare_and_swap(&"tenant-a".into(), Some(1), &forked_state),
+            Err(Errors::StateConflict(_))
+        ));
+        assert_eq!(storage.lineage(&"tenant-a".into()).unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,496 @@
+use super::{ChainId, KeyChainAlgorithm, KeyChainState, any_keychain::AnyKeyChain};
+use crate::{
+    HashFunc, Xof,
+    errors::Errors::{self, MalformedState},
+    secret_bytes::SecretBytes,
+};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+// Versioned envelope of a state at rest, as written by every storage backend
+// and by encode_state(). All integers are big endian.
+//
+//   magic "KCST" (4) | format version (1) | algorithm | chain ID flag (1)
+//   | [chain ID length (4) | chain ID] | epoch (8) | state length (4) | state
+//   | checksum (4)
+//
+// The algorithm is one of
+//   0 (HKDF) | hash function (1) | output key length (8)
+//   1 (PRG)  | security parameter lambda (8)
+//   2 (XDRBG) | Xof (1) | output key length (8)
+// with hash functions Sha256 = 0, Sha512 = 1, Sha3_256 = 2, Sha3_512 = 3 and
+// Xofs Shake128 = 0, Shake256 = 1, Ascon = 2. The chain ID flag is 0 for no
+// chain ID and 1 otherwise. The checksum is the first four bytes of SHA-256
+// over everything before it. It catches corruption, not tampering; use
+// AuthenticatedStorage or EncryptedStorage for that.
+
+pub const STATE_FORMAT_VERSION: u8 = 1;
+
+const MAGIC: &[u8; 4] = b"KCST";
+const CHECKSUM_LENGTH: usize = 4;
+
+const HKDF_TAG: u8 = 0;
+const PRG_TAG: u8 = 1;
+const XDRBG_TAG: u8 = 2;
+
+fn hash_func_to_byte(hash_func: HashFunc) -> u8 {
+    match hash_func {
+        HashFunc::Sha256 => 0,
+        HashFunc::Sha512 => 1,
+        HashFunc::Sha3_256 => 2,
+        HashFunc::Sha3_512 => 3,
+    }
+}
+
+fn hash_func_from_byte(byte: u8) -> Result<HashFunc, Errors> {
+    match byte {
+        0 => Ok(HashFunc::Sha256),
+        1 => Ok(HashFunc::Sha512),
+        2 => Ok(HashFunc::Sha3_256),
+        3 => Ok(HashFunc::Sha3_512),
+        _ => Err(MalformedState(format!("Unknown hash function {}", byte))),
+    }
+}
+
+fn xof_to_byte(xof: Xof) -> u8 {
+    match xof {
+        Xof::Shake128 => 0,
+        Xof::Shake256 => 1,
+        Xof::Ascon => 2,
+    }
+}
+
+fn xof_from_byte(byte: u8) -> Result<Xof, Errors> {
+    match byte {
+        0 => Ok(Xof::Shake128),
+        1 => Ok(Xof::Shake256),
+        2 => Ok(Xof::Ascon),
+        _ => Err(MalformedState(format!("Unknown Xof {}", byte))),
+    }
+}
+
+pub(crate) fn encode_algorithm(algorithm: KeyChainAlgorithm, encoded: &mut Vec<u8>) {
+    match algorithm {
+        KeyChainAlgorithm::Hkdf {
+            hash_func,
+            output_key_length,
+        } => {
+            encoded.push(HKDF_TAG);
+            encoded.push(hash_func_to_byte(hash_func));
+            encoded.extend_from_slice(&(output_key_length as u64).to_be_bytes());
+        }
+        KeyChainAlgorithm::Prg {
+            security_param_lambda,
+        } => {
+            encoded.push(PRG_TAG);
+            encoded.extend_from_slice(&(security_param_lambda as u64).to_be_bytes());
+        }
+        KeyChainAlgorithm::Xdrbg {
+            xof,
+            output_key_length,
+        } => {
+            encoded.push(XDRBG_TAG);
+            encoded.push(xof_to_byte(xof));
+            encoded.extend_from_slice(&(output_key_length as u64).to_be_bytes());
+        }
+    }
+}
+
+fn encoded_algorithm_length(algorithm: KeyChainAlgorithm) -> usize {
+    match algorithm {
+        KeyChainAlgorithm::Hkdf { .. } | KeyChainAlgorithm::Xdrbg { .. } => 1 + 1 + 8,
+        KeyChainAlgorithm::Prg { .. } => 1 + 8,
+    }
+}
+
+// The buffer is allocated at its final size up front. Growing it would free
+// the old allocation with the state bytes still in it.
+pub fn encode_state(state: &KeyChainState) -> SecretBytes {
+    let chain_id_length: usize = state
+        .get_chain_id()
+        .map_or(0, |chain_id| 4 + chain_id.as_str().len());
+    let encoded_length: usize = MAGIC.len()
+        + 1
+        + encoded_algorithm_length(state.get_algorithm())
+        + 1
+        + chain_id_length
+        + 8
+        + 4
+        + state.len()
+        + CHECKSUM_LENGTH;
+    let mut encoded: Vec<u8> = Vec::with_capacity(encoded_length);
+
+    encoded.extend_from_slice(MAGIC);
+    encoded.push(STATE_FORMAT_VERSION);
+    encode_algorithm(state.get_algorithm(), &mut encoded);
+
+    match state.get_chain_id() {
+        Some(chain_id) => {
+            encoded.push(1);
+            encoded.extend_from_slice(&(chain_id.as_str().len() as u32).to_be_bytes());
+            encoded.extend_from_slice(chain_id.as_str().as_bytes());
+        }
+        None => encoded.push(0),
+    }
+
+    encoded.extend_from_slice(&state.get_epoch().to_be_bytes());
+    encoded.extend_from_slice(&(state.len() as u32).to_be_bytes());
+    encoded.extend_from_slice(state.as_bytes());
+
+    let checksum: [u8; CHECKSUM_LENGTH] = checksum(&encoded);
+    encoded.extend_from_slice(&checksum);
+    debug_assert_eq!(encoded.len(), encoded_length);
+
+    SecretBytes::from(encoded)
+}
+
+pub fn decode_state(encoded: &[u8]) -> Result<KeyChainState, Errors> {
+    if encoded.len() < MAGIC.len() + 1 + CHECKSUM_LENGTH {
+        return Err(MalformedState(format!(
+            "Encoded state of {} bytes is too short",
+            encoded.len()
+        )));
+    }
+
+    if !encoded.starts_with(MAGIC) {
+        return Err(MalformedState("Not an encoded key chain state".to_string()));
+    }
+
+    let version: u8 = encoded[MAGIC.len()];
+    if version != STATE_FORMAT_VERSION {
+        return Err(MalformedState(format!(
+            "Unsupported state format version {}. Supported version is {}.",
+            version, STATE_FORMAT_VERSION
+        )));
+    }
+
+    let (body, stored_checksum) = encoded.split_at(encoded.len() - CHECKSUM_LENGTH);
+    if !bool::from(checksum(body).ct_eq(stored_checksum)) {
+        return Err(MalformedState("Checksum mismatch".to_string()));
+    }
+
+    let mut reader = Reader {
+        remaining: &body[MAGIC.len() + 1..],
+    };
+
+    let algorithm: KeyChainAlgorithm = read_algorithm(&mut reader)?;
+    algorithm
+        .check_parameters()
+        .map_err(|err| MalformedState(err.to_string()))?;
+
+    let chain_id: Option<ChainId> = match reader.read_u8()? {
+        0 => None,
+        1 => {
+            let chain_id_length: usize = reader.read_u32()? as usize;
+            let chain_id_bytes: &[u8] = reader.read_bytes(chain_id_length)?;
+            let chain_id = String::from_utf8(chain_id_bytes.to_vec())
+                .map_err(|_| MalformedState("Chain ID is not valid UTF-8".to_string()))?;
+            Some(ChainId::from(chain_id))
+        }
+        flag => return Err(MalformedState(format!("Unknown chain ID flag {}", flag))),
+    };
+
+    let epoch: u64 = reader.read_u64()?;
+    let state_length: usize = reader.read_u32()? as usize;
+    let state_bytes = SecretBytes::from(reader.read_bytes(state_length)?);
+
+    reader.finish()?;
+
+    KeyChainState::new(algorithm, chain_id, epoch, state_bytes)
+}
+
+// Decodes a state together with a key chain of the matching configuration,
+// ready to continue from it.
+pub fn decode_key_chain(encoded: &[u8]) -> Result<(AnyKeyChain, KeyChainState), Errors> {
+    let state: KeyChainState = decode_state(encoded)?;
+    let key_chain: AnyKeyChain =
+        AnyKeyChain::from_algorithm(state.get_algorithm(), state.get_chain_id().cloned())?;
+
+    Ok((key_chain, state))
+}
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_LENGTH] {
+    Sha256::digest(bytes)[..CHECKSUM_LENGTH].try_into().unwrap()
+}
+
+#[cfg(feature = "sqlite")]
+pub(crate) fn decode_algorithm(encoded: &[u8]) -> Result<KeyChainAlgorithm, Errors> {
+    let mut reader = Reader { remaining: encoded };
+
+    let algorithm: KeyChainAlgorithm = read_algorithm(&mut reader)?;
+    reader.finish()?;
+
+    Ok(algorithm)
+}
+
+fn read_algorithm(reader: &mut Reader<'_>) -> Result<KeyChainAlgorithm, Errors> {
+    match reader.read_u8()? {
+        HKDF_TAG => Ok(KeyChainAlgorithm::Hkdf {
+            hash_func: hash_func_from_byte(reader.read_u8()?)?,
+            output_key_length: reader.read_usize()?,
+        }),
+        PRG_TAG => Ok(KeyChainAlgorithm::Prg {
+            security_param_lambda: reader.read_usize()?,
+        }),
+        XDRBG_TAG => Ok(KeyChainAlgorithm::Xdrbg {
+            xof: xof_from_byte(reader.read_u8()?)?,
+            output_key_length: reader.read_usize()?,
+        }),
+        tag => Err(MalformedState(format!("Unknown algorithm tag {}", tag))),
+    }
+}
+
+#[cfg(any(feature = "serde", feature = "std"))]
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(any(feature = "serde", feature = "std"))]
+pub(crate) fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+struct Reader<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], Errors> {
+        if self.remaining.len() < length {
+            return Err(MalformedState(format!(
+                "Expected {} more bytes, found {}",
+                length,
+                self.remaining.len()
+            )));
+        }
+
+        let (bytes, remaining) = self.remaining.split_at(length);
+        self.remaining = remaining;
+
+        Ok(bytes)
+    }
+
+    fn finish(&self) -> Result<(), Errors> {
+        if !self.remaining.is_empty() {
+            return Err(MalformedState(format!(
+                "{} trailing bytes",
+                self.remaining.len()
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Errors> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Errors> {
+        Ok(u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, Errors> {
+        Ok(u64::from_be_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    // Lengths are encoded as u64, which may not fit usize on 32 bit targets.
+    fn read_usize(&mut self) -> Result<usize, Errors> {
+        let value: u64 = self.read_u64()?;
+
+        usize::try_from(value)
+            .map_err(|_| MalformedState(format!("Length {} does not fit this target", value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_chains::xdrbg_keychain::XdrbgKeyChain;
+
+    #[test]
+    fn test_round_trip() {
+        let states = [
+            KeyChainState::new(
+                KeyChainAlgorithm::Hkdf {
+                    hash_func: HashFunc::Sha3_512,
+                    output_key_length: 48,
+                },
+                Some(ChainId::from("tenant-a")),
+                7,
+                SecretBytes::from(vec![0x11; 64]),
+            )
+            .unwrap(),
+            KeyChainState::new(
+                KeyChainAlgorithm::Prg {
+                    security_param_lambda: 24,
+                },
+                None,
+                0,
+                SecretBytes::from(vec![0x22; 24]),
+            )
+            .unwrap(),
+            KeyChainState::new(
+                KeyChainAlgorithm::Xdrbg {
+                    xof: Xof::Ascon,
+                    output_key_length: 16,
+                },
+                Some(ChainId::from("tenant-b")),
+                u64::MAX,
+                SecretBytes::from(vec![0x33; 32]),
+            )
+            .unwrap(),
+        ];
+
+        for state in states {
+            let decoded = decode_state(&encode_state(&state)).unwrap();
+
+            assert_eq!(decoded, state);
+            assert_eq!(decoded.get_epoch(), state.get_epoch());
+            assert_eq!(decoded.get_chain_id(), state.get_chain_id());
+        }
+    }
+
+    #[test]
+    fn test_decode_key_chain_continues_the_chain() {
+        let key_chain = XdrbgKeyChain::new(Xof::Shake256, Some(24), None, None)
+            .unwrap()
+            .with_chain_id("tenant-a");
+        let state = key_chain
+            .key_chain_instantiate_ref(&[0x11; 48], None)
+            .unwrap();
+
+        let (decoded_key_chain, decoded_state) = decode_key_chain(&encode_state(&state)).unwrap();
+        let AnyKeyChain::Xdrbg(decoded_key_chain) = decoded_key_chain else {
+            panic!("Expected an Xdrbg key chain");
+        };
+
+        assert_eq!(decoded_key_chain.get_output_key_length(), 24);
+        assert_eq!(
+            decoded_key_chain
+                .key_chain_update_ref(&[0x22; 32], &decoded_state, None, None)
+                .unwrap(),
+            key_chain
+                .key_chain_update_ref(&[0x22; 32], &state, None, None)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_corrupted_or_unknown_version_is_rejected() {
+        let state = KeyChainState::new(
+            KeyChainAlgorithm::Hkdf {
+                hash_func: HashFunc::Sha256,
+                output_key_length: 32,
+            },
+            Some(ChainId::from("tenant-a")),
+            1,
+            SecretBytes::zeroed(32),
+        )
+        .unwrap();
+        let encoded: Vec<u8> = encode_state(&state).to_vec();
+
+        let mut corrupted = encoded.clone();
+        corrupted[20] ^= 0x01;
+        assert!(
+            matches!(decode_state(&corrupted), Err(MalformedState(msg)) if msg.contains("Checksum"))
+        );
+
+        let mut future_version = encoded.clone();
+        future_version[4] = STATE_FORMAT_VERSION + 1;
+        assert!(
+            matches!(decode_state(&future_version), Err(MalformedState(msg)) if msg.contains("version"))
+        );
+
+        assert!(matches!(decode_state(b"KCS"), Err(MalformedState(_))));
+        assert!(matches!(
+            decode_state(&encoded[4..]),
+            Err(MalformedState(_))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_or_extended_input_is_rejected() {
+        let state = KeyChainState::new(
+            KeyChainAlgorithm::Prg {
+                security_param_lambda: 16,
+            },
+            None,
+            3,
+            SecretBytes::zeroed(16),
+        )
+        .unwrap();
+        let encoded = encode_state(&state);
+
+        assert!(matches!(
+            decode_state(&encoded[..encoded.len() - 1]),
+            Err(MalformedState(_))
+        ));
+        assert!(matches!(
+            decode_state(&[encoded.as_bytes(), &[0u8]].concat()),
+            Err(MalformedState(_))
+        ));
+    }
+
+    #[test]
+    fn test_oversized_parameters_are_rejected() {
+        let crafted_algorithms = [
+            KeyChainAlgorithm::Hkdf {
+                hash_func: HashFunc::Sha256,
+                output_key_length: 1 << 40,
+            },
+            KeyChainAlgorithm::Xdrbg {
+                xof: Xof::Shake128,
+                output_key_length: usize::MAX,
+            },
+            KeyChainAlgorithm::Xdrbg {
+                xof: Xof::Shake128,
+                output_key_length: 0,
+            },
+        ];
+
+        for algorithm in crafted_algorithms {
+            // The envelope is well formed, only its parameters are out of range
+            let state = KeyChainState::new(algorithm, None, 0, SecretBytes::zeroed(32)).unwrap();
+            let encoded = encode_state(&state);
+
+            assert!(matches!(
+                decode_state(&encoded),
+                Err(Errors::MalformedState(_))
+            ));
+            assert!(matches!(
+                decode_key_chain(&encoded),
+                Err(Errors::MalformedState(_))
+            ));
+            assert!(matches!(
+                AnyKeyChain::from_algorithm(algorithm, None),
+                Err(Errors::InvalidConfiguration(_))
+            ));
+        }
+
+        let prg_state = KeyChainState::new(
+            KeyChainAlgorithm::Prg {
+                security_param_lambda: 17,
+            },
+            None,
+            0,
+            SecretBytes::zeroed(17),
+        )
+        .unwrap();
+        assert!(matches!(
+            decode_state(&encode_state(&prg_state)),
+            Err(Errors::MalformedState(_))
+        ));
+    }
+}
@@ -1,119 +1,447 @@
 use crate::{
-    HashFunc, Xof,
-    errors::Errors::{self, NoStoredState, UninitializedStorage},
-    key_chains::NewState,
+    errors::Errors::{self, StateConflict},
+    key_chains::{ChainId, NewState},
+    secret_bytes::SecretBytes,
 };
-use std::{collections::HashMap, sync::Mutex};
+use alloc::{format, vec::Vec};
+#[cfg(feature = "std")]
+use {
+    crate::errors::Errors::{NoStoredState, UninitializedStorage},
+    std::{
+        collections::HashMap,
+        sync::{Mutex, MutexGuard},
+    },
+};
+
+// States are keyed by the chain they belong to. The algorithm that produced a
+// state travels with it as metadata, so one backend can hold any number of
+// independent chains of any kind. Backends are shared between key chains and
+// threads, hence Send + Sync.
+pub trait Storage: Send + Sync {
+    fn store_state(&self, chain_id: &ChainId, state_of_key_chain: &NewState) -> Result<(), Errors>;
+
+    fn fetch_state(&self, chain_id: &ChainId) -> Result<NewState, Errors>;
+
+    // With expected_epoch Some(epoch), stores the state only if a state of
+    // the chain is stored and at that epoch. With expected_epoch None, only if
+    // nothing is stored yet. Otherwise fails with StateConflict, and the caller
+    // can retry from the fresh state. Check and write must be atomic with
+    // respect to every other writer of the backend.
+    fn compare_and_swap(
+        &self,
+        chain_id: &ChainId,
+        expected_epoch: Option<u64>,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors>;
 
-pub trait Storage {
-    fn store_state_for_hkdf_keychain(&self, state_of_key_chain: &[u8], hash_func: HashFunc);
-    fn store_state_for_prg_keychain(&self, state_of_key_chain: &[u8], security_param_lambda: usize);
-    fn store_state_for_xdrbg_keychain(&self, state_of_key_chain: &[u8], xof: Xof);
+    fn list_chains(&self) -> Result<Vec<ChainId>, Errors>;
 
-    fn fetch_hkdf_keychain_state(&self, hash_func: HashFunc) -> Result<NewState, Errors>;
-    fn fetch_prg_keychain_state(&self, security_param_lambda: usize) -> Result<NewState, Errors>;
-    fn fetch_xdrbg_keychain_state(&self, xof: Xof) -> Result<NewState, Errors>;
+    fn delete_state(&self, chain_id: &ChainId) -> Result<(), Errors>;
 }
 
+// Backends that persist opaque bytes per chain. Storage layers such as
+// EncryptedStorage turn states into blobs and keep them in one of these.
+pub trait BlobStorage: Send + Sync {
+    fn store_blob(&self, chain_id: &ChainId, blob: &[u8]) -> Result<(), Errors>;
+
+    fn fetch_blob(&self, chain_id: &ChainId) -> Result<SecretBytes, Errors>;
+
+    // Replaces the blob only if the stored one equals current_blob, where None
+    // stands for no blob. Otherwise fails with StateConflict.
+    fn compare_and_swap_blob(
+        &self,
+        chain_id: &ChainId,
+        current_blob: Option<&[u8]>,
+        new_blob: &[u8],
+    ) -> Result<(), Errors>;
+
+    fn list_blobs(&self) -> Result<Vec<ChainId>, Errors>;
+
+    fn delete_blob(&self, chain_id: &ChainId) -> Result<(), Errors>;
+}
+
+// In memory backend. It needs std for its mutexes, so no_std targets implement
+// Storage over their own synchronization instead.
+#[cfg(feature = "std")]
 pub struct DefaultStorage {
-    hkdf_map: Option<Mutex<HashMap<HashFunc, NewState>>>,
-    prg_map: Option<Mutex<HashMap<usize, NewState>>>,
-    xdrbg_map: Option<Mutex<HashMap<Xof, NewState>>>,
+    hkdf_map: Option<Mutex<HashMap<ChainId, NewState>>>,
+    prg_map: Option<Mutex<HashMap<ChainId, NewState>>>,
+    xdrbg_map: Option<Mutex<HashMap<ChainId, NewState>>>,
 }
 
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum KeyChainType {
     HkdfKeyChain,
     PrgKeyChain,
     XdrbgKeyChain,
 }
 
+impl KeyChainType {
+    pub const ALL: [KeyChainType; 3] = [
+        KeyChainType::HkdfKeyChain,
+        KeyChainType::PrgKeyChain,
+        KeyChainType::XdrbgKeyChain,
+    ];
+}
+
+#[cfg(feature = "std")]
 impl DefaultStorage {
     pub fn new(key_chain_type: KeyChainType) -> Self {
+        Self::with_types(&[key_chain_type])
+    }
+
+    // Serves every key chain type, so chains of mixed kinds can share it.
+    pub fn new_all() -> Self {
+        Self::with_types(&KeyChainType::ALL)
+    }
+
+    pub fn with_types(key_chain_types: &[KeyChainType]) -> Self {
+        let new_map_if_served = |key_chain_type: KeyChainType| {
+            key_chain_types
+                .contains(&key_chain_type)
+                .then(|| Mutex::new(HashMap::new()))
+        };
+
+        Self {
+            hkdf_map: new_map_if_served(KeyChainType::HkdfKeyChain),
+            prg_map: new_map_if_served(KeyChainType::PrgKeyChain),
+            xdrbg_map: new_map_if_served(KeyChainType::XdrbgKeyChain),
+        }
+    }
+
+    pub fn serves(&self, key_chain_type: KeyChainType) -> bool {
+        self.map_for(key_chain_type).is_some()
+    }
+
+    pub fn state_count(&self, key_chain_type: KeyChainType) -> usize {
+        self.map_for(key_chain_type)
+            .map_or(0, |map_mutex| map_mutex.lock().unwrap().len())
+    }
+
+    fn map_for(&self, key_chain_type: KeyChainType) -> Option<&Mutex<HashMap<ChainId, NewState>>> {
         match key_chain_type {
-            KeyChainType::HkdfKeyChain => Self {
-                hkdf_map: Some(Mutex::new(HashMap::new())),
-                prg_map: None,
-                xdrbg_map: None,
-            },
-            KeyChainType::PrgKeyChain => Self {
-                hkdf_map: None,
-                prg_map: Some(Mutex::new(HashMap::new())),
-                xdrbg_map: None,
-            },
-            KeyChainType::XdrbgKeyChain => Self {
-                hkdf_map: None,
-                prg_map: None,
-                xdrbg_map: Some(Mutex::new(HashMap::new())),
-            },
+            KeyChainType::HkdfKeyChain => self.hkdf_map.as_ref(),
+            KeyChainType::PrgKeyChain => self.prg_map.as_ref(),
+            KeyChainType::XdrbgKeyChain => self.xdrbg_map.as_ref(),
         }
     }
+
+    fn served_maps(&self) -> impl Iterator<Item = &Mutex<HashMap<ChainId, NewState>>> {
+        [&self.hkdf_map, &self.prg_map, &self.xdrbg_map]
+            .into_iter()
+            .flatten()
+    }
 }
 
+#[cfg(feature = "std")]
+impl Default for DefaultStorage {
+    fn default() -> Self {
+        Self::new_all()
+    }
+}
+
+#[cfg(feature = "std")]
 impl Storage for DefaultStorage {
-    fn store_state_for_hkdf_keychain(&self, state_of_key_chain: &[u8], hash_func: HashFunc) {
-        if let Some(ref map_mutex) = self.hkdf_map {
-            let mut map = map_mutex.lock().unwrap();
-            map.insert(hash_func, state_of_key_chain.to_vec());
+    fn store_state(&self, chain_id: &ChainId, state_of_key_chain: &NewState) -> Result<(), Errors> {
+        let key_chain_type: KeyChainType = state_of_key_chain.get_algorithm().key_chain_type();
+
+        let map_mutex = self.map_for(key_chain_type).ok_or_else(|| {
+            UninitializedStorage(format!(
+                "Storage does not serve {:?} states (chain {})",
+                key_chain_type, chain_id
+            ))
+        })?;
+
+        // A chain ID names a single chain, even if it changes its algorithm
+        for other_map_mutex in self.served_maps() {
+            if !std::ptr::eq(other_map_mutex, map_mutex) {
+                other_map_mutex.lock().unwrap().remove(chain_id);
+            }
         }
+
+        map_mutex
+            .lock()
+            .unwrap()
+            .insert(chain_id.clone(), state_of_key_chain.clone());
+
+        Ok(())
     }
 
-    fn store_state_for_prg_keychain(
+    fn compare_and_swap(
         &self,
-        state_of_key_chain: &[u8],
-        security_param_lambda: usize,
-    ) {
-        if let Some(ref map_mutex) = self.prg_map {
-            let mut map = map_mutex.lock().unwrap();
-            map.insert(security_param_lambda, state_of_key_chain.to_vec());
+        chain_id: &ChainId,
+        expected_epoch: Option<u64>,
+        state_of_key_chain: &NewState,
+    ) -> Result<(), Errors> {
+        let key_chain_type: KeyChainType = state_of_key_chain.get_algorithm().key_chain_type();
+
+        if !self.serves(key_chain_type) {
+            return Err(UninitializedStorage(format!(
+                "Storage does not serve {:?} states (chain {})",
+                key_chain_type, chain_id
+            )));
         }
-    }
 
-    fn store_state_for_xdrbg_keychain(&self, state_of_key_chain: &[u8], xof: Xof) {
-        if let Some(ref map_mutex) = self.xdrbg_map {
-            let mut map = map_mutex.lock().unwrap();
-            map.insert(xof, state_of_key_chain.to_vec());
+        // Holds every map, so no other writer gets between check and swap
+        let mut maps: Vec<(KeyChainType, MutexGuard<'_, HashMap<ChainId, NewState>>)> =
+            KeyChainType::ALL
+                .into_iter()
+                .filter_map(|map_type| {
+                    self.map_for(map_type)
+                        .map(|map_mutex| (map_type, map_mutex.lock().unwrap()))
+                })
+                .collect();
+
+        let stored_epoch: Option<u64> = maps
+            .iter()
+            .find_map(|(_, map)| map.get(chain_id))
+            .map(NewState::get_epoch);
+        check_expected_epoch(chain_id, expected_epoch, stored_epoch)?;
+
+        for (map_type, map) in maps.iter_mut() {
+            if *map_type == key_chain_type {
+                map.insert(chain_id.clone(), state_of_key_chain.clone());
+            } else {
+                map.remove(chain_id);
+            }
         }
+
+        Ok(())
     }
 
-    fn fetch_hkdf_keychain_state(&self, hash_func: HashFunc) -> Result<NewState, Errors> {
-        if let Some(ref map_mutex) = self.hkdf_map {
-            let map = map_mutex.lock().unwrap();
-            map.get(&hash_func)
-                .cloned()
-                .ok_or_else(|| NoStoredState(format!("No Hkdf state found for {:?}", hash_func)))
-        } else {
-            Err(UninitializedStorage(format!(
-                "Hkdf Keychain storage not initialized"
-            )))
-        }
+    fn fetch_state(&self, chain_id: &ChainId) -> Result<NewState, Errors> {
+        self.served_maps()
+            .find_map(|map_mutex| map_mutex.lock().unwrap().get(chain_id).cloned())
+            .ok_or_else(|| NoStoredState(format!("No state found for chain {}", chain_id)))
     }
 
-    fn fetch_prg_keychain_state(&self, security_param_lambda: usize) -> Result<NewState, Errors> {
-        if let Some(ref map_mutex) = self.prg_map {
-            let map = map_mutex.lock().unwrap();
-            map.get(&security_param_lambda).cloned().ok_or_else(|| {
-                NoStoredState(format!(
-                    "No Prg state found for lambda {}",
-                    security_param_lambda
-                ))
+    fn list_chains(&self) -> Result<Vec<ChainId>, Errors> {
+        let mut chain_ids: Vec<ChainId> = self
+            .served_maps()
+            .flat_map(|map_mutex| {
+                map_mutex
+                    .lock()
+                    .unwrap()
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<ChainId>>()
             })
-        } else {
-            Err(UninitializedStorage(format!(
-                "Prg keychain storage not initialized"
-            )))
+            .collect();
+        chain_ids.sort();
+
+        Ok(chain_ids)
+    }
+
+    fn delete_state(&self, chain_id: &ChainId) -> Result<(), Errors> {
+        for map_mutex in self.served_maps() {
+            if map_mutex.lock().unwrap().remove(chain_id).is_some() {
+                return Ok(());
+            }
+        }
+
+        Err(NoStoredState(format!(
+            "No state found for chain {}",
+            chain_id
+        )))
+    }
+}
+
+pub(crate) fn check_expected_epoch(
+    chain_id: &ChainId,
+    expected_epoch: Option<u64>,
+    stored_epoch: Option<u64>,
+) -> Result<(), Errors> {
+    match (expected_epoch, stored_epoch) {
+        (None, None) => Ok(()),
+        (Some(expected_epoch), None) => Err(StateConflict(format!(
+            "Chain {} has no stored state, expected epoch {}",
+            chain_id, expected_epoch
+        ))),
+        (Some(expected_epoch), Some(stored_epoch)) if expected_epoch == stored_epoch => Ok(()),
+        (_, Some(stored_epoch)) => Err(StateConflict(format!(
+            "Chain {} is at epoch {}, expected {:?}",
+            chain_id, stored_epoch, expected_epoch
+        ))),
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{
+        HashFunc, Xof,
+        key_chains::{
+            hkdf_keychain::HkdfKeyChain, prg_keychain::PrgKeyChain, xdrbg_keychain::XdrbgKeyChain,
+        },
+    };
+    use std::sync::Arc;
+
+    #[test]
+    fn test_chains_with_same_algorithm_do_not_collide() {
+        let storage = DefaultStorage::new(KeyChainType::HkdfKeyChain);
+
+        let tenant_a = HkdfKeyChain::new(HashFunc::Sha256, None, None, None)
+            .unwrap()
+            .with_chain_id("tenant-a");
+        let tenant_b = HkdfKeyChain::new(HashFunc::Sha256, None, None, None)
+            .unwrap()
+            .with_chain_id("tenant-b");
+
+        let state_a = tenant_a
+            .key_chain_instantiate_ref(b"seed a", None, None)
+            .unwrap();
+        let state_b = tenant_b
+            .key_chain_instantiate_ref(b"seed b", None, None)
+            .unwrap();
+
+        storage.store_state(&"tenant-a".into(), &state_a).unwrap();
+        storage.store_state(&"tenant-b".into(), &state_b).unwrap();
+
+        assert_eq!(storage.fetch_state(&"tenant-a".into()).unwrap(), state_a);
+        assert_eq!(storage.fetch_state(&"tenant-b".into()).unwrap(), state_b);
+    }
+
+    #[test]
+    fn test_list_and_delete() {
+        let storage = DefaultStorage::new(KeyChainType::HkdfKeyChain);
+        let key_chain = HkdfKeyChain::new(HashFunc::Sha512, None, None, None).unwrap();
+        let state = key_chain
+            .key_chain_instantiate_ref(b"seed", None, None)
+            .unwrap();
+
+        for tenant in ["tenant-c", "tenant-a", "tenant-b"] {
+            storage.store_state(&tenant.into(), &state).unwrap();
         }
+
+        assert_eq!(
+            storage.list_chains().unwrap(),
+            vec![
+                ChainId::from("tenant-a"),
+                ChainId::from("tenant-b"),
+                ChainId::from("tenant-c")
+            ]
+        );
+
+        storage.delete_state(&"tenant-b".into()).unwrap();
+
+        assert_eq!(storage.list_chains().unwrap().len(), 2);
+        assert!(matches!(
+            storage.fetch_state(&"tenant-b".into()),
+            Err(NoStoredState(_))
+        ));
+        assert!(matches!(
+            storage.delete_state(&"tenant-b".into()),
+            Err(NoStoredState(_))
+        ));
     }
 
-    fn fetch_xdrbg_keychain_state(&self, xof: Xof) -> Result<NewState, Errors> {
-        if let Some(ref map_mutex) = self.xdrbg_map {
-            let map = map_mutex.lock().unwrap();
-            map.get(&xof)
-                .cloned()
-                .ok_or_else(|| NoStoredState(format!("No Xdrbg state found for {:?}", xof)))
-        } else {
-            Err(UninitializedStorage(format!(
-                "Xdrbg keychain storage not initialized"
-            )))
+    #[test]
+    fn test_one_storage_for_mixed_chains() {
+        let storage = Arc::new(DefaultStorage::new_all());
+
+        let hkdf_kc =
+            HkdfKeyChain::new(HashFunc::Sha256, None, Some(true), Some(storage.clone())).unwrap();
+        let prg_kc = PrgKeyChain::new(16, Some(true), Some(storage.clone()))
+            .unwrap()
+            .with_chain_id("prg-a");
+        let prg_kc_b = PrgKeyChain::new(16, Some(true), Some(storage.clone()))
+            .unwrap()
+            .with_chain_id("prg-b");
+        let xdrbg_kc =
+            XdrbgKeyChain::new(Xof::Shake128, None, Some(true), Some(storage.clone())).unwrap();
+
+        let hkdf_state = hkdf_kc
+            .key_chain_instantiate_ref(b"seed", None, None)
+            .unwrap();
+        hkdf_kc
+            .key_chain_update_ref(b"input", &hkdf_state, None, None)
+            .unwrap();
+        for key_chain in [&prg_kc, &prg_kc_b] {
+            let prg_state = key_chain.key_chain_instantiate(&[0x11; 16]).unwrap();
+            key_chain.key_chain_update(&[0x22; 16], &prg_state).unwrap();
         }
+        let xdrbg_state = xdrbg_kc
+            .key_chain_instantiate_ref(&[0x33; 24], None)
+            .unwrap();
+        xdrbg_kc
+            .key_chain_update_ref(&[0x44; 16], &xdrbg_state, None, None)
+            .unwrap();
+
+        assert_eq!(storage.state_count(KeyChainType::HkdfKeyChain), 1);
+        assert_eq!(storage.state_count(KeyChainType::PrgKeyChain), 2);
+        assert_eq!(storage.state_count(KeyChainType::XdrbgKeyChain), 1);
+        assert_eq!(storage.list_chains().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_store_to_unserved_type_fails() {
+        let storage = DefaultStorage::new(KeyChainType::PrgKeyChain);
+        let key_chain = HkdfKeyChain::new(HashFunc::Sha256, None, None, None).unwrap();
+        let state = key_chain
+            .key_chain_instantiate_ref(b"seed", None, None)
+            .unwrap();
+
+        assert!(!storage.serves(KeyChainType::HkdfKeyChain));
+        assert!(matches!(
+            storage.store_state(&state.storage_chain_id(), &state),
+            Err(UninitializedStorage(_))
+        ));
+        assert_eq!(storage.state_count(KeyChainType::HkdfKeyChain), 0);
+    }
+
+    #[test]
+    fn test_compare_and_swap_checks_epoch() {
+        let storage = DefaultStorage::new_all();
+        let key_chain = XdrbgKeyChain::new(Xof::Shake128, None, None, None)
+            .unwrap()
+            .with_chain_id("tenant-a");
+        let initial_state = key_chain
+            .key_chain_instantiate_ref(&[0x11; 24], None)
+            .unwrap();
+        let (state_1, _) = key_chain
+            .key_chain_update_ref(&[0x22; 16], &initial_state, None, None)
+            .unwrap();
+        let chain_id = ChainId::from("tenant-a");
+
+        storage
+            .compare_and_swap(&chain_id, None, &initial_state)
+            .unwrap();
+        assert!(matches!(
+            storage.compare_and_swap(&chain_id, None, &initial_state),
+            Err(StateConflict(_))
+        ));
+
+        storage
+            .compare_and_swap(&chain_id, Some(0), &state_1)
+            .unwrap();
+        assert!(matches!(
+            storage.compare_and_swap(&chain_id, Some(0), &state_1),
+            Err(StateConflict(_))
+        ));
+        assert_eq!(storage.fetch_state(&chain_id).unwrap(), state_1);
+    }
+
+    #[test]
+    fn test_compare_and_swap_on_empty_slot_expects_no_epoch() {
+        let storage = DefaultStorage::new_all();
+        let key_chain = XdrbgKeyChain::new(Xof::Shake128, None, None, None)
+            .unwrap()
+            .with_chain_id("tenant-a");
+        let initial_state = key_chain
+            .key_chain_instantiate_ref(&[0x11; 24], None)
+            .unwrap();
+        let (state_1, _) = key_chain
+            .key_chain_update_ref(&[0x22; 16], &initial_state, None, None)
+            .unwrap();
+        let chain_id = ChainId::from("tenant-a");
+
+        assert!(matches!(
+            storage.compare_and_swap(&chain_id, Some(0), &state_1),
+            Err(StateConflict(_))
+        ));
+        assert!(matches!(
+            storage.fetch_state(&chain_id),
+            Err(NoStoredState(_))
+        ));
     }
 }
@@ -1,27 +1,53 @@
-use super::{InitialState, NewState, RandomOutput, storage_handler::Storage};
+use super::{
+    ChainId, FixedOutput, FixedState, HighWaterMark, InitialState, KeyChainAlgorithm, NewState,
+    RandomOutput,
+    keychain_session::KeyChainSession,
+    keychain_state::check_update_buffers,
+    keychain_trait::{XdrbgInstantiateParams, XdrbgUpdateParams},
+    keychain_transaction::PendingUpdate,
+    storage_handler::Storage,
+};
 use crate::{
-    crypto_primitives::xdrbg_ops::{Xdrbg, Xof},
-    errors::Errors::{self, UninitializedStorage},
+    crypto_primitives::xdrbg_ops::{MAX_LEN_ALPHA, MAX_STATE_SIZE, TypedXdrbg, XdrbgXof, Xof},
+    errors::Errors::{self, InvalidConfiguration, UninitializedStorage},
+    secret_bytes::SecretBytes,
 };
-use std::sync::Arc;
+use alloc::{format, string::ToString, sync::Arc, vec::Vec};
+use ascon_hash::AsconXof128;
+use sha3::{Shake128, Shake256};
+use zeroize::{Zeroize, Zeroizing};
+
+// Dispatches once on the XOF to the monomorphized chain inside.
+macro_rules! with_typed_chain {
+    ($core:expr, $chain:ident => $body:expr) => {
+        match $core {
+            XdrbgCore::Shake128($chain) => $body,
+            XdrbgCore::Shake256($chain) => $body,
+            XdrbgCore::Ascon($chain) => $body,
+        }
+    };
+}
 
 #[derive(Clone)]
 pub struct XdrbgKeyChain {
-    xdrbg_obj: Xdrbg,
-    output_key_length: usize,
+    core: XdrbgCore,
     store_persistently: bool,
     storage: Option<Arc<dyn Storage>>,
+    chain_id: Option<ChainId>,
+    high_water_mark: HighWaterMark,
 }
 
 impl XdrbgKeyChain {
+    pub fn builder(chosen_xof: Xof) -> XdrbgKeyChainBuilder {
+        XdrbgKeyChainBuilder::new(chosen_xof)
+    }
+
     pub fn new(
         chosen_xof: Xof,
         output_key_length: Option<usize>,
         store_persistently: Option<bool>,
         storage: Option<Arc<dyn Storage>>,
     ) -> Result<Self, Errors> {
-        let xdrbg_obj: Xdrbg = Xdrbg::new(chosen_xof);
-
         let store_persistently: bool = store_persistently.unwrap_or(false);
 
         let storage_choice: Option<Arc<dyn Storage>> = if store_persistently {
@@ -37,69 +63,721 @@ impl XdrbgKeyChain {
         };
 
         Ok(Self {
-            xdrbg_obj,
-            output_key_length: output_key_length.unwrap_or(chosen_xof.state_size()),
+            core: XdrbgCore::new(chosen_xof, output_key_length),
             store_persistently: store_persistently,
             storage: storage_choice,
+            chain_id: None,
+            high_water_mark: HighWaterMark::default(),
         })
     }
 
+    // A different chain, so it does not share the high water mark of this one.
+    pub fn with_chain_id(mut self, chain_id: impl Into<ChainId>) -> Self {
+        self.chain_id = Some(chain_id.into());
+        self.high_water_mark = HighWaterMark::default();
+        self
+    }
+
+    pub fn with_high_water_mark(mut self, epoch: u64) -> Self {
+        self.high_water_mark = HighWaterMark::new(epoch);
+        self
+    }
+
+    // Continues the chain stored under chain_id in the given storage, which
+    // this chain then stores persistently to. The configuration, including the
+    // builder defaults, is that of this chain, so the stored state must have
+    // been produced by the same algorithm. Fails with NoStoredState if nothing
+    // is stored there, and with RollbackDetected if the stored state is below
+    // the high water mark, e.g. one set with with_high_water_mark() from a
+    // trusted source.
+    pub fn resume(
+        self,
+        storage: Arc<dyn Storage>,
+        chain_id: impl Into<ChainId>,
+    ) -> Result<KeyChainSession<Self>, Errors> {
+        let chain_id: ChainId = chain_id.into();
+        let mut key_chain: Self = if self.chain_id.as_ref() == Some(&chain_id) {
+            self
+        } else {
+            self.with_chain_id(chain_id)
+        };
+        key_chain.store_persistently = true;
+        key_chain.storage = Some(storage);
+
+        KeyChainSession::from_storage(key_chain)
+    }
+
+    #[deprecated(note = "Use instantiate_or_resume_ref, which borrows alpha.")]
+    pub fn instantiate_or_resume(
+        self,
+        seed: &[u8],
+        alpha: Option<Vec<u8>>,
+    ) -> Result<KeyChainSession<Self>, Errors> {
+        self.instantiate_or_resume_ref(seed, alpha.as_deref())
+    }
+
+    // Resumes the chain from its storage, or instantiates it there on first
+    // boot. Requires the chain to store persistently.
+    pub fn instantiate_or_resume_ref(
+        self,
+        seed: &[u8],
+        alpha: Option<&[u8]>,
+    ) -> Result<KeyChainSession<Self>, Errors> {
+        if self.persistent_storage().is_none() {
+            return Err(UninitializedStorage(
+                "Xdrbg keychain storage not initialized.".to_string(),
+            ));
+        }
+
+        KeyChainSession::instantiate_or_resume(
+            self,
+            seed,
+            XdrbgInstantiateParams {
+                alpha: alpha.map(<[u8]>::to_vec),
+            },
+        )
+    }
+
+    #[deprecated(note = "Use key_chain_instantiate_ref, which borrows alpha.")]
     pub fn key_chain_instantiate(
         &self,
         seed: &[u8],
         alpha: Option<Vec<u8>>,
     ) -> Result<InitialState, Errors> {
-        let initial_state: Vec<u8> = match self.xdrbg_obj.xdrbg_instantiate(seed, alpha) {
-            Ok(xdrbg_init_state) => xdrbg_init_state,
-            Err(err) => return Err(err),
-        };
+        self.key_chain_instantiate_ref(seed, alpha.as_deref())
+    }
+
+    // A persistent chain stores its initial state right away, and only into an
+    // empty slot, so instantiating twice cannot fork a stored chain.
+    pub fn key_chain_instantiate_ref(
+        &self,
+        seed: &[u8],
+        alpha: Option<&[u8]>,
+    ) -> Result<InitialState, Errors> {
+        let initial_state: InitialState = self.new_initial_state(seed, alpha)?;
+
+        if let Some(storage) = self.persistent_storage() {
+            storage.compare_and_swap(&initial_state.storage_chain_id(), None, &initial_state)?;
+        }
 
         Ok(initial_state)
     }
 
+    fn new_initial_state(&self, seed: &[u8], alpha: Option<&[u8]>) -> Result<InitialState, Errors> {
+        let initial_state: SecretBytes =
+            with_typed_chain!(&self.core, chain => chain.key_chain_instantiate(seed, alpha))?;
+
+        InitialState::new(
+            self.get_algorithm(),
+            self.chain_id.clone(),
+            0,
+            initial_state,
+        )
+    }
+
+    #[deprecated(note = "Use key_chain_update_ref, which borrows the alphas.")]
     pub fn key_chain_update(
         &self,
         arbitrary_input_param: &[u8],
-        keychain_state: &[u8],
+        keychain_state: &NewState,
         alpha_reseed: Option<Vec<u8>>,
         alpha_generate: Option<Vec<u8>>,
     ) -> Result<(NewState, RandomOutput), Errors> {
-        let reseeded_xdrbg_state: Vec<u8> =
-            match self
-                .xdrbg_obj
-                .xdrbg_reseed(keychain_state, arbitrary_input_param, alpha_reseed)
-            {
-                Ok(reseeded_state) => reseeded_state,
-                Err(err) => return Err(err),
-            };
+        self.key_chain_update_ref(
+            arbitrary_input_param,
+            keychain_state,
+            alpha_reseed.as_deref(),
+            alpha_generate.as_deref(),
+        )
+    }
 
-        let (new_state_of_key_chain, random_output) = match self.xdrbg_obj.xdrbg_generate(
-            &reseeded_xdrbg_state,
-            self.output_key_length,
+    pub fn key_chain_update_ref(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        alpha_reseed: Option<&[u8]>,
+        alpha_generate: Option<&[u8]>,
+    ) -> Result<(NewState, RandomOutput), Errors> {
+        self.begin_key_chain_update_ref(
+            arbitrary_input_param,
+            keychain_state,
+            alpha_reseed,
             alpha_generate,
-        ) {
-            Ok(total_output) => total_output,
-            Err(err) => return Err(err),
-        };
+        )?
+        .commit()
+    }
 
+    #[deprecated(note = "Use begin_key_chain_update_ref, which borrows the alphas.")]
+    pub fn begin_key_chain_update(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        alpha_reseed: Option<Vec<u8>>,
+        alpha_generate: Option<Vec<u8>>,
+    ) -> Result<PendingUpdate<'_>, Errors> {
+        self.begin_key_chain_update_ref(
+            arbitrary_input_param,
+            keychain_state,
+            alpha_reseed.as_deref(),
+            alpha_generate.as_deref(),
+        )
+    }
+
+    pub fn begin_key_chain_update_ref(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        alpha_reseed: Option<&[u8]>,
+        alpha_generate: Option<&[u8]>,
+    ) -> Result<PendingUpdate<'_>, Errors> {
+        keychain_state.check_belongs_to(self.get_algorithm(), self.chain_id.as_ref())?;
+
+        let (new_state_of_key_chain, random_output) = with_typed_chain!(&self.core, chain => {
+            chain.key_chain_update(
+                arbitrary_input_param,
+                keychain_state.as_bytes(),
+                alpha_reseed,
+                alpha_generate,
+            )
+        })?;
+
+        Ok(PendingUpdate::new(
+            self.persistent_storage(),
+            &self.high_water_mark,
+            keychain_state.advance(new_state_of_key_chain)?,
+            random_output,
+        ))
+    }
+
+    // Allocation free update on raw state bytes for chains that do not store
+    // persistently. The buffers must be get_state_length() and
+    // get_output_key_length() bytes long. Epochs and rollback checks are left
+    // to the caller.
+    pub fn key_chain_update_into(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8],
+        alpha_reseed: Option<&[u8]>,
+        alpha_generate: Option<&[u8]>,
+        new_state: &mut [u8],
+        output_key: &mut [u8],
+    ) -> Result<(), Errors> {
         if self.store_persistently {
-            if let Some(storage) = &self.storage {
-                storage.store_state_for_xdrbg_keychain(
-                    &new_state_of_key_chain,
-                    self.xdrbg_obj.get_chosen_xof(),
-                );
+            return Err(InvalidConfiguration(
+                "Xdrbg keychain stores persistently, use key_chain_update_ref.".to_string(),
+            ));
+        }
+
+        with_typed_chain!(&self.core, chain => {
+            chain.key_chain_update_into(
+                arbitrary_input_param,
+                current_state,
+                alpha_reseed,
+                alpha_generate,
+                new_state,
+                output_key,
+            )
+        })
+    }
+
+    // key_chain_update_into() for state and key lengths known at compile time.
+    pub fn key_chain_update_array<const S: usize, const K: usize>(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8; S],
+        alpha_reseed: Option<&[u8]>,
+        alpha_generate: Option<&[u8]>,
+    ) -> Result<(FixedState<S>, FixedOutput<K>), Errors> {
+        let mut new_state: FixedState<S> = Zeroizing::new([0u8; S]);
+        let mut output_key: FixedOutput<K> = Zeroizing::new([0u8; K]);
+        self.key_chain_update_into(
+            arbitrary_input_param,
+            current_state,
+            alpha_reseed,
+            alpha_generate,
+            new_state.as_mut_slice(),
+            output_key.as_mut_slice(),
+        )?;
+
+        Ok((new_state, output_key))
+    }
+
+    #[cfg(feature = "async")]
+    #[deprecated(note = "Use key_chain_instantiate_async_ref, which borrows alpha.")]
+    pub async fn key_chain_instantiate_async(
+        &self,
+        seed: &[u8],
+        alpha: Option<Vec<u8>>,
+        storage: &dyn super::async_storage::AsyncStorage,
+    ) -> Result<InitialState, Errors> {
+        self.key_chain_instantiate_async_ref(seed, alpha.as_deref(), storage)
+            .await
+    }
+
+    // The async variants persist to the given storage in place of the one
+    // passed to new(). The initial state is persisted as well, only into an
+    // empty slot like key_chain_instantiate.
+    #[cfg(feature = "async")]
+    pub async fn key_chain_instantiate_async_ref(
+        &self,
+        seed: &[u8],
+        alpha: Option<&[u8]>,
+        storage: &dyn super::async_storage::AsyncStorage,
+    ) -> Result<InitialState, Errors> {
+        let initial_state: InitialState = self.new_initial_state(seed, alpha)?;
+        storage
+            .compare_and_swap(&initial_state.storage_chain_id(), None, &initial_state)
+            .await?;
+
+        Ok(initial_state)
+    }
+
+    #[cfg(feature = "async")]
+    #[deprecated(note = "Use key_chain_update_async_ref, which borrows the alphas.")]
+    pub async fn key_chain_update_async(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        alpha_reseed: Option<Vec<u8>>,
+        alpha_generate: Option<Vec<u8>>,
+        storage: &dyn super::async_storage::AsyncStorage,
+    ) -> Result<(NewState, RandomOutput), Errors> {
+        self.key_chain_update_async_ref(
+            arbitrary_input_param,
+            keychain_state,
+            alpha_reseed.as_deref(),
+            alpha_generate.as_deref(),
+            storage,
+        )
+        .await
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn key_chain_update_async_ref(
+        &self,
+        arbitrary_input_param: &[u8],
+        keychain_state: &NewState,
+        alpha_reseed: Option<&[u8]>,
+        alpha_generate: Option<&[u8]>,
+        storage: &dyn super::async_storage::AsyncStorage,
+    ) -> Result<(NewState, RandomOutput), Errors> {
+        self.begin_key_chain_update_ref(
+            arbitrary_input_param,
+            keychain_state,
+            alpha_reseed,
+            alpha_generate,
+        )?
+        .commit_async(storage)
+        .await
+    }
+
+    // One generate step without reseeding, persisted on commit like an update.
+    pub(crate) fn begin_key_chain_generate(
+        &self,
+        keychain_state: &NewState,
+        output_key_length: usize,
+    ) -> Result<PendingUpdate<'_>, Errors> {
+        keychain_state.check_belongs_to(self.get_algorithm(), self.chain_id.as_ref())?;
+
+        let (new_state_of_key_chain, random_output) = with_typed_chain!(&self.core, chain => {
+            chain.generate(keychain_state.as_bytes(), output_key_length)
+        })?;
+
+        Ok(PendingUpdate::new(
+            self.persistent_storage(),
+            &self.high_water_mark,
+            keychain_state.advance(new_state_of_key_chain)?,
+            random_output,
+        ))
+    }
+
+    pub(crate) fn persistent_storage(&self) -> Option<&dyn Storage> {
+        if self.store_persistently {
+            self.storage.as_deref()
+        } else {
+            None
+        }
+    }
+
+    pub fn get_algorithm(&self) -> KeyChainAlgorithm {
+        KeyChainAlgorithm::Xdrbg {
+            xof: self.get_chosen_xof(),
+            output_key_length: self.get_output_key_length(),
+        }
+    }
+
+    pub fn get_chain_id(&self) -> Option<&ChainId> {
+        self.chain_id.as_ref()
+    }
+
+    pub fn get_high_water_mark(&self) -> &HighWaterMark {
+        &self.high_water_mark
+    }
+
+    pub fn get_chosen_xof(&self) -> Xof {
+        match self.core {
+            XdrbgCore::Shake128(_) => Xof::Shake128,
+            XdrbgCore::Shake256(_) => Xof::Shake256,
+            XdrbgCore::Ascon(_) => Xof::Ascon,
+        }
+    }
+
+    pub fn get_output_key_length(&self) -> usize {
+        with_typed_chain!(&self.core, chain => chain.get_output_key_length())
+    }
+
+    pub fn get_state_length(&self) -> usize {
+        with_typed_chain!(&self.core, chain => chain.get_state_length())
+    }
+
+    pub fn get_default_instantiate_params(&self) -> &XdrbgInstantiateParams {
+        with_typed_chain!(&self.core, chain => chain.get_default_instantiate_params())
+    }
+
+    pub fn get_default_update_params(&self) -> &XdrbgUpdateParams {
+        with_typed_chain!(&self.core, chain => chain.get_default_update_params())
+    }
+}
+
+// Typed alternative to new(). Setting a storage makes the chain store its
+// states persistently. The personalization string is the alpha of
+// instantiate, and the alphas are used whenever a call passes None for them.
+#[derive(Clone)]
+pub struct XdrbgKeyChainBuilder {
+    chosen_xof: Xof,
+    output_key_length: Option<usize>,
+    default_instantiate_params: XdrbgInstantiateParams,
+    default_update_params: XdrbgUpdateParams,
+    storage: Option<Arc<dyn Storage>>,
+    chain_id: Option<ChainId>,
+    high_water_mark: Option<u64>,
+}
+
+impl XdrbgKeyChainBuilder {
+    pub fn new(chosen_xof: Xof) -> Self {
+        Self {
+            chosen_xof,
+            output_key_length: None,
+            default_instantiate_params: XdrbgInstantiateParams::default(),
+            default_update_params: XdrbgUpdateParams::default(),
+            storage: None,
+            chain_id: None,
+            high_water_mark: None,
+        }
+    }
+
+    pub fn with_output_key_length(mut self, output_key_length: usize) -> Self {
+        self.output_key_length = Some(output_key_length);
+        self
+    }
+
+    pub fn with_personalization(mut self, personalization: impl Into<Vec<u8>>) -> Self {
+        self.default_instantiate_params.alpha = Some(personalization.into());
+        self
+    }
+
+    pub fn with_alpha_reseed(mut self, alpha_reseed: impl Into<Vec<u8>>) -> Self {
+        self.default_update_params.alpha_reseed = Some(alpha_reseed.into());
+        self
+    }
+
+    pub fn with_alpha_generate(mut self, alpha_generate: impl Into<Vec<u8>>) -> Self {
+        self.default_update_params.alpha_generate = Some(alpha_generate.into());
+        self
+    }
+
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn with_chain_id(mut self, chain_id: impl Into<ChainId>) -> Self {
+        self.chain_id = Some(chain_id.into());
+        self
+    }
+
+    pub fn with_high_water_mark(mut self, epoch: u64) -> Self {
+        self.high_water_mark = Some(epoch);
+        self
+    }
+
+    pub fn build(self) -> Result<XdrbgKeyChain, Errors> {
+        let output_key_length: usize = self
+            .output_key_length
+            .unwrap_or(self.chosen_xof.state_size());
+
+        KeyChainAlgorithm::Xdrbg {
+            xof: self.chosen_xof,
+            output_key_length,
+        }
+        .check_parameters()?;
+
+        let alphas = [
+            ("Personalization", &self.default_instantiate_params.alpha),
+            ("Alpha for reseed", &self.default_update_params.alpha_reseed),
+            (
+                "Alpha for generate",
+                &self.default_update_params.alpha_generate,
+            ),
+        ];
+        for (name, alpha) in alphas {
+            if let Some(alpha) = alpha
+                && alpha.len() > MAX_LEN_ALPHA
+            {
+                return Err(InvalidConfiguration(format!(
+                    "{} of {} bytes. Maximum length is {} bytes.",
+                    name,
+                    alpha.len(),
+                    MAX_LEN_ALPHA
+                )));
             }
         }
 
-        Ok((new_state_of_key_chain, random_output))
+        if self.chain_id.as_ref().is_some_and(ChainId::is_empty) {
+            return Err(InvalidConfiguration(
+                "Xdrbg keychain chain ID must not be empty.".to_string(),
+            ));
+        }
+
+        let mut key_chain: XdrbgKeyChain = XdrbgKeyChain::new(
+            self.chosen_xof,
+            Some(output_key_length),
+            Some(self.storage.is_some()),
+            self.storage,
+        )?;
+        key_chain.chain_id = self.chain_id;
+        key_chain.high_water_mark = HighWaterMark::new(self.high_water_mark.unwrap_or(0));
+        with_typed_chain!(&mut key_chain.core, chain => {
+            chain.default_instantiate_params = self.default_instantiate_params;
+            chain.default_update_params = self.default_update_params;
+        });
+
+        Ok(key_chain)
+    }
+}
+
+// XDRBG key chain monomorphized over the XOF X, which may be any XOF with an
+// XdrbgXof impl. It works on raw state bytes: storage, epochs and rollback
+// checks stay with XdrbgKeyChain, which dispatches to it for the XOFs of Xof.
+pub struct TypedXdrbgKeyChain<X> {
+    xdrbg_obj: TypedXdrbg<X>,
+    output_key_length: usize,
+    default_instantiate_params: XdrbgInstantiateParams,
+    default_update_params: XdrbgUpdateParams,
+}
+
+impl<X> Clone for TypedXdrbgKeyChain<X> {
+    fn clone(&self) -> Self {
+        Self {
+            xdrbg_obj: self.xdrbg_obj,
+            output_key_length: self.output_key_length,
+            default_instantiate_params: self.default_instantiate_params.clone(),
+            default_update_params: self.default_update_params.clone(),
+        }
+    }
+}
+
+impl<X: XdrbgXof> TypedXdrbgKeyChain<X> {
+    // The output key length defaults to the state size.
+    pub fn new(output_key_length: Option<usize>) -> Self {
+        // The reseeded state is kept in a MAX_STATE_SIZE buffer on the stack
+        const { assert!(X::STATE_SIZE <= MAX_STATE_SIZE) };
+
+        Self {
+            xdrbg_obj: TypedXdrbg::new(),
+            output_key_length: output_key_length.unwrap_or(X::STATE_SIZE),
+            default_instantiate_params: XdrbgInstantiateParams::default(),
+            default_update_params: XdrbgUpdateParams::default(),
+        }
+    }
+
+    pub fn with_personalization(mut self, personalization: impl Into<Vec<u8>>) -> Self {
+        self.default_instantiate_params.alpha = Some(personalization.into());
+        self
+    }
+
+    pub fn with_alpha_reseed(mut self, alpha_reseed: impl Into<Vec<u8>>) -> Self {
+        self.default_update_params.alpha_reseed = Some(alpha_reseed.into());
+        self
+    }
+
+    pub fn with_alpha_generate(mut self, alpha_generate: impl Into<Vec<u8>>) -> Self {
+        self.default_update_params.alpha_generate = Some(alpha_generate.into());
+        self
+    }
+
+    pub fn key_chain_instantiate(
+        &self,
+        seed: &[u8],
+        alpha: Option<&[u8]>,
+    ) -> Result<SecretBytes, Errors> {
+        let alpha: Option<&[u8]> = alpha.or(self.default_instantiate_params.alpha.as_deref());
+
+        self.xdrbg_obj.xdrbg_instantiate_ref(seed, alpha)
+    }
+
+    pub fn key_chain_update(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8],
+        alpha_reseed: Option<&[u8]>,
+        alpha_generate: Option<&[u8]>,
+    ) -> Result<(SecretBytes, RandomOutput), Errors> {
+        let mut new_state: SecretBytes = SecretBytes::zeroed(X::STATE_SIZE);
+        let mut random_output: RandomOutput = RandomOutput::zeroed(self.output_key_length);
+        self.derive_update_into(
+            arbitrary_input_param,
+            current_state,
+            alpha_reseed,
+            alpha_generate,
+            &mut new_state,
+            &mut random_output,
+        )?;
+
+        Ok((new_state, random_output))
+    }
+
+    // Allocation free update. The buffers must be get_state_length() and
+    // get_output_key_length() bytes long.
+    pub fn key_chain_update_into(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8],
+        alpha_reseed: Option<&[u8]>,
+        alpha_generate: Option<&[u8]>,
+        new_state: &mut [u8],
+        output_key: &mut [u8],
+    ) -> Result<(), Errors> {
+        check_update_buffers(
+            X::STATE_SIZE,
+            self.output_key_length,
+            current_state,
+            new_state,
+            output_key,
+        )?;
+
+        self.derive_update_into(
+            arbitrary_input_param,
+            current_state,
+            alpha_reseed,
+            alpha_generate,
+            new_state,
+            output_key,
+        )
+    }
+
+    // key_chain_update_into() for state and key lengths known at compile time.
+    pub fn key_chain_update_array<const S: usize, const K: usize>(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8; S],
+        alpha_reseed: Option<&[u8]>,
+        alpha_generate: Option<&[u8]>,
+    ) -> Result<(FixedState<S>, FixedOutput<K>), Errors> {
+        let mut new_state: FixedState<S> = Zeroizing::new([0u8; S]);
+        let mut output_key: FixedOutput<K> = Zeroizing::new([0u8; K]);
+        self.key_chain_update_into(
+            arbitrary_input_param,
+            current_state,
+            alpha_reseed,
+            alpha_generate,
+            new_state.as_mut_slice(),
+            output_key.as_mut_slice(),
+        )?;
+
+        Ok((new_state, output_key))
+    }
+
+    // Plain generate without reseeding or alpha, as used by KeyChainRng.
+    pub(crate) fn generate(
+        &self,
+        current_state: &[u8],
+        output_key_length: usize,
+    ) -> Result<(SecretBytes, RandomOutput), Errors> {
+        self.xdrbg_obj
+            .xdrbg_generate_ref(current_state, output_key_length, None)
+    }
+
+    // Reseeds with the input and generates straight into the new state and the
+    // output key. The reseeded state lives on the stack.
+    fn derive_update_into(
+        &self,
+        arbitrary_input_param: &[u8],
+        current_state: &[u8],
+        alpha_reseed: Option<&[u8]>,
+        alpha_generate: Option<&[u8]>,
+        new_state: &mut [u8],
+        output_key: &mut [u8],
+    ) -> Result<(), Errors> {
+        let alpha_reseed: Option<&[u8]> =
+            alpha_reseed.or(self.default_update_params.alpha_reseed.as_deref());
+        let alpha_generate: Option<&[u8]> =
+            alpha_generate.or(self.default_update_params.alpha_generate.as_deref());
+
+        let mut reseeded_buffer: [u8; MAX_STATE_SIZE] = [0u8; MAX_STATE_SIZE];
+        let reseeded_xdrbg_state: &mut [u8] = &mut reseeded_buffer[..X::STATE_SIZE];
+
+        let result: Result<(), Errors> = self
+            .xdrbg_obj
+            .xdrbg_reseed_into(
+                current_state,
+                arbitrary_input_param,
+                alpha_reseed,
+                reseeded_xdrbg_state,
+            )
+            .and_then(|_| {
+                self.xdrbg_obj.xdrbg_generate_into(
+                    reseeded_xdrbg_state,
+                    alpha_generate,
+                    new_state,
+                    output_key,
+                )
+            });
+        reseeded_buffer.zeroize();
+
+        result
+    }
+
+    pub fn get_output_key_length(&self) -> usize {
+        self.output_key_length
+    }
+
+    pub fn get_state_length(&self) -> usize {
+        X::STATE_SIZE
+    }
+
+    pub fn get_default_instantiate_params(&self) -> &XdrbgInstantiateParams {
+        &self.default_instantiate_params
+    }
+
+    pub fn get_default_update_params(&self) -> &XdrbgUpdateParams {
+        &self.default_update_params
     }
 }
 
-#[cfg(test)]
+#[derive(Clone)]
+enum XdrbgCore {
+    Shake128(TypedXdrbgKeyChain<Shake128>),
+    Shake256(TypedXdrbgKeyChain<Shake256>),
+    Ascon(TypedXdrbgKeyChain<AsconXof128>),
+}
+
+impl XdrbgCore {
+    fn new(chosen_xof: Xof, output_key_length: Option<usize>) -> Self {
+        match chosen_xof {
+            Xof::Shake128 => Self::Shake128(TypedXdrbgKeyChain::new(output_key_length)),
+            Xof::Shake256 => Self::Shake256(TypedXdrbgKeyChain::new(output_key_length)),
+            Xof::Ascon => Self::Ascon(TypedXdrbgKeyChain::new(output_key_length)),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
     use crate::crypto_primitives::xdrbg_ops::Xof;
     use crate::errors::Errors;
+    use crate::key_chains::keychain_trait::KeyChainInfo;
     use crate::key_chains::storage_handler::{DefaultStorage, KeyChainType, Storage};
     use std::sync::Arc;
 
@@ -117,7 +795,7 @@ mod tests {
         let xof = Xof::Shake128;
         let keychain = XdrbgKeyChain::new(xof, None, Some(false), None).unwrap();
         let initial_state = keychain
-            .key_chain_instantiate(&sample_seed(xof), None)
+            .key_chain_instantiate_ref(&sample_seed(xof), None)
             .unwrap();
         assert_eq!(initial_state.len(), xof.state_size());
     }
@@ -141,18 +819,18 @@ mod tests {
         let keychain = XdrbgKeyChain::new(xof, None, Some(true), Some(storage.clone())).unwrap();
 
         let initial_state = keychain
-            .key_chain_instantiate(&sample_seed(xof), None)
+            .key_chain_instantiate_ref(&sample_seed(xof), None)
             .unwrap();
 
         let (new_state, random_output) = keychain
-            .key_chain_update(&sample_seed(xof), &initial_state, None, None)
+            .key_chain_update_ref(&sample_seed(xof), &initial_state, None, None)
             .unwrap();
 
         assert_eq!(new_state.len(), xof.state_size());
         assert_eq!(random_output.len(), xof.state_size());
 
         // Check that state is stored in storage
-        let fetched = storage.fetch_xdrbg_keychain_state(xof).unwrap();
+        let fetched = storage.fetch_state(&keychain.storage_chain_id()).unwrap();
         assert_eq!(fetched, new_state);
     }
 
@@ -161,11 +839,11 @@ mod tests {
         let xof = Xof::Shake128;
         let keychain = XdrbgKeyChain::new(xof, None, Some(false), None).unwrap();
         let initial_state = keychain
-            .key_chain_instantiate(&sample_seed(xof), None)
+            .key_chain_instantiate_ref(&sample_seed(xof), None)
             .unwrap();
 
         let (new_state, random_output) = keychain
-            .key_chain_update(&sample_seed(xof), &initial_state, None, None)
+            .key_chain_update_ref(&sample_seed(xof), &initial_state, None, None)
             .unwrap();
 
         assert_eq!(new_state.len(), xof.state_size());
@@ -175,12 +853,239 @@ mod tests {
     #[test]
     fn test_storage_fetch_error() {
         let storage = Arc::new(DefaultStorage::new(KeyChainType::XdrbgKeyChain));
-        let err = storage
-            .fetch_xdrbg_keychain_state(Xof::Shake256)
-            .unwrap_err();
+        let err = storage.fetch_state(&"xdrbg/Shake256".into()).unwrap_err();
         match err {
-            Errors::NoStoredState(msg) => assert!(msg.contains("No Xdrbg state found")),
+            Errors::NoStoredState(msg) => assert!(msg.contains("No state found for chain")),
             _ => panic!("Expected NoStoredState error"),
         }
     }
+
+    #[test]
+    fn test_update_rejects_state_of_other_chain_id() {
+        let xof = Xof::Shake256;
+        let keychain_a = XdrbgKeyChain::new(xof, None, None, None)
+            .unwrap()
+            .with_chain_id("tenant-a");
+        let keychain_b = XdrbgKeyChain::new(xof, None, None, None)
+            .unwrap()
+            .with_chain_id("tenant-b");
+
+        let state_a = keychain_a
+            .key_chain_instantiate_ref(&sample_seed(xof), None)
+            .unwrap();
+        let result = keychain_b.key_chain_update_ref(&sample_seed(xof), &state_a, None, None);
+
+        assert!(matches!(result, Err(Errors::StateMismatch(_))));
+
+        let (new_state_a, _) = keychain_a
+            .key_chain_update_ref(&sample_seed(xof), &state_a, None, None)
+            .unwrap();
+        assert_eq!(new_state_a.get_epoch(), 1);
+    }
+
+    #[test]
+    fn test_instantiate_or_resume_requires_storage() {
+        let keychain = XdrbgKeyChain::new(Xof::Shake256, None, None, None).unwrap();
+
+        let result = keychain.instantiate_or_resume_ref(&sample_seed(Xof::Shake256), None);
+
+        assert!(matches!(result, Err(Errors::UninitializedStorage(_))));
+    }
+
+    #[test]
+    fn test_update_from_older_epoch_is_rejected() {
+        let storage: Arc<dyn Storage> = Arc::new(DefaultStorage::new_all());
+        let keychain =
+            XdrbgKeyChain::new(Xof::Shake128, None, Some(true), Some(storage.clone())).unwrap();
+        let initial_state = keychain
+            .key_chain_instantiate_ref(&sample_seed(Xof::Shake128), None)
+            .unwrap();
+
+        let (state_1, _) = keychain
+            .key_chain_update_ref(&[0x22; 16], &initial_state, None, None)
+            .unwrap();
+        let replay = keychain.key_chain_update_ref(&[0x22; 16], &initial_state, None, None);
+
+        assert!(matches!(replay, Err(Errors::RollbackDetected(_))));
+        assert_eq!(keychain.get_high_water_mark().get(), 1);
+
+        // An aborted update leaves the mark where it was
+        keychain
+            .begin_key_chain_update_ref(&[0x33; 16], &state_1, None, None)
+            .unwrap()
+            .abort();
+        assert_eq!(keychain.get_high_water_mark().get(), 1);
+
+        let (state_2, _) = keychain
+            .key_chain_update_ref(&[0x33; 16], &state_1, None, None)
+            .unwrap();
+        let restarted_keychain = XdrbgKeyChain::new(Xof::Shake128, None, Some(true), Some(storage))
+            .unwrap()
+            .with_high_water_mark(3);
+        assert!(matches!(
+            restarted_keychain.key_chain_update_ref(&[0x44; 16], &state_2, None, None),
+            Err(Errors::RollbackDetected(_))
+        ));
+    }
+
+    #[test]
+    fn test_builder_applies_defaults_and_rejects_invalid_configuration() {
+        let keychain = XdrbgKeyChain::builder(Xof::Ascon)
+            .with_output_key_length(48)
+            .with_personalization(b"device-42".to_vec())
+            .with_alpha_reseed(b"reseed".to_vec())
+            .with_alpha_generate(b"generate".to_vec())
+            .build()
+            .unwrap();
+        let plain_keychain = XdrbgKeyChain::new(Xof::Ascon, Some(48), None, None).unwrap();
+
+        let initial_state = keychain
+            .key_chain_instantiate_ref(&[0x11; 24], None)
+            .unwrap();
+        assert_eq!(
+            initial_state,
+            plain_keychain
+                .key_chain_instantiate_ref(&[0x11; 24], Some(b"device-42".as_slice()))
+                .unwrap()
+        );
+        assert_eq!(
+            keychain
+                .key_chain_update_ref(&[0x22; 16], &initial_state, None, None)
+                .unwrap(),
+            plain_keychain
+                .key_chain_update_ref(
+                    &[0x22; 16],
+                    &initial_state,
+                    Some(b"reseed".as_slice()),
+                    Some(b"generate".as_slice())
+                )
+                .unwrap()
+        );
+
+        let too_long = XdrbgKeyChain::builder(Xof::Ascon)
+            .with_output_key_length(Xof::Ascon.max_output_key_length() + 1)
+            .build();
+        assert!(matches!(too_long, Err(Errors::InvalidConfiguration(_))));
+
+        let long_alpha = XdrbgKeyChain::builder(Xof::Shake256)
+            .with_alpha_generate(vec![0u8; 85])
+            .build();
+        assert!(
+            matches!(long_alpha, Err(Errors::InvalidConfiguration(msg)) if msg.contains("generate"))
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_owned_alphas_match_borrowed_alphas() {
+        let keychain = XdrbgKeyChain::new(Xof::Shake256, None, None, None).unwrap();
+        let initial_state = keychain
+            .key_chain_instantiate(&[0x11; 48], Some(b"alpha".to_vec()))
+            .unwrap();
+        assert_eq!(
+            initial_state,
+            keychain
+                .key_chain_instantiate_ref(&[0x11; 48], Some(b"alpha"))
+                .unwrap()
+        );
+
+        let replay_keychain = XdrbgKeyChain::new(Xof::Shake256, None, None, None).unwrap();
+        assert_eq!(
+            keychain
+                .key_chain_update(
+                    &[0x22; 32],
+                    &initial_state,
+                    Some(b"reseed".to_vec()),
+                    Some(b"generate".to_vec())
+                )
+                .unwrap(),
+            replay_keychain
+                .key_chain_update_ref(
+                    &[0x22; 32],
+                    &initial_state,
+                    Some(b"reseed"),
+                    Some(b"generate")
+                )
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_update_into_matches_update() {
+        let xof = Xof::Shake256;
+        let keychain = XdrbgKeyChain::builder(xof)
+            .with_output_key_length(40)
+            .with_alpha_reseed(b"reseed".to_vec())
+            .build()
+            .unwrap();
+        let initial_state = keychain
+            .key_chain_instantiate_ref(&sample_seed(xof), None)
+            .unwrap();
+        let (expected_state, expected_output) = keychain
+            .key_chain_update_ref(&sample_seed(xof), &initial_state, None, Some(b"generate"))
+            .unwrap();
+
+        let (mut new_state, mut output_key) = ([0u8; 64], [0u8; 40]);
+        keychain
+            .key_chain_update_into(
+                &sample_seed(xof),
+                initial_state.as_bytes(),
+                None,
+                Some(b"generate"),
+                &mut new_state,
+                &mut output_key,
+            )
+            .unwrap();
+        assert_eq!(&new_state[..], expected_state.as_bytes());
+        assert_eq!(&output_key[..], expected_output.as_bytes());
+
+        let current_state: [u8; 64] = initial_state.as_bytes().try_into().unwrap();
+        let (new_state, output_key) = keychain
+            .key_chain_update_array::<64, 40>(
+                &sample_seed(xof),
+                &current_state,
+                None,
+                Some(b"generate"),
+            )
+            .unwrap();
+        assert_eq!(&new_state[..], expected_state.as_bytes());
+        assert_eq!(&output_key[..], expected_output.as_bytes());
+
+        let result =
+            keychain.key_chain_update_array::<32, 40>(&sample_seed(xof), &[0u8; 32], None, None);
+        assert!(matches!(result, Err(Errors::InvalidLength(_))));
+    }
+
+    #[test]
+    fn test_typed_chain_matches_dynamic_chain() {
+        let xof = Xof::Shake256;
+        let keychain = XdrbgKeyChain::builder(xof)
+            .with_output_key_length(16)
+            .with_alpha_generate(b"generate".to_vec())
+            .build()
+            .unwrap();
+        let typed_keychain =
+            TypedXdrbgKeyChain::<Shake256>::new(Some(16)).with_alpha_generate(b"generate");
+
+        let initial_state = keychain
+            .key_chain_instantiate_ref(&sample_seed(xof), None)
+            .unwrap();
+        let typed_initial_state = typed_keychain
+            .key_chain_instantiate(&sample_seed(xof), None)
+            .unwrap();
+        assert_eq!(typed_initial_state.as_bytes(), initial_state.as_bytes());
+
+        let (new_state, random_output) = keychain
+            .key_chain_update_ref(&sample_seed(xof), &initial_state, None, None)
+            .unwrap();
+        let (typed_new_state, typed_random_output) = typed_keychain
+            .key_chain_update(&sample_seed(xof), &typed_initial_state, None, None)
+            .unwrap();
+        assert_eq!(typed_new_state.as_bytes(), new_state.as_bytes());
+        assert_eq!(typed_random_output, random_output);
+        assert_eq!(
+            typed_keychain.get_state_length(),
+            keychain.get_state_length()
+        );
+    }
 }
@@ -1,5 +1,13 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
 mod crypto_primitives;
 mod errors;
 pub mod key_chains;
+pub mod primitives;
+mod secret_bytes;
 
 pub use crate::crypto_primitives::{hkdf_wrap_ops::HashFunc, xdrbg_ops::Xof};
+pub use crate::errors::Errors;
+pub use crate::secret_bytes::SecretBytes;
@@ -0,0 +1,94 @@
+//! Low level building blocks behind the key chains.
+//!
+//! The key chains in [`crate::key_chains`] are thin compositions of these
+//! primitives. They are exposed for custom constructions and report failures
+//! through the same [`Errors`](crate::Errors) type. Intermediate key material
+//! is returned as [`SecretBytes`](crate::SecretBytes), or written into caller
+//! provided buffers by the allocation free `_into` variants.
+//!
+//! - [`HkdfWrap`]: HKDF extract and expand.
+//! - [`Prg`]: the Barak-Halevi refresh and next pair.
+//! - [`Xdrbg`]: XDRBG instantiate, reseed and generate.
+//!
+//! [`TypedHkdf`] and [`TypedXdrbg`] are the same primitives monomorphized over
+//! the digest or XOF. They accept any RustCrypto hash implementing
+//! [`HkdfDigest`], and any XOF with an [`XdrbgXof`] impl.
+
+pub use crate::crypto_primitives::{
+    hkdf_wrap_ops::{HashFunc, HkdfDigest, HkdfWrap, TypedHkdf},
+    prg_ops::Prg,
+    xdrbg_ops::{TypedXdrbg, Xdrbg, XdrbgOps, XdrbgXof, Xof},
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Errors, SecretBytes};
+
+    #[test]
+    fn test_custom_construction_from_primitives() {
+        // Seed a PRG from an HKDF derived key and an XDRBG generated refresh value
+        let hkdf = HkdfWrap::new(HashFunc::Sha256);
+        let prk: SecretBytes = hkdf.hkdf_extract_ref(None, b"input key material").unwrap();
+        let prg_state: SecretBytes = hkdf.hkdf_expand_ref(&prk, None, 16).unwrap();
+
+        let xdrbg = Xdrbg::new(Xof::Shake128);
+        let xdrbg_state = xdrbg.xdrbg_instantiate_ref(&[0x11; 24], None).unwrap();
+        let (_, refresh_value) = xdrbg.xdrbg_generate_ref(&xdrbg_state, 16, None).unwrap();
+
+        let prg = Prg::new(16);
+        let refreshed_state = prg.prg_refresh(&prg_state, &refresh_value).unwrap();
+        let (random_output, new_state) = prg.prg_next(&refreshed_state).unwrap();
+
+        assert_eq!(random_output.len(), 16);
+        assert_ne!(new_state, refreshed_state);
+    }
+
+    #[test]
+    fn test_primitives_share_error_type() {
+        let result: Result<SecretBytes, Errors> = Prg::new(16).prg_refresh(&[0u8; 15], &[0u8; 15]);
+        assert!(matches!(result, Err(Errors::InvalidLength(_))));
+
+        let result = Xof::Shake256.are_params_okay(&[0u8; 8], b"", XdrbgOps::Instantiate);
+        assert!(matches!(result, Err(Errors::InvalidLength(_))));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_owned_inputs_match_borrowed_inputs() {
+        let hkdf = HkdfWrap::new(HashFunc::Sha512);
+        let prk = hkdf.hkdf_extract(Some(b"salt".to_vec()), b"ikm").unwrap();
+        assert_eq!(prk, hkdf.hkdf_extract_ref(Some(b"salt"), b"ikm").unwrap());
+        assert_eq!(
+            hkdf.hkdf_expand(&prk, Some(b"info".to_vec()), 80).unwrap(),
+            hkdf.hkdf_expand_ref(&prk, Some(b"info"), 80).unwrap()
+        );
+
+        let xdrbg = Xdrbg::new(Xof::Ascon);
+        let state = xdrbg
+            .xdrbg_instantiate(&[0x11; 24], Some(b"alpha".to_vec()))
+            .unwrap();
+        assert_eq!(
+            state,
+            xdrbg
+                .xdrbg_instantiate_ref(&[0x11; 24], Some(b"alpha"))
+                .unwrap()
+        );
+        assert_eq!(
+            xdrbg
+                .xdrbg_reseed(&state, &[0x22; 16], Some(b"alpha".to_vec()))
+                .unwrap(),
+            xdrbg
+                .xdrbg_reseed_ref(&state, &[0x22; 16], Some(b"alpha"))
+                .unwrap()
+        );
+        assert_eq!(
+            xdrbg
+                .xdrbg_generate(&state, 32, Some(b"alpha".to_vec()))
+                .unwrap(),
+            xdrbg
+                .xdrbg_generate_ref(&state, 32, Some(b"alpha"))
+                .unwrap()
+        );
+    }
+}
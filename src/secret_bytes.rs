@@ -0,0 +1,136 @@
+use alloc::{vec, vec::Vec};
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+use subtle::{Choice, ConstantTimeEq};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+// Heap buffer for key chain states, output keys and intermediate key material.
+// It is wiped on drop, never printed and only compared in constant time.
+#[derive(Clone, Default)]
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn zeroed(length: usize) -> Self {
+        Self(vec![0u8; length])
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn as_mut_bytes(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<&[u8]> for SecretBytes {
+    fn from(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec())
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl AsRef<[u8]> for SecretBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBytes([REDACTED; {} bytes])", self.0.len())
+    }
+}
+
+impl ConstantTimeEq for SecretBytes {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        self.0.as_slice().ct_eq(other.0.as_slice())
+    }
+}
+
+impl PartialEq for SecretBytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.ct_eq(other).into()
+    }
+}
+
+impl Eq for SecretBytes {}
+
+impl Zeroize for SecretBytes {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for SecretBytes {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let secret = SecretBytes::from(b"top secret key".to_vec());
+        let printed = format!("{:?}", secret);
+
+        assert!(!printed.contains("116"));
+        assert_eq!(printed, "SecretBytes([REDACTED; 14 bytes])");
+    }
+
+    #[test]
+    fn test_equality() {
+        let secret = SecretBytes::from(&[1u8, 2, 3][..]);
+
+        assert_eq!(secret, SecretBytes::new(vec![1, 2, 3]));
+        assert_ne!(secret, SecretBytes::new(vec![1, 2, 4]));
+        assert_ne!(secret, SecretBytes::new(vec![1, 2]));
+    }
+
+    #[test]
+    fn test_zeroize_clears_contents() {
+        let mut secret = SecretBytes::from(vec![0xAA; 32]);
+        secret.zeroize();
+
+        assert!(secret.is_empty());
+    }
+
+    #[test]
+    fn test_deref_to_slice() {
+        let mut secret = SecretBytes::zeroed(4);
+        secret[1..3].copy_from_slice(&[7, 8]);
+
+        assert_eq!(secret.as_bytes(), &[0, 7, 8, 0]);
+        assert_eq!(secret.len(), 4);
+    }
+}